@@ -0,0 +1,421 @@
+use super::*;
+
+/// Every local bitmap block is marked with this level instead of a tree level
+pub const LOCAL_BITMAP_LEVEL: u8 = 0xFF;
+
+/// Each block is represented by 2 bits in a local bitmap
+pub const BITS_PER_BLOCK: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BitmapError {
+    /// The local bitmap header has a level other than 0xFF
+    IncorrectLevel(u8),
+    /// The local bitmap header has a bsiz which does not fit the blocks per map
+    IncorrectSize(u32),
+    /// The master bitmap says this local map is full, but it has free blocks
+    MasterMapIncorrectlyMarkedFull,
+    /// The master bitmap says this local map has free blocks, but it is full
+    MasterMapIncorrectlyMarkedFree,
+}
+
+impl LocalBitmapStatus {
+    fn from_bits(bits: u8) -> LocalBitmapStatus {
+        match bits {
+            0 => LocalBitmapStatus::Busy,
+            1 => LocalBitmapStatus::NeverUsed,
+            3 => LocalBitmapStatus::Free,
+            _ => LocalBitmapStatus::Invalid,
+        }
+    }
+
+    fn to_bits(&self) -> u8 {
+        match self {
+            LocalBitmapStatus::Busy => 0,
+            LocalBitmapStatus::NeverUsed => 1,
+            LocalBitmapStatus::Invalid => 2,
+            LocalBitmapStatus::Free => 3,
+        }
+    }
+
+    /// Returns true if the block can be handed out by the allocator
+    pub fn is_free(&self) -> bool {
+        *self == LocalBitmapStatus::NeverUsed || *self == LocalBitmapStatus::Free
+    }
+}
+
+/// Marks block `index` within a raw local bitmap block, as returned by `Database::get_block`
+pub fn set_local_status(raw: &mut [u8], index: usize, status: &LocalBitmapStatus) {
+    let byte = mem::size_of::<blk_hdr>() + index * BITS_PER_BLOCK / 8;
+    let shift = (index * BITS_PER_BLOCK) % 8;
+    raw[byte] = (raw[byte] & !(0b11 << shift)) | (status.to_bits() << shift);
+}
+
+impl Database {
+    /// Builds local bitmap `map_num` for a newly created or extended region, with every block
+    /// it covers below `total_blks` other than itself marked never used, and the rest busy so
+    /// they are never allocated
+    pub(crate) fn build_local_bitmap(&self, map_num: usize, total_blks: usize, tn: u64) -> Vec<u8> {
+        let bplmap = self.blocks_per_map();
+        let mut raw = vec![0; self.fhead.blk_size as usize];
+        raw[0..2].copy_from_slice(&block::GDSV6.to_le_bytes());
+        raw[3] = LOCAL_BITMAP_LEVEL;
+        raw[4..8].copy_from_slice(&(self.local_bitmap_size() as u32).to_le_bytes());
+        raw[8..16].copy_from_slice(&tn.to_le_bytes());
+        for i in 1..bplmap {
+            if map_num * bplmap + i < total_blks {
+                set_local_status(&mut raw, i, &LocalBitmapStatus::NeverUsed);
+            }
+        }
+        raw
+    }
+
+    /// Number of blocks covered by each local bitmap, including the bitmap itself
+    pub fn blocks_per_map(&self) -> usize {
+        self.fhead.bplmap as usize
+    }
+
+    /// Total number of blocks in the database, according to the file header
+    pub fn total_blocks(&self) -> usize {
+        self.fhead.trans_hist.total_blks as usize
+    }
+
+    /// Number of local bitmaps needed to cover every block in the database
+    pub fn local_bitmap_count(&self) -> usize {
+        let bplmap = self.blocks_per_map();
+        self.total_blocks().div_ceil(bplmap)
+    }
+
+    /// The size a local bitmap block should have, header included
+    pub fn local_bitmap_size(&self) -> usize {
+        mem::size_of::<blk_hdr>() + (self.blocks_per_map() * BITS_PER_BLOCK).div_ceil(8)
+    }
+
+    /// Returns true if the master bitmap says local map `map_num` has free blocks
+    pub fn master_map_free(&self, map_num: usize) -> bool {
+        self.master_bitmap[map_num / 8] & (1 << (map_num % 8)) != 0
+    }
+
+    /// Marks local map `map_num` as having free blocks or being full in the in memory master
+    /// bitmap; `write_header` is needed to save it
+    pub fn set_master_map_free(&mut self, map_num: usize, free: bool) {
+        let byte = map_num / 8;
+        let before = self.master_bitmap[byte];
+        if free {
+            self.master_bitmap[byte] |= 1 << (map_num % 8);
+        } else {
+            self.master_bitmap[byte] &= !(1 << (map_num % 8));
+        }
+        if self.master_bitmap[byte] != before {
+            self.master_map_dirty = Some(match self.master_map_dirty {
+                Some((start, end)) => (std::cmp::min(start, byte), std::cmp::max(end, byte + 1)),
+                None => (byte, byte + 1),
+            });
+        }
+    }
+
+    /// Reads the local bitmap `map_num` and returns the status of each block it covers. Blocks
+    /// past the end of the database are not included, so the last map may be short
+    pub fn local_bitmap(&self, map_num: usize) -> Result<Vec<LocalBitmapStatus>, ValueError> {
+        let bplmap = self.blocks_per_map();
+        let first = map_num * bplmap;
+        let count = std::cmp::min(bplmap, self.total_blocks().saturating_sub(first));
+        let blk = self.get_block(first)?;
+        let bits = &blk[mem::size_of::<blk_hdr>()..];
+        let mut ret = Vec::with_capacity(count);
+        for i in 0..count {
+            let byte = bits[i * BITS_PER_BLOCK / 8];
+            let shift = (i * BITS_PER_BLOCK) % 8;
+            ret.push(LocalBitmapStatus::from_bits((byte >> shift) & 0b11));
+        }
+        Ok(ret)
+    }
+
+    /// Marks block `blk_num` in its local bitmap, keeping the master bitmap and free block count
+    /// in step. Only the local bitmap is written; `write_header` is needed to save the rest
+    pub fn set_block_status(&mut self, blk_num: usize, status: LocalBitmapStatus)
+            -> Result<(), ValueError> {
+        let bplmap = self.blocks_per_map();
+        let map_num = blk_num / bplmap;
+        let old = self.local_block_status(blk_num)?;
+        let mut raw = self.get_block(map_num * bplmap)?;
+        set_local_status(&mut raw, blk_num % bplmap, &status);
+        let tn = self.current_tn();
+        raw[8..16].copy_from_slice(&tn.to_le_bytes());
+        self.write_block(map_num * bplmap, &raw)?;
+        if old.is_free() && !status.is_free() {
+            self.fhead.trans_hist.free_blocks -= 1;
+        } else if !old.is_free() && status.is_free() {
+            self.fhead.trans_hist.free_blocks += 1;
+        }
+        let has_free = self.local_bitmap(map_num)?.iter().skip(1).any(|s| s.is_free());
+        self.set_master_map_free(map_num, has_free);
+        Ok(())
+    }
+
+    /// Finds a free block, marks it busy and returns its number. If every block is in use, the
+    /// file is first extended by the extension count in the file header
+    pub fn allocate_block(&mut self) -> Result<usize, ValueError> {
+        let bplmap = self.blocks_per_map();
+        for map_num in 0..self.local_bitmap_count() {
+            if !self.master_map_free(map_num) {
+                continue;
+            }
+            let statuses = self.local_bitmap(map_num)?;
+            match statuses.iter().skip(1).position(|s| s.is_free()) {
+                Some(i) => {
+                    let blk_num = map_num * bplmap + i + 1;
+                    self.set_block_status(blk_num, LocalBitmapStatus::Busy)?;
+                    return Ok(blk_num);
+                },
+                // The master bitmap was out of date; correct it and keep looking
+                None => self.set_master_map_free(map_num, false),
+            }
+        }
+        let extension = self.fhead.extension_size as usize;
+        if extension == 0 {
+            return Err(ValueError::DatabaseFull);
+        }
+        self.extend(extension)?;
+        self.allocate_block()
+    }
+
+    /// Grows the database file by `blocks` usable blocks, plus any local bitmaps needed to
+    /// cover them, like MUPIP EXTEND. The new blocks are marked never used, and the file header
+    /// is saved with the new total and free block counts
+    pub fn extend(&mut self, blocks: usize) -> Result<(), ValueError> {
+        let bplmap = self.blocks_per_map();
+        let old_total = self.total_blocks();
+        let old_maps = self.local_bitmap_count();
+        // The new bitmaps take up blocks too, which may call for yet another bitmap
+        let mut new_total = old_total + blocks;
+        while new_total - old_total - (new_total.div_ceil(bplmap) - old_maps) < blocks {
+            new_total += 1;
+        }
+        let new_maps = new_total.div_ceil(bplmap);
+        if new_maps > self.master_bitmap.len() * 8 || new_total > i32::MAX as usize {
+            return Err(ValueError::DatabaseFull);
+        }
+        let end = self.block_offset(new_total) + PHYSICAL_DATABASE_BLOCK_SIZE as u64;
+        self.handle.set_len(end)?;
+        let tn = self.current_tn();
+        // Blocks past the old end in the old last bitmap were marked busy so nothing used them
+        if !old_total.is_multiple_of(bplmap) {
+            let map_num = old_maps - 1;
+            let mut raw = self.get_block(map_num * bplmap)?;
+            for blk_num in old_total..std::cmp::min(new_total, old_maps * bplmap) {
+                set_local_status(&mut raw, blk_num % bplmap, &LocalBitmapStatus::NeverUsed);
+            }
+            raw[8..16].copy_from_slice(&tn.to_le_bytes());
+            self.write_block(map_num * bplmap, &raw)?;
+            self.set_master_map_free(map_num, true);
+        }
+        for map_num in old_maps..new_maps {
+            let raw = self.build_local_bitmap(map_num, new_total, tn);
+            self.write_block(map_num * bplmap, &raw)?;
+            self.set_master_map_free(map_num, true);
+        }
+        self.fhead.trans_hist.total_blks = new_total as _;
+        let free_blocks = self.fhead.trans_hist.free_blocks as usize + blocks;
+        self.fhead.trans_hist.free_blocks = free_blocks as _;
+        self.write_header()?;
+        Ok(())
+    }
+
+    /// Returns a block to its local bitmap so it can be allocated again
+    pub fn free_block(&mut self, blk_num: usize) -> Result<(), ValueError> {
+        self.set_block_status(blk_num, LocalBitmapStatus::Free)
+    }
+
+    /// Removes local bitmaps from the end of the file, with the blocks they cover, for as long
+    /// as none of those blocks are in use, like MUPIP REORG -TRUNCATE. The first bitmap is
+    /// always kept. The file header is saved with the new total and free block counts. Returns
+    /// the number of blocks removed
+    pub fn truncate(&mut self) -> Result<usize, ValueError> {
+        let bplmap = self.blocks_per_map();
+        let old_total = self.total_blocks();
+        let mut maps = self.local_bitmap_count();
+        let mut removed_free = 0;
+        while maps > 1 {
+            let statuses = self.local_bitmap(maps - 1)?;
+            if !statuses.iter().skip(1).all(|s| s.is_free()) {
+                break;
+            }
+            removed_free += statuses.len() - 1;
+            maps -= 1;
+            // Maps past the end of the file are marked free, as MUPIP CREATE leaves them
+            self.set_master_map_free(maps, true);
+        }
+        let new_total = maps * bplmap;
+        if new_total >= old_total {
+            return Ok(0);
+        }
+        self.fhead.trans_hist.total_blks = new_total as _;
+        let free_blocks = self.fhead.trans_hist.free_blocks as usize - removed_free;
+        self.fhead.trans_hist.free_blocks = free_blocks as _;
+        self.write_header()?;
+        let end = self.block_offset(new_total) + PHYSICAL_DATABASE_BLOCK_SIZE as u64;
+        self.handle.set_len(end)?;
+        Ok(old_total - new_total)
+    }
+
+    /// Verifies every local bitmap has a valid header, and that the master bitmap agrees with
+    /// whether each local bitmap has free blocks
+    pub fn check_bitmaps(&self) -> Result<Vec<IntegFinding>, ValueError> {
+        let mut findings = Vec::new();
+        let bplmap = self.blocks_per_map();
+        for map_num in 0..self.local_bitmap_count() {
+            let blk_num = map_num * bplmap;
+            let raw = self.get_block(blk_num)?;
+            let blk = get_block(&raw, blk_num, BlkType::LocalBitmap)?;
+            if blk.header().levl != LOCAL_BITMAP_LEVEL {
+                findings.push(IntegFinding {
+                    blk_num,
+                    error: ValueError::from(BitmapError::IncorrectLevel(blk.header().levl)),
+                });
+            }
+            if blk.header().bsiz as usize != self.local_bitmap_size() {
+                findings.push(IntegFinding {
+                    blk_num,
+                    error: ValueError::from(BitmapError::IncorrectSize(blk.header().bsiz)),
+                });
+            }
+            // The bitmap itself is always marked busy, so it does not count towards free space
+            let has_free = self.local_bitmap(map_num)?.iter().skip(1).any(|s| s.is_free());
+            let marked_free = self.master_map_free(map_num);
+            if has_free && !marked_free {
+                findings.push(IntegFinding {
+                    blk_num,
+                    error: ValueError::from(BitmapError::MasterMapIncorrectlyMarkedFull),
+                });
+            } else if !has_free && marked_free {
+                findings.push(IntegFinding {
+                    blk_num,
+                    error: ValueError::from(BitmapError::MasterMapIncorrectlyMarkedFree),
+                });
+            }
+        }
+        Ok(findings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::create::CreateParams;
+    use self::update::MIN_FILL_FACTOR;
+    use test_util::{TempDb, key, sample_nodes, all_nodes, assert_integ_clean};
+
+    fn free_blocks(db: &Database) -> usize {
+        db.fhead.trans_hist.free_blocks as usize
+    }
+
+    fn file_len(db: &TempDb) -> u64 {
+        std::fs::metadata(&db.path.0).unwrap().len()
+    }
+
+    #[test]
+    fn extend_adds_usable_blocks_and_bitmaps() {
+        let mut db = TempDb::new(&CreateParams { allocation: 1000, ..CreateParams::default() });
+        assert_eq!(db.total_blocks(), 1002);
+        let free = free_blocks(&db);
+        // Crossing into a third local bitmap takes one more block than asked for
+        db.extend(100).unwrap();
+        assert_eq!(db.total_blocks(), 1103);
+        assert_eq!(db.local_bitmap_count(), 3);
+        assert_eq!(free_blocks(&db), free + 100);
+        assert_eq!(file_len(&db), db.block_offset(1103) + PHYSICAL_DATABASE_BLOCK_SIZE as u64);
+        assert_integ_clean(&db);
+        let reopened = Database::open(&db.path.0).unwrap();
+        assert_eq!(reopened.total_blocks(), 1103);
+        assert_eq!(free_blocks(&reopened), free + 100);
+        // Filling the rest of the last bitmap doesn't need another one
+        db.extend(1536 - 1103 - 3).unwrap();
+        assert_eq!(db.total_blocks(), 1533);
+        assert_eq!(db.local_bitmap_count(), 3);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn grows_when_blocks_run_out() {
+        let mut db = TempDb::new(&CreateParams {
+            blk_size: 1024,
+            allocation: 10,
+            extension_count: 50,
+            ..CreateParams::default()
+        });
+        let nodes = sample_nodes(2000);
+        for (key, value) in nodes.iter() {
+            db.set(key, value).unwrap();
+        }
+        assert!(db.total_blocks() > 11);
+        assert_eq!(all_nodes(&db, b"x"), nodes);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn full_without_an_extension_count() {
+        let mut db = TempDb::new(&CreateParams {
+            blk_size: 1024,
+            allocation: 10,
+            extension_count: 0,
+            ..CreateParams::default()
+        });
+        let err = sample_nodes(2000).into_iter()
+            .try_for_each(|(key, value)| db.set(&key, &value))
+            .unwrap_err();
+        assert!(matches!(err, ValueError::DatabaseFull), "{:?}", err);
+        assert_eq!(db.total_blocks(), 11);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn truncate_keeps_the_first_bitmap() {
+        let mut db = TempDb::new(&CreateParams { allocation: 2000, ..CreateParams::default() });
+        assert_eq!(db.total_blocks(), 2004);
+        assert_eq!(db.truncate().unwrap(), 2004 - 512);
+        assert_eq!(db.total_blocks(), 512);
+        assert_eq!(free_blocks(&db), 512 - 1 - 2);
+        assert_eq!(file_len(&db), db.block_offset(512) + PHYSICAL_DATABASE_BLOCK_SIZE as u64);
+        // The bits of the removed maps are left as MUPIP CREATE would have them
+        assert!((1..4).all(|map_num| db.master_map_free(map_num)));
+        assert_integ_clean(&db);
+        let reopened = Database::open(&db.path.0).unwrap();
+        assert_eq!(reopened.total_blocks(), 512);
+        assert_eq!(free_blocks(&reopened), 509);
+        // Nothing more can go
+        assert_eq!(db.truncate().unwrap(), 0);
+        // And it can grow again
+        db.extend(600).unwrap();
+        assert_eq!(free_blocks(&db), 509 + 600);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn truncate_stops_at_a_busy_block() {
+        let mut db = TempDb::new(&CreateParams {
+            blk_size: 1024,
+            allocation: 5000,
+            ..CreateParams::default()
+        });
+        // Sparse blocks, so the global spills past the first bitmap
+        let nodes = sample_nodes(12000);
+        db.set_fill_factor(MIN_FILL_FACTOR);
+        db.bulk_load(nodes.clone()).unwrap();
+        // The last block in use, not counting the local bitmaps themselves
+        let busy = (0..db.total_blocks()).rev()
+            .find(|b| b % 512 != 0 && !db.local_block_status(*b).unwrap().is_free())
+            .unwrap();
+        assert!(busy > 512);
+        let kept = (busy / 512 + 1) * 512;
+        assert_eq!(db.truncate().unwrap(), 5010 - kept);
+        assert_eq!(db.total_blocks(), kept);
+        assert_eq!(all_nodes(&db, b"x"), nodes);
+        assert_integ_clean(&db);
+        // Once the global is killed only its root is left in use; a bulk load writes it last
+        db.kill(&key("^x")).unwrap();
+        let root = db.find_global_root(b"x").unwrap();
+        db.truncate().unwrap();
+        assert_eq!(db.total_blocks(), (root / 512 + 1) * 512);
+        assert_integ_clean(&db);
+    }
+}
@@ -19,6 +19,27 @@ pub enum BlkType {
     Unknown,
 }
 
+/// Block version written by V6 and later; V4 blocks have a different header layout
+pub const GDSV6: u16 = 1;
+/// Block version of blocks left in the V4 format until the database is fully upgraded
+pub const GDSV4: u16 = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    /// The block was last updated at a transaction number past the database's current one
+    TransactionNumberTooLarge(u64),
+    /// The block's bsiz is smaller than a block header
+    SizeTooSmall(u32),
+    /// The block's bsiz is larger than the database's block size
+    SizeTooLarge(u32),
+    /// The block's level does not fit its position in the tree
+    IncorrectLevel { expected: u8, found: u8 },
+    /// The block has a bver this library can't read
+    UnsupportedVersion(u16),
+    /// The block is still in the V4 format, which the database allows until it is upgraded
+    NeedsUpgrade(u16),
+}
+
 /// Represents a database block, trimmed to exactly fit the data in use
 #[derive(Debug, Clone)]
 pub struct Blk<'a>{
@@ -41,8 +62,48 @@ named_args!(pub(crate) read_block(blk_num: BlkNum, typ: BlkType)<Blk>,
         )
 );
 
-pub fn get_block<'a>(data: &[u8], blk_num: usize, typ: BlkType) -> Result<Blk, ValueError>  {
-    let (_, b) = read_block(data, BlkNum::Block(blk_num), typ)?;
+/// Parses a raw block as read by `Database::get_block`. The bsiz is checked against the size of
+/// `data` before parsing, so a corrupt header is reported instead of underflowing
+pub fn get_block(data: &[u8], blk_num: usize, typ: BlkType) -> Result<Blk<'_>, ValueError>  {
+    let hdr_size = mem::size_of::<blk_hdr>();
+    if data.len() >= hdr_size {
+        let bsiz = u32::from(data[4]) | u32::from(data[5]) << 8
+            | u32::from(data[6]) << 16 | u32::from(data[7]) << 24;
+        if (bsiz as usize) < hdr_size {
+            return Err(ValueError::from(BlockError::SizeTooSmall(bsiz)));
+        }
+        if bsiz as usize > data.len() {
+            return Err(ValueError::from(BlockError::SizeTooLarge(bsiz)));
+        }
+    }
+    let (_, mut b) = read_block(data, BlkNum::Block(blk_num), typ)?;
+    // A global's root block is only an index block once the tree grows past a single level
+    if b.typ == BlkType::IndexBlock && b.header.levl == 0 {
+        b.typ = BlkType::DataBlock;
+    }
+    Ok(b)
+}
+
+/// Like `get_block`, but also verifies the block version, and if `levl` is given, that the
+/// block is at the level its parent expects. A V4 block is only corrupt once the database is
+/// `fully_upgraded`; before then it is reported as needing an upgrade
+pub fn get_valid_block(data: &[u8], blk_num: usize, typ: BlkType, levl: Option<u8>,
+                       fully_upgraded: bool) -> Result<Blk<'_>, ValueError> {
+    let b = get_block(data, blk_num, typ)?;
+    if b.header.bver == GDSV4 && !fully_upgraded {
+        return Err(ValueError::from(BlockError::NeedsUpgrade(b.header.bver)));
+    }
+    if b.header.bver != GDSV6 {
+        return Err(ValueError::from(BlockError::UnsupportedVersion(b.header.bver)));
+    }
+    if let Some(expected) = levl {
+        if b.header.levl != expected {
+            return Err(ValueError::from(BlockError::IncorrectLevel {
+                expected,
+                found: b.header.levl,
+            }));
+        }
+    }
     Ok(b)
 }
 
@@ -52,43 +113,78 @@ impl<'a> Blk<'a> {
     }
 
     pub fn data(&self) -> &[u8] {
-        &self.data
+        self.data
+    }
+
+    pub fn typ(&self) -> &BlkType {
+        &self.typ
     }
 
-    pub fn integ(&self, start: &[u8]) -> Result<Vec<IntegBlock>, ValueError> {
+    /// Verifies that this block was not updated after `curr_tn`, which would indicate a torn
+    /// write or a block copied in from another database
+    pub fn check_tn(&self, curr_tn: u64) -> Result<(), ValueError> {
+        if self.header.tn > curr_tn {
+            return Err(ValueError::from(BlockError::TransactionNumberTooLarge(self.header.tn)));
+        }
+        Ok(())
+    }
+
+    /// Checks every record in this block, and returns the blocks it points to so they can be
+    /// checked next. `start` is the key before this block's range, taken from the parent, and
+    /// `max_key_size` is the limit from the file header
+    pub fn integ(&self, start: &[u8], max_key_size: usize) -> Result<Vec<IntegBlock>, ValueError> {
         // We don't need to scan records for these types, but should verify the blocks they point
         // too
         let mut queue = Vec::new();
         if self.typ == BlkType::MasterBitmap || self.typ == BlkType::LocalBitmap {
             return Ok(queue);
         }
-        let mut state = State{compression: 0, goal: start};
-        let rc = RecordCursor::new(&self);
-        //let mut next_goal = Vec::from(start);
-        let mut goal = Vec::new();
+        let rc = RecordCursor::new(self);
+        // The previous key in this block, used both for compression and the sort check
+        let mut key = Vec::new();
+        let mut prev = Vec::from(start);
         for (i, record) in rc.enumerate() {
-            // This will check for block too short
+            // This will check for records too short, or overrunning the block
             let record = record?;
+            let is_star = self.header.levl > 0 && record.header.rsiz == 8;
             // Check for a datablock which has an empty compression count
             if self.typ == BlkType::DataBlock && i > 0 && record.header.cmpc == 0 {
                 return Err(ValueError::from(RecordError::ZeroCompressionCount));
             }
             // Note down where we started so the next integ can compare
-            let start = goal.clone();
-            // Verify that this record sorts after the previous record
-            let sorts = RecordCursor::compare(&record, &mut state);
-            //RecordCursor::expand_key(&record, &mut goal)?;
-            // Note down where we end
-            let end = goal.clone();
-            if RecordCursor::compare_strings(&start, &end) == SortOrder::SortsAfter
-                    && record.header.rsiz != 8 {
-                return Err(ValueError::from(RecordError::IncorrectSort));
+            let start = prev.clone();
+            if !is_star {
+                // A record can only share the previous key up to, but not including, its final
+                // terminator
+                if record.header.cmpc as usize > key.len().saturating_sub(1) {
+                    return Err(ValueError::from(RecordError::CompressionCountTooLarge));
+                }
+                RecordCursor::expand_key(&record, &mut key)?;
+                if key.len() > max_key_size {
+                    return Err(ValueError::from(RecordError::KeyTooLong));
+                }
+                // Verify that this record sorts after the previous record
+                if !prev.is_empty()
+                        && RecordCursor::compare_strings(&key, &prev) != SortOrder::SortsAfter {
+                    return Err(ValueError::from(RecordError::IncorrectSort));
+                }
+                prev = key.clone();
             }
+            // Note down where we end; the * record covers everything after the previous record
+            let end = if is_star { vec![] } else { key.clone() };
 
             // If needed, add the pointer of this block to be scanned
             // TODO: we should detect loops
-            //println!("Here! {:#?}", self);
             if self.typ != BlkType::DataBlock {
+                // Index records hold exactly a block pointer; directory tree leaves may follow
+                // the pointer to a global's root with collation information. A * record's rsiz
+                // of 8 is exactly a header and a pointer
+                if !is_star {
+                    let ptr_len = record.data().len();
+                    if (self.header.levl > 0 && ptr_len != 4) || ptr_len < 4 {
+                        return Err(ValueError::from(RecordError::IncorrectPointerLength));
+                    }
+                }
                 let mut typ = BlkType::DataBlock;
                 if self.typ == BlkType::DirectoryTree {
                     typ = match self.header.levl {
@@ -101,18 +197,25 @@ impl<'a> Blk<'a> {
                         _ => BlkType::IndexBlock,
                     };
                 }
-                let blk_num = record.ptr();
-                if blk_num.is_err() {
-                    println!("Problem parsing block num {:?}, record {:?}", self.blk_num, record);
-                    return Err(blk_num.err().unwrap());
-                } else {
-                    queue.push(IntegBlock {
-                        blk_num: blk_num.unwrap(),
-                        typ: typ,
-                        start: start,
-                        end: end,
-                    });
-                }
+                // A global's tree has its own key range, unrelated to the directory tree's
+                let (start, end) = match typ {
+                    BlkType::IndexBlock if self.typ == BlkType::DirectoryTree => (vec![], vec![]),
+                    _ => (start, end),
+                };
+                // Children are one level down, except a global's root which can be at any level
+                let levl = match typ {
+                    BlkType::IndexBlock if self.typ == BlkType::DirectoryTree => None,
+                    _ => Some(self.header.levl - 1),
+                };
+                let blk_num = match record.ptr() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        println!("Problem parsing block num {:?}, record {:?}", self.blk_num,
+                                 record);
+                        return Err(e);
+                    },
+                };
+                queue.push(IntegBlock { blk_num, typ, start, end, levl });
             }
         }
         Ok(queue)
@@ -122,7 +225,6 @@ impl<'a> Blk<'a> {
 pub struct RecordCursor<'a> {
     remaining_data: &'a [u8],
     current_offset: usize,
-    blk: &'a Blk<'a>,
 }
 
 impl<'a> RecordCursor<'a> {
@@ -130,7 +232,6 @@ impl<'a> RecordCursor<'a> {
         RecordCursor {
             remaining_data: block.data,
             current_offset: mem::size_of::<blk_hdr>(),
-            blk: block,
         }
     }
 
@@ -152,7 +253,7 @@ impl<'a> RecordCursor<'a> {
         if i < b.len() {
             return SortOrder::SortsBefore;
         }
-        return SortOrder::SortsEqual;
+        SortOrder::SortsEqual
     }
 
     /// Copies new values from the raw record into the key. Must be called for every record to be
@@ -169,7 +270,7 @@ impl<'a> RecordCursor<'a> {
             cmpc +=1;
             i += 1;
         }
-        if i + 1 > data.len() {
+        if i + 1 >= data.len() {
             return Err(ValueError::RecordError(RecordError::NoTerminatingCharacter));
         }
         unsafe {
@@ -227,18 +328,142 @@ impl<'a> Iterator for RecordCursor<'a> {
     type Item = Result<RawRec<'a>, ValueError>;
 
     fn next(&mut self) -> Option<Result<RawRec<'a>, ValueError>> {
-        if self.remaining_data.len() == 0 {
+        if self.remaining_data.is_empty() {
             return None;
         }
-        // This feels ugly; is there a cleanier way to do this?
-        let next = record_header(self.remaining_data, self.current_offset);
-        if next.is_err() {
-            return Some(Err(ValueError::from(next.unwrap_err())));
+        // Check the record fits in what is left of the block before parsing it, so a bad rsiz
+        // is reported rather than underflowing
+        let hdr_size = mem::size_of::<rec_hdr>();
+        let error = if self.remaining_data.len() < hdr_size {
+            Some(RecordError::TooSmall)
+        } else {
+            let rsiz = self.remaining_data[0] as usize | (self.remaining_data[1] as usize) << 8;
+            if rsiz == 0 {
+                Some(RecordError::LengthZero)
+            } else if rsiz < hdr_size {
+                Some(RecordError::TooSmall)
+            } else if rsiz > self.remaining_data.len() {
+                Some(RecordError::TooBig)
+            } else {
+                None
+            }
+        };
+        if let Some(error) = error {
+            self.remaining_data = &[];
+            return Some(Err(ValueError::from(error)));
         }
-        let (rest, rec) = next.unwrap();
+        let (rest, rec) = match record_header(self.remaining_data, self.current_offset) {
+            Ok(x) => x,
+            Err(e) => return Some(Err(ValueError::from(e))),
+        };
         self.remaining_data = rest;
         self.current_offset += rec.header.rsiz as usize;
         Some(Ok(rec))
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLK_SIZE: usize = 1024;
+
+    /// A record with the given compression count, compressed key and value or pointer
+    fn rec(cmpc: u8, data: &[u8]) -> Vec<u8> {
+        let mut ret = ((data.len() + 4) as u16).to_le_bytes().to_vec();
+        ret.extend(&[cmpc, 0]);
+        ret.extend(data);
+        ret
+    }
+
+    /// A raw V6 block holding `records`, padded out to the block size
+    fn raw_block(levl: u8, records: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = records.concat();
+        let mut raw = GDSV6.to_le_bytes().to_vec();
+        raw.extend(&[0, levl]);
+        raw.extend(&((body.len() + 16) as u32).to_le_bytes());
+        raw.extend(&1u64.to_le_bytes());
+        raw.extend(body);
+        raw.resize(BLK_SIZE, 0);
+        raw
+    }
+
+    fn record_error(levl: u8, records: &[Vec<u8>], max_key_size: usize) -> RecordError {
+        let raw = raw_block(levl, records);
+        let typ = if levl == 0 { BlkType::DataBlock } else { BlkType::IndexBlock };
+        let blk = get_block(&raw, 3, typ).unwrap();
+        match blk.integ(&[], max_key_size) {
+            Err(ValueError::RecordError(e)) => e,
+            x => panic!("expected a record error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn checks_records() {
+        let first = rec(0, b"x\0\xFFb\0\0value");
+        let ok = [first.clone(), rec(4, b"c\0\0value")];
+        let raw = raw_block(0, &ok);
+        assert!(get_block(&raw, 3, BlkType::DataBlock).unwrap().integ(&[], 64).unwrap().is_empty());
+
+        let mut too_big = rec(0, b"x\0\0value");
+        too_big[0] = 200;
+        let mut too_small = rec(0, b"x\0\0value");
+        too_small[0..2].copy_from_slice(&2u16.to_le_bytes());
+        let mut zero = rec(0, b"x\0\0value");
+        zero[0..2].copy_from_slice(&0u16.to_le_bytes());
+        // RecordError::IoError only wraps read failures, which no block contents can cause
+        let cases: Vec<(u8, Vec<Vec<u8>>, usize, &str)> = vec![
+            (0, vec![first.clone(), too_big], 64, "TooBig"),
+            (0, vec![too_small], 64, "TooSmall"),
+            (0, vec![zero], 64, "LengthZero"),
+            (0, vec![first.clone(), rec(0, b"x\0\xFFc\0\0v")], 64, "ZeroCompressionCount"),
+            // The first key is 6 bytes, so at most 5 can be shared
+            (0, vec![first.clone(), rec(6, b"\0\0v")], 64, "CompressionCountTooLarge"),
+            (0, vec![first.clone()], 5, "KeyTooLong"),
+            (0, vec![first.clone(), rec(3, b"a\0\0v")], 64, "IncorrectSort"),
+            (0, vec![first.clone(), rec(4, b"cvalue")], 64, "NoTerminatingCharacter"),
+            // Index records hold exactly a 4 byte pointer after the key
+            (1, vec![rec(0, b"x\0\xFFb\0\0\x05\0\0"), rec(0, b"\x06\0\0\0")], 64,
+             "IncorrectPointerLength"),
+        ];
+        for (levl, records, max_key_size, expected) in cases {
+            let error = record_error(levl, &records, max_key_size);
+            assert_eq!(format!("{:?}", error), expected);
+        }
+        // A pointer of the right length, then a * record
+        let index = raw_block(1, &[rec(0, b"x\0\xFFb\0\0\x05\0\0\0"), rec(0, b"\x06\0\0\0")]);
+        let children = get_block(&index, 3, BlkType::IndexBlock).unwrap().integ(&[], 64).unwrap();
+        let ptrs: Vec<_> = children.iter().map(|c| c.blk_num.clone()).collect();
+        assert_eq!(ptrs, [BlkNum::Block(5), BlkNum::Block(6)]);
+        assert_eq!(children[0].levl, Some(0));
+    }
+
+    #[test]
+    fn checks_headers() {
+        let raw = raw_block(0, &[rec(0, b"x\0\0value")]);
+        let with = |offset: usize, bytes: &[u8]| {
+            let mut raw = raw.clone();
+            raw[offset..offset + bytes.len()].copy_from_slice(bytes);
+            raw
+        };
+        let error = |raw: &[u8], levl, fully_upgraded| {
+            match get_valid_block(raw, 3, BlkType::DataBlock, levl, fully_upgraded) {
+                Err(ValueError::BlockError(e)) => e,
+                x => panic!("expected a block error, got {:?}", x),
+            }
+        };
+        assert!(get_valid_block(&raw, 3, BlkType::DataBlock, Some(0), true).is_ok());
+        assert_eq!(error(&with(4, &8u32.to_le_bytes()), None, true), BlockError::SizeTooSmall(8));
+        assert_eq!(error(&with(4, &1025u32.to_le_bytes()), None, true),
+                   BlockError::SizeTooLarge(1025));
+        assert_eq!(error(&raw, Some(1), true),
+                   BlockError::IncorrectLevel { expected: 1, found: 0 });
+        assert_eq!(error(&with(0, &7u16.to_le_bytes()), None, true),
+                   BlockError::UnsupportedVersion(7));
+        // A V4 block only needs upgrading until the database says every block has been
+        let v4 = with(0, &GDSV4.to_le_bytes());
+        assert_eq!(error(&v4, None, false), BlockError::NeedsUpgrade(GDSV4));
+        assert_eq!(error(&v4, None, true), BlockError::UnsupportedVersion(GDSV4));
+    }
+}
@@ -0,0 +1,282 @@
+//! Building global trees from sorted input, for loads too big to go through `set` a node at a
+//! time.
+//!
+//! Nodes are packed into data blocks in order, each filled to the fill factor. When a block is
+//! full it is written and a record for it is added to the index block being filled above it,
+//! which is written in turn once it is full, and so on up. Every block is written exactly once,
+//! after which only the directory tree is updated, to point at the new root.
+
+use super::*;
+
+use self::tree::MAX_BT_DEPTH;
+use self::update::{Entry, build_block, close_chunk, record_size};
+
+/// What `Database::bulk_load` built
+#[derive(Debug, Clone, Default)]
+pub struct BulkReport {
+    pub globals: usize,
+    pub nodes: usize,
+    pub data_blocks: usize,
+    pub index_blocks: usize,
+}
+
+/// Records waiting to be written as a block at one level
+struct Pending {
+    entries: Vec<Entry>,
+    /// Size of the block they would make, header included
+    size: usize,
+}
+
+impl Pending {
+    fn new() -> Pending {
+        Pending { entries: Vec::new(), size: mem::size_of::<blk_hdr>() }
+    }
+}
+
+/// Builds one global at a time from nodes given in key order
+pub(crate) struct BulkBuilder {
+    tn: u64,
+    /// Space records may use in each block, given the fill factor
+    fill: usize,
+    /// The global being built, and the last key added to it
+    name: Vec<u8>,
+    last_key: Vec<u8>,
+    /// Blocks being filled, from level 0 up
+    levels: Vec<Pending>,
+    /// Blocks written for the global being built, so they can be freed if it is abandoned
+    written: Vec<usize>,
+    pub report: BulkReport,
+}
+
+impl BulkBuilder {
+    pub fn new(database: &Database) -> BulkBuilder {
+        BulkBuilder {
+            tn: database.current_tn(),
+            fill: database.fill_capacity(),
+            name: Vec::new(),
+            last_key: Vec::new(),
+            levels: Vec::new(),
+            written: Vec::new(),
+            report: BulkReport::default(),
+        }
+    }
+
+    /// Adds the next node. Keys must be in increasing order, and each global must not already
+    /// be in the database
+    pub fn add(&mut self, database: &mut Database, key: &[u8], value: &[u8])
+            -> Result<(), ValueError> {
+        if key.len() > database.max_key_size() {
+            return Err(ValueError::from(RecordError::KeyTooLong));
+        }
+        if value.len() > database.fhead.max_rec_size as usize {
+            return Err(ValueError::RecordTooLarge);
+        }
+        if key <= self.last_key.as_slice() {
+            return Err(ValueError::from(RecordError::IncorrectSort));
+        }
+        let name = &key[..key.iter().position(|c| *c == 0).unwrap_or(key.len())];
+        if name != self.name.as_slice() {
+            match database.find_global_root(name) {
+                Ok(_) => return Err(ValueError::GlobalExists),
+                Err(ValueError::GlobalNotFound) => {},
+                Err(e) => return Err(e),
+            }
+            self.finish(database)?;
+            self.name = name.to_vec();
+        }
+        let entry = Entry { key: Some(key.to_vec()), value: value.to_vec() };
+        self.push(database, 0, entry)?;
+        self.last_key = key.to_vec();
+        self.report.nodes += 1;
+        Ok(())
+    }
+
+    /// Adds a record to the block being filled at `levl`, writing that block out first if the
+    /// record would take it past the fill factor
+    fn push(&mut self, database: &mut Database, levl: usize, entry: Entry)
+            -> Result<(), ValueError> {
+        if levl > MAX_BT_DEPTH {
+            return Err(ValueError::RecordTooLarge);
+        }
+        if self.levels.len() <= levl {
+            self.levels.push(Pending::new());
+        }
+        let mut size = {
+            let prev = self.levels[levl].entries.last().and_then(|e| e.key.as_deref());
+            record_size(prev.unwrap_or(&[]), &entry)
+        };
+        if !self.levels[levl].entries.is_empty() && self.levels[levl].size + size > self.fill {
+            let ptr = self.write(database, levl, false)?;
+            self.push(database, levl + 1, ptr)?;
+            size = record_size(&[], &entry);
+        }
+        let pending = &mut self.levels[levl];
+        // A record too big for the fill factor still gets a block to itself
+        if pending.size + size > database.block_capacity() {
+            return Err(ValueError::RecordTooLarge);
+        }
+        pending.size += size;
+        pending.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the block being filled at `levl` to a newly allocated block, and returns the
+    /// record pointing at it. The last block at each level covers everything after it
+    fn write(&mut self, database: &mut Database, levl: usize, last: bool)
+            -> Result<Entry, ValueError> {
+        let pending = mem::replace(&mut self.levels[levl], Pending::new());
+        let (key, mut chunk) = close_chunk(pending.entries, levl as u8, last);
+        if last && levl > 0 {
+            if let Some(entry) = chunk.last_mut() {
+                entry.key = None;
+            }
+        }
+        let blk_num = database.allocate_block()?;
+        self.written.push(blk_num);
+        let raw = build_block(levl as u8, self.tn, &chunk, database.fhead.blk_size as usize);
+        database.write_block(blk_num, &raw)?;
+        if levl == 0 {
+            self.report.data_blocks += 1;
+        } else {
+            self.report.index_blocks += 1;
+        }
+        Ok(Entry::pointer(key, blk_num))
+    }
+
+    /// Writes out whatever is left of the global being built, up to a root at level 1 or
+    /// above, and adds the global to the directory tree
+    pub fn finish(&mut self, database: &mut Database) -> Result<(), ValueError> {
+        if self.levels.is_empty() {
+            return Ok(());
+        }
+        let mut levl = 0;
+        let root = loop {
+            let top = self.levels.len() == levl + 1;
+            let ptr = self.write(database, levl, true)?;
+            if top && levl > 0 {
+                break ptr.ptr()?;
+            }
+            self.push(database, levl + 1, ptr)?;
+            levl += 1;
+        };
+        let mut key = self.name.clone();
+        key.extend(&[0, 0]);
+        database.insert(1, BlkType::DirectoryTree, &key, &(root as u32).to_le_bytes(), self.tn)?;
+        self.levels.clear();
+        self.written.clear();
+        self.report.globals += 1;
+        Ok(())
+    }
+
+    /// Frees the blocks written for a global which won't be finished
+    pub fn abandon(&mut self, database: &mut Database) -> Result<(), ValueError> {
+        for blk_num in self.written.drain(..) {
+            database.free_block(blk_num)?;
+        }
+        self.levels.clear();
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Builds new globals from `nodes`, given as (key, value) pairs in increasing key order,
+    /// much faster than setting each node. Blocks are filled to the fill factor set with
+    /// `set_fill_factor`, and each is written once. None of the globals may already exist. If
+    /// anything goes wrong, the globals finished so far are kept and the one being built is
+    /// discarded
+    pub fn bulk_load<I>(&mut self, nodes: I) -> Result<BulkReport, ValueError>
+            where I: IntoIterator<Item = (Vec<u8>, Vec<u8>)> {
+        let mut builder = BulkBuilder::new(self);
+        let mut ret = nodes.into_iter()
+            .try_for_each(|(key, value)| builder.add(self, &key, &value));
+        if ret.is_ok() {
+            ret = builder.finish(self);
+        }
+        if ret.is_err() {
+            // The error which stopped the load matters more than one freeing its blocks
+            let _ = builder.abandon(self);
+        }
+        let committed = self.commit();
+        ret.and(committed.map_err(ValueError::from)).map(|_| builder.report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key, sample_nodes, all_nodes, assert_integ_clean};
+
+    #[test]
+    fn builds_the_same_tree_as_setting_each_node() {
+        let nodes = sample_nodes(3000);
+        let mut set = TempDb::small();
+        for (key, value) in nodes.iter() {
+            set.set(key, value).unwrap();
+        }
+        let mut bulk = TempDb::small();
+        let report = bulk.bulk_load(nodes.clone()).unwrap();
+        assert_eq!(report.globals, 1);
+        assert_eq!(report.nodes, nodes.len());
+        assert_eq!(all_nodes(&bulk, b"x"), all_nodes(&set, b"x"));
+        assert_integ_clean(&bulk);
+        // Every block is packed full, so the bulk tree is never the bigger one
+        let (bulk_stats, set_stats) = (bulk.global_stats(b"x").unwrap(),
+                                       set.global_stats(b"x").unwrap());
+        assert_eq!(bulk_stats.blocks(), report.data_blocks + report.index_blocks);
+        assert!(bulk_stats.blocks() <= set_stats.blocks());
+        let free = |db: &Database| db.fhead.trans_hist.free_blocks as usize;
+        assert_eq!(free(&bulk) + bulk_stats.blocks(), free(&TempDb::small()));
+    }
+
+    #[test]
+    fn follows_the_fill_factor() {
+        let nodes = sample_nodes(3000);
+        let mut full = TempDb::small();
+        full.bulk_load(nodes.clone()).unwrap();
+        let mut half = TempDb::small();
+        half.set_fill_factor(50);
+        half.bulk_load(nodes.clone()).unwrap();
+        let (full, half) = (full.global_stats(b"x").unwrap(), half.global_stats(b"x").unwrap());
+        assert!(half.blocks() > full.blocks() * 3 / 2);
+        assert!(half.max_fill <= 60.0);
+    }
+
+    #[test]
+    fn loads_several_globals() {
+        let mut nodes = sample_nodes(200);
+        nodes.extend((0..200).map(|i| (key(&format!("^y({})", i)), vec![b'y'; i % 50])));
+        nodes.sort();
+        let mut db = TempDb::small();
+        assert_eq!(db.bulk_load(nodes.clone()).unwrap().globals, 2);
+        let mut loaded = all_nodes(&db, b"x");
+        loaded.extend(all_nodes(&db, b"y"));
+        assert_eq!(loaded, nodes);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn abandons_a_global_given_out_of_order() {
+        let mut db = TempDb::small();
+        let free = db.fhead.trans_hist.free_blocks;
+        let tn = db.current_tn();
+        let mut nodes = sample_nodes(1000);
+        nodes.swap(800, 900);
+        let err = db.bulk_load(nodes).unwrap_err();
+        assert!(matches!(err, ValueError::RecordError(RecordError::IncorrectSort)), "{:?}", err);
+        // The blocks written before the bad node are free again, and the tn still moves on
+        assert_eq!(db.fhead.trans_hist.free_blocks, free);
+        assert_eq!(db.current_tn(), tn + 1);
+        assert!(all_nodes(&db, b"x").is_empty());
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn refuses_existing_globals() {
+        let mut db = TempDb::small();
+        db.set(&key("^x(1)"), b"one").unwrap();
+        let err = db.bulk_load(sample_nodes(10)).unwrap_err();
+        assert!(matches!(err, ValueError::GlobalExists), "{:?}", err);
+        assert_eq!(all_nodes(&db, b"x"), vec![(key("^x(1)"), b"one".to_vec())]);
+        assert_integ_clean(&db);
+    }
+}
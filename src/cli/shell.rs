@@ -0,0 +1,383 @@
+//! The interactive shell started by `ydb-ng shell`, for browsing a database without DSE.
+//!
+//! The shell keeps a current node, like a current directory: `cd` moves around the tree of
+//! subscripts, `ls` lists the children of a node, and most other commands act on the current
+//! node unless given a reference. Lines are read with rustyline, so the arrow keys edit and
+//! recall commands; history is kept in ~/.ydb_ng_history between sessions.
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use ydb_ng::*;
+use ydb_ng::key::{decode_key, encode_key, format_key, format_subscript,
+                  format_value, parse_reference, parse_subscripts};
+
+use crate::{print_block, print_path, EXIT_OK};
+
+const HELP: &str = "\
+cd [REF]      Move to a node: ^ACCT(1) from anywhere, or 2 or \"x\",3 below the current node.
+              .. goes up a level, and cd on its own goes back to the list of globals
+ls [REF]      List the children of the current node (or REF); / marks those with children
+get [REF]     Print the value of the current node (or REF)
+find [REF]    Show the blocks visited looking up the current node (or REF)
+dump BLOCK    Print a block's header and records; add hex for the raw bytes
+pwd           Print the current node
+history       List previous commands; !! repeats the last one and !N repeats number N
+quit          Leave the shell";
+
+/// A place in the database: the list of globals when `name` is None, otherwise a node
+#[derive(Debug, Clone, PartialEq)]
+struct Location {
+    name: Option<Vec<u8>>,
+    subs: Vec<Vec<u8>>,
+}
+
+impl Location {
+    /// The list of globals, where the shell starts
+    fn top() -> Location {
+        Location { name: None, subs: Vec::new() }
+    }
+}
+
+/// A line of input, parsed
+#[derive(Debug, PartialEq)]
+enum Command {
+    Cd(Location),
+    Ls(Location),
+    Get(Location),
+    Find(Location),
+    Dump { blk_num: usize, hex: bool },
+    Pwd,
+    History,
+    Help,
+    Quit,
+    /// A known command given arguments it can't use, with its usage
+    Usage(&'static str),
+    Unknown(String),
+}
+
+/// Works out which location `arg` refers to from `current`: a reference starting with ^ stands
+/// on its own, anything else is subscripts below the current node
+fn resolve(current: &Location, arg: &str) -> Result<Location, ValueError> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Ok(current.clone());
+    }
+    if arg == ".." {
+        let mut ret = current.clone();
+        if ret.subs.pop().is_none() {
+            ret.name = None;
+        }
+        return Ok(ret);
+    }
+    if arg.starts_with('^') || current.name.is_none() {
+        let (name, subs) = parse_reference(arg)?;
+        return Ok(Location { name: Some(name), subs });
+    }
+    let mut ret = current.clone();
+    ret.subs.extend(parse_subscripts(arg)?);
+    Ok(ret)
+}
+
+/// Parses a line of input, resolving any reference in it against `current`
+fn parse_command(line: &str, current: &Location) -> Result<Command, ValueError> {
+    let (command, arg) = match line.find(char::is_whitespace) {
+        Some(x) => (&line[..x], line[x..].trim()),
+        None => (line, ""),
+    };
+    Ok(match command {
+        "cd" if arg.is_empty() => Command::Cd(Location::top()),
+        "cd" => Command::Cd(resolve(current, arg)?),
+        "ls" => Command::Ls(resolve(current, arg)?),
+        "get" => Command::Get(resolve(current, arg)?),
+        "find" => Command::Find(resolve(current, arg)?),
+        "dump" => {
+            let mut args = arg.split_whitespace();
+            let blk_num = args.next().and_then(|b| b.parse::<usize>().ok());
+            let hex = args.next();
+            match (blk_num, hex, args.next()) {
+                (Some(blk_num), None, None) => Command::Dump { blk_num, hex: false },
+                (Some(blk_num), Some("hex"), None) => Command::Dump { blk_num, hex: true },
+                _ => Command::Usage("dump BLOCK [hex]"),
+            }
+        },
+        "pwd" => Command::Pwd,
+        "history" => Command::History,
+        "help" | "?" => Command::Help,
+        "quit" | "exit" | "q" => Command::Quit,
+        _ => Command::Unknown(String::from(command)),
+    })
+}
+
+/// Replaces !! and !N with the command they refer to in `history`, numbered from 1. Returns
+/// None if there is no such command
+fn expand_history(line: &str, history: &[String]) -> Option<String> {
+    if line == "!!" {
+        return history.last().cloned();
+    }
+    if let Some(n) = line.strip_prefix('!') {
+        let n = n.parse::<usize>().ok()?;
+        return history.get(n.checked_sub(1)?).cloned();
+    }
+    Some(String::from(line))
+}
+
+struct Shell<'a> {
+    database: &'a Database,
+    location: Location,
+    editor: DefaultEditor,
+    history_file: Option<PathBuf>,
+}
+
+impl<'a> Shell<'a> {
+    fn new(database: &'a Database) -> Result<Shell<'a>, ValueError> {
+        let mut editor = DefaultEditor::new().map_err(readline_error)?;
+        let history_file = env::var_os("HOME").map(|home| {
+            let mut path = PathBuf::from(home);
+            path.push(".ydb_ng_history");
+            path
+        });
+        // A missing or unreadable history file just means starting without history
+        if let Some(path) = &history_file {
+            let _ = editor.load_history(path);
+        }
+        Ok(Shell { database, location: Location::top(), editor, history_file })
+    }
+
+    fn history(&self) -> Vec<String> {
+        self.editor.history().iter().cloned().collect()
+    }
+
+    fn key(&self, location: &Location) -> Option<Vec<u8>> {
+        location.name.as_ref()
+            .map(|name| encode_key(name, &location.subs, self.database.std_null_coll()))
+    }
+
+    fn prompt(&self) -> String {
+        match self.key(&self.location) {
+            Some(key) => format!("{}> ", format_key(&key)),
+            None => String::from("/> "),
+        }
+    }
+
+    /// Returns true if the location is the list of globals, or a node with a value or children
+    fn exists(&self, location: &Location) -> Result<bool, ValueError> {
+        match self.key(location) {
+            Some(key) => Ok(self.database.get(&key)?.is_some()
+                            || self.database.first_child(&key)?.is_some()),
+            None => Ok(true),
+        }
+    }
+
+    fn ls(&self, location: &Location) -> Result<(), ValueError> {
+        let key = match self.key(location) {
+            Some(key) => key,
+            None => {
+                for (name, _) in self.database.globals()? {
+                    println!("^{}", String::from_utf8_lossy(&name));
+                }
+                return Ok(());
+            },
+        };
+        let mut children = Vec::new();
+        let mut next = self.database.first_child(&key)?;
+        while let Some(child) = next {
+            let (_, subs) = decode_key(&child);
+            let mut label = subs.last().map(format_subscript).unwrap_or_default();
+            if self.database.first_child(&child)?.is_some() {
+                label.push('/');
+            }
+            let value = self.database.get(&child)?.map(|v| format_value(&v));
+            children.push((label, value));
+            next = self.database.next_sibling(&child, false)?;
+        }
+        let width = children.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+        for (label, value) in children {
+            match value {
+                Some(value) => println!("{:<width$}  {}", label, value, width = width),
+                None => println!("{}", label),
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, location: &Location) -> Result<(), ValueError> {
+        let key = match self.key(location) {
+            Some(key) => key,
+            None => {
+                println!("Not at a node");
+                return Ok(());
+            },
+        };
+        match self.database.get(&key)? {
+            Some(value) => println!("{}={}", format_key(&key), format_value(&value)),
+            None => println!("{} has no value", format_key(&key)),
+        }
+        Ok(())
+    }
+
+    fn remember(&mut self, line: &str) {
+        // Losing history is not worth interrupting the session for
+        if let Ok(true) = self.editor.add_history_entry(line) {
+            if let Some(path) = &self.history_file {
+                let _ = self.editor.append_history(path);
+            }
+        }
+    }
+
+    /// Runs one command. Returns false when the shell should exit
+    fn execute(&mut self, command: Command) -> Result<bool, ValueError> {
+        match command {
+            Command::Cd(location) => {
+                if self.exists(&location)? {
+                    self.location = location;
+                } else {
+                    println!("No such node");
+                }
+            },
+            Command::Ls(location) => self.ls(&location)?,
+            Command::Get(location) => self.get(&location)?,
+            Command::Find(location) => match self.key(&location) {
+                Some(key) => {
+                    print_path(self.database, &key)?;
+                },
+                None => println!("Not at a node"),
+            },
+            Command::Dump { blk_num, hex } => {
+                print_block(&mut io::stdout().lock(), self.database, blk_num, hex)?;
+            },
+            Command::Pwd => println!("{}", self.prompt().trim_end_matches("> ")),
+            Command::History => {
+                for (i, line) in self.history().iter().enumerate() {
+                    println!("{:>5}  {}", i + 1, line);
+                }
+            },
+            Command::Help => println!("{}", HELP),
+            Command::Quit => return Ok(false),
+            Command::Usage(usage) => println!("Usage: {}", usage),
+            Command::Unknown(command) => println!("Unknown command {}; try help", command),
+        }
+        Ok(true)
+    }
+}
+
+/// Turns a failure reading the terminal into the error the rest of the tool reports
+fn readline_error(error: ReadlineError) -> ValueError {
+    match error {
+        ReadlineError::Io(e) => ValueError::from(e),
+        e => ValueError::from(io::Error::other(e.to_string())),
+    }
+}
+
+/// Reads and runs commands until quit, end of input or Ctrl-D
+pub fn run(database: &Database) -> Result<i32, ValueError> {
+    let mut shell = Shell::new(database)?;
+    loop {
+        let prompt = shell.prompt();
+        let line = match shell.editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C abandons the line being typed, like in a Unix shell
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => {
+                println!();
+                break;
+            },
+            Err(e) => return Err(readline_error(e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line = match expand_history(line, &shell.history()) {
+            Some(x) => x,
+            None => {
+                println!("No such command in history");
+                continue;
+            },
+        };
+        shell.remember(&line);
+        let result = parse_command(&line, &shell.location)
+            .and_then(|command| shell.execute(command));
+        match result {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(e) => println!("Error: {:?}", e),
+        }
+    }
+    Ok(EXIT_OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, subs: &[&str]) -> Location {
+        Location {
+            name: Some(name.as_bytes().to_vec()),
+            subs: subs.iter().map(|s| s.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    fn parse(line: &str, current: &Location) -> Command {
+        parse_command(line, current).unwrap()
+    }
+
+    #[test]
+    fn cd_resolves_references() {
+        let top = Location::top();
+        let acct = node("ACCT", &["1"]);
+        assert_eq!(parse("cd ^ACCT(1)", &top), Command::Cd(acct.clone()));
+        // At the top, a name needs no ^
+        assert_eq!(parse("cd ACCT", &top), Command::Cd(node("ACCT", &[])));
+        // Anything else is below the current node
+        assert_eq!(parse("cd 2,\"x\"", &acct), Command::Cd(node("ACCT", &["1", "2", "x"])));
+        assert_eq!(parse("cd ^B", &acct), Command::Cd(node("B", &[])));
+        assert_eq!(parse("cd ..", &acct), Command::Cd(node("ACCT", &[])));
+        assert_eq!(parse("cd ..", &node("ACCT", &[])), Command::Cd(top.clone()));
+        assert_eq!(parse("cd ..", &top), Command::Cd(top.clone()));
+        assert_eq!(parse("cd", &acct), Command::Cd(top.clone()));
+        assert!(parse_command("cd ^ACCT(", &top).is_err());
+    }
+
+    #[test]
+    fn node_commands_default_to_the_current_node() {
+        let acct = node("ACCT", &["1"]);
+        assert_eq!(parse("ls", &acct), Command::Ls(acct.clone()));
+        assert_eq!(parse("ls  3 ", &acct), Command::Ls(node("ACCT", &["1", "3"])));
+        assert_eq!(parse("get", &acct), Command::Get(acct.clone()));
+        assert_eq!(parse("get ^X(\"a\")", &acct), Command::Get(node("X", &["a"])));
+        assert_eq!(parse("find", &acct), Command::Find(acct.clone()));
+        assert_eq!(parse("find ..", &acct), Command::Find(node("ACCT", &[])));
+    }
+
+    #[test]
+    fn parses_other_commands() {
+        let top = Location::top();
+        assert_eq!(parse("dump 3", &top), Command::Dump { blk_num: 3, hex: false });
+        assert_eq!(parse("dump 3 hex", &top), Command::Dump { blk_num: 3, hex: true });
+        for bad in ["dump", "dump x", "dump 3 raw", "dump 3 hex 4"].iter() {
+            assert_eq!(parse(bad, &top), Command::Usage("dump BLOCK [hex]"), "{}", bad);
+        }
+        assert_eq!(parse("pwd", &top), Command::Pwd);
+        assert_eq!(parse("history", &top), Command::History);
+        assert_eq!(parse("?", &top), Command::Help);
+        assert_eq!(parse("q", &top), Command::Quit);
+        assert_eq!(parse("rm x", &top), Command::Unknown(String::from("rm")));
+    }
+
+    #[test]
+    fn expands_history() {
+        let history = vec![String::from("ls"), String::from("cd 1")];
+        assert_eq!(expand_history("!!", &history), Some(String::from("cd 1")));
+        assert_eq!(expand_history("!1", &history), Some(String::from("ls")));
+        assert_eq!(expand_history("get", &history), Some(String::from("get")));
+        assert_eq!(expand_history("!0", &history), None);
+        assert_eq!(expand_history("!3", &history), None);
+        assert_eq!(expand_history("!x", &history), None);
+        assert_eq!(expand_history("!!", &[]), None);
+    }
+}
@@ -0,0 +1,252 @@
+use super::*;
+
+use self::block::GDSV6;
+use self::update::{Entry, build_block};
+
+/// The label at the start of a V6 database file header
+pub const GDS_LABEL: &[u8] = b"GDSDYNUNX03";
+/// Blocks covered by each local bitmap; YottaDB always uses 512
+pub const BLKS_PER_LMAP: usize = 512;
+/// The largest block size YottaDB accepts
+pub const MAX_BLK_SIZE: usize = 65024;
+/// The largest record size YottaDB accepts
+pub const MAX_REC_SIZE: usize = 1_048_576;
+/// The largest key size YottaDB accepts
+pub const MAX_KEY_SIZE: usize = 1019;
+/// The highest transaction number a V6 database may reach
+pub const MAX_TN_V6: u64 = 0xFFFF_FFFF_83FF_FFFF;
+/// The smallest allocation MUPIP CREATE accepts
+pub const MIN_ALLOCATION: usize = 10;
+/// BG, the buffered global access method
+const ACC_METH_BG: i32 = 1;
+/// GDSMV63014, the minor database version MUPIP CREATE writes in a V6 header
+const GDSMVCURR: u32 = 20;
+
+/// The settings for a new database file, like the segment and region qualifiers given to GDE
+#[derive(Debug, Clone)]
+pub struct CreateParams {
+    /// Size of each block in bytes; a multiple of 512
+    pub blk_size: usize,
+    /// Number of blocks to create, not counting local bitmaps
+    pub allocation: usize,
+    /// Number of blocks to add each time the file runs out of space
+    pub extension_count: usize,
+    /// The longest key, in the internal format with its terminators
+    pub max_key_size: usize,
+    /// The longest value of a single node
+    pub max_rec_size: usize,
+    /// Bytes left unused at the end of every block
+    pub reserved_bytes: usize,
+    /// Whether empty string subscripts sort before numbers, rather than between numbers and strings
+    pub std_null_coll: bool,
+    /// Number of global buffers
+    pub global_buffers: usize,
+    /// Lock space, in pages
+    pub lock_space: usize,
+}
+
+impl Default for CreateParams {
+    fn default() -> Self {
+        CreateParams {
+            blk_size: 4096,
+            allocation: 5000,
+            extension_count: 10000,
+            max_key_size: 64,
+            max_rec_size: 256,
+            reserved_bytes: 0,
+            std_null_coll: true,
+            global_buffers: 1024,
+            lock_space: 220,
+        }
+    }
+}
+
+impl CreateParams {
+    /// Checks each setting is within the limits YottaDB accepts, returning the name of the
+    /// first one which isn't
+    pub fn validate(&self) -> Result<(), ValueError> {
+        let hdr_size = mem::size_of::<blk_hdr>();
+        let phys = PHYSICAL_DATABASE_BLOCK_SIZE as usize;
+        if self.blk_size == 0 || !self.blk_size.is_multiple_of(phys)
+                || self.blk_size > MAX_BLK_SIZE {
+            return Err(ValueError::InvalidParameter("block size"));
+        }
+        // Each local bitmap needs a bit in the master bitmap
+        let maps = self.allocation.div_ceil(BLKS_PER_LMAP - 1);
+        if self.allocation < MIN_ALLOCATION || self.allocation > i32::MAX as usize
+                || maps > MASTER_MAP_SIZE * 8 {
+            return Err(ValueError::InvalidParameter("allocation"));
+        }
+        if self.extension_count > u16::MAX as usize {
+            return Err(ValueError::InvalidParameter("extension count"));
+        }
+        if self.reserved_bytes > self.blk_size - hdr_size {
+            return Err(ValueError::InvalidParameter("reserved bytes"));
+        }
+        // An index block must be able to hold two records with the longest key
+        let index_record = mem::size_of::<rec_hdr>() + self.max_key_size + 4;
+        if self.max_key_size < 3 || self.max_key_size > MAX_KEY_SIZE
+                || hdr_size + 2 * index_record > self.blk_size - self.reserved_bytes {
+            return Err(ValueError::InvalidParameter("key size"));
+        }
+        if self.max_rec_size == 0 || self.max_rec_size > MAX_REC_SIZE {
+            return Err(ValueError::InvalidParameter("record size"));
+        }
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Creates a new database file at `path`, like MUPIP CREATE: a file header, a master
+    /// bitmap, a local bitmap for every BLKS_PER_LMAP blocks and an empty directory tree. The
+    /// file must not already exist. Returns the database, opened
+    pub fn create(path: &str, params: &CreateParams) -> Result<Database, ValueError> {
+        params.validate()?;
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+        // Don't leave a partly written file behind, as it would stop the create being retried
+        Database::init(file, params).inspect_err(|_| {
+            let _ = std::fs::remove_file(path);
+        })
+    }
+
+    /// Writes the header, bitmaps and directory tree of a new database to `file`
+    fn init(file: File, params: &CreateParams) -> Result<Database, ValueError> {
+        let hdr_size = mem::size_of::<sgmnt_data_struct>();
+        let maps = params.allocation.div_ceil(BLKS_PER_LMAP - 1);
+        let total_blks = params.allocation + maps;
+
+        let mut fhead: sgmnt_data_struct = unsafe { mem::zeroed() };
+        fhead.label[..GDS_LABEL.len()].copy_from_slice(GDS_LABEL);
+        fhead.acc_meth = ACC_METH_BG as _;
+        fhead.blk_size = params.blk_size as _;
+        fhead.bplmap = BLKS_PER_LMAP as _;
+        fhead.master_map_len = MASTER_MAP_SIZE as _;
+        // Blocks start on the first 512 byte boundary after the header and master bitmap
+        let phys = PHYSICAL_DATABASE_BLOCK_SIZE as usize;
+        fhead.start_vbn = ((hdr_size + MASTER_MAP_SIZE).div_ceil(phys) + 1) as _;
+        fhead.extension_size = params.extension_count as _;
+        fhead.max_key_size = params.max_key_size as _;
+        fhead.max_rec_size = params.max_rec_size as _;
+        fhead.reserved_bytes = params.reserved_bytes as _;
+        fhead.std_null_coll = params.std_null_coll as _;
+        fhead.semid = INVALID_SEMID as _;
+        fhead.shmid = INVALID_SHMID as _;
+        fhead.desired_db_format = GDSV6 as _;
+        fhead.creation_db_ver = GDSV6 as _;
+        fhead.certified_for_upgrade_to = GDSV6 as _;
+        fhead.fully_upgraded = 1;
+        fhead.db_got_to_v5_once = 1;
+        fhead.creation_mdb_ver = GDSMVCURR as _;
+        fhead.minor_dbver = GDSMVCURR as _;
+        fhead.last_mdb_ver = GDSMVCURR as _;
+        fhead.n_bts = params.global_buffers as _;
+        fhead.lock_space_size = (params.lock_space * phys) as _;
+        fhead.max_tn = MAX_TN_V6;
+        // Warn once three quarters of the transaction numbers are used up
+        fhead.max_tn_warn = MAX_TN_V6 - MAX_TN_V6 / 4;
+        fhead.trans_hist.curr_tn = 1;
+        fhead.trans_hist.early_tn = 1;
+        fhead.trans_hist.total_blks = total_blks as _;
+        // Blocks 1 and 2 hold the directory tree
+        fhead.trans_hist.free_blocks = (total_blks - maps - 2) as _;
+
+        let mut database = Database {
+            fhead,
+            // Maps past the end of the file are marked free too, so extending needs no change
+            master_bitmap: new_master_map(0xFF),
+            handle: file,
+            fill_factor: 100,
+            master_map_dirty: None,
+        };
+        // Never used blocks are left as holes in the file; the file ends with a 512 byte block
+        let end = database.block_offset(total_blks) + phys as u64;
+        database.handle.set_len(end)?;
+        let tn = database.current_tn();
+        for map_num in 0..maps {
+            let raw = database.build_local_bitmap(map_num, total_blks, tn);
+            database.write_block(map_num * BLKS_PER_LMAP, &raw)?;
+        }
+        // The directory tree: a root at level 1 whose * record points at an empty level 0 block
+        let mut map = database.get_block(0)?;
+        set_local_status(&mut map, 1, &LocalBitmapStatus::Busy);
+        set_local_status(&mut map, 2, &LocalBitmapStatus::Busy);
+        database.write_block(0, &map)?;
+        let blk_size = params.blk_size;
+        database.write_block(1, &build_block(1, tn, &[Entry::pointer(None, 2)], blk_size))?;
+        database.write_block(2, &build_block(0, tn, &[], blk_size))?;
+        database.write_header_and_master_map()?;
+        Ok(database)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key, all_nodes, assert_integ_clean};
+
+    #[test]
+    fn creates_an_empty_database() {
+        let db = TempDb::new(&CreateParams::default());
+        let maps = 5000usize.div_ceil(BLKS_PER_LMAP - 1);
+        assert_eq!(db.total_blocks(), 5000 + maps);
+        assert_eq!(db.fhead.trans_hist.free_blocks as usize, 5000 - 2);
+        assert_eq!(db.fhead.semid, INVALID_SEMID as _);
+        assert_eq!(db.fhead.shmid, INVALID_SHMID as _);
+        assert!(!db.in_use());
+        assert!(db.fully_upgraded());
+        assert!(db.globals().unwrap().is_empty());
+        assert_integ_clean(&db);
+        // Reopening reads back the same header and master bitmap
+        let reopened = Database::open(&db.path.0).unwrap();
+        assert_eq!(reopened.header_bytes(), db.header_bytes());
+        assert!(reopened.master_bitmap.iter().all(|b| *b == 0xFF));
+        assert_integ_clean(&reopened);
+    }
+
+    #[test]
+    fn in_use_while_yottadb_has_it_open() {
+        let mut db = TempDb::small();
+        db.fhead.shmid = 1234;
+        db.write_header().unwrap();
+        assert!(Database::open(&db.path.0).unwrap().in_use());
+        db.fhead.shmid = INVALID_SHMID as _;
+        db.fhead.semid = 5678;
+        db.write_header().unwrap();
+        assert!(Database::open(&db.path.0).unwrap().in_use());
+    }
+
+    #[test]
+    fn rejects_bad_parameters() {
+        let bad = [
+            CreateParams { blk_size: 1000, ..CreateParams::default() },
+            CreateParams { allocation: 1, ..CreateParams::default() },
+            CreateParams { allocation: 1_100_000_000, ..CreateParams::default() },
+            CreateParams { max_key_size: 2, ..CreateParams::default() },
+            CreateParams { blk_size: 512, max_key_size: 255, ..CreateParams::default() },
+            CreateParams { max_rec_size: 0, ..CreateParams::default() },
+        ];
+        for params in bad.iter() {
+            assert!(params.validate().is_err(), "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn never_overwrites_a_file() {
+        let db = TempDb::small();
+        assert!(Database::create(&db.path.0, &CreateParams::default()).is_err());
+        // The existing database is left alone
+        assert_eq!(Database::open(&db.path.0).unwrap().header_bytes(), db.header_bytes());
+    }
+
+    #[test]
+    fn null_collation_follows_params() {
+        let mut db = TempDb::new(&CreateParams { std_null_coll: false, ..CreateParams::default() });
+        assert!(!db.std_null_coll());
+        let empty = key::encode_key(b"x", &[vec![]], false);
+        db.set(&empty, b"empty").unwrap();
+        db.set(&key("^x(1)"), b"one").unwrap();
+        db.set(&key::encode_key(b"x", &[b"a".to_vec()], false), b"a").unwrap();
+        let order: Vec<_> = all_nodes(&db, b"x").into_iter().map(|(_, v)| v).collect();
+        assert_eq!(order, vec![b"one".to_vec(), b"empty".to_vec(), b"a".to_vec()]);
+    }
+}
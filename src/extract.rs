@@ -0,0 +1,191 @@
+use super::*;
+
+use std::io::Write;
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::key::{format_key, format_value};
+use self::progress::{Progress, CancelToken};
+use self::update::{Entry, block_size, build_block};
+
+/// Output formats understood by MUPIP LOAD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtractFormat {
+    /// One ZWRITE style line per node, like ^ACCT(1)="Bob"
+    Zwr,
+    /// Each node as two lines: the reference, then the value as it is stored. Values containing
+    /// a line break can't be read back
+    Go,
+    /// Length prefixed records: a header with the database limits, then for each global a
+    /// collation record followed by records laid out as in a data block
+    Binary,
+}
+
+/// The label at the start of a binary extract's header record
+pub const BIN_HEADER_LABEL: &str = "GDS BINARY EXTRACT LEVEL 6";
+/// Size of a binary extract's header record: the label, a YYYYMMDDHHMMSS timestamp, the block,
+/// record and key size limits and null collation as 7 digit numbers, and a 32 byte user label
+pub const BIN_HEADER_SZ: usize = 100;
+/// Width of each number in the binary header
+pub const BIN_HEADER_NUMSZ: usize = 7;
+/// Width of the user label at the end of the binary header
+pub const BIN_HEADER_LABSZ: usize = 32;
+
+/// Counts for one global written by an extract, like MUPIP's RECORDSTAT message
+#[derive(Debug, Clone, Default)]
+pub struct ExtractStats {
+    pub name: Vec<u8>,
+    pub records: usize,
+    /// Longest key, in the internal format
+    pub max_key_len: usize,
+    pub max_value_len: usize,
+}
+
+/// Returns true if global `name` matches `pattern`, where * matches any run of characters
+pub fn matches_pattern(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.iter().position(|c| *c == b'*') {
+        None => pattern == name,
+        Some(x) => {
+            if !name.starts_with(&pattern[..x]) {
+                return false;
+            }
+            let rest = &pattern[x + 1..];
+            // Try every possible length for the run the * stands for
+            (x..=name.len()).any(|i| matches_pattern(rest, &name[i..]))
+        },
+    }
+}
+
+/// The current date and time in UTC, as year, month, day, hour, minute and second
+fn now() -> (i64, usize, i64, u64, u64, u64) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, time) = ((secs / 86400) as i64, secs % 86400);
+    // Converts days since 1970-01-01 to a civil date, counting in 400 year eras from March 1st
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as usize, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// The current date and time, in UTC, the way MUPIP EXTRACT writes it: 18-OCT-2026  14:03:52
+pub fn extract_timestamp() -> String {
+    const MONTHS: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN",
+                                "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+    let (year, month, day, hour, minute, second) = now();
+    format!("{:02}-{}-{:04}  {:02}:{:02}:{:02}", day, MONTHS[month - 1], year, hour, minute,
+            second)
+}
+
+/// Writes one record of a binary extract, preceded by its length
+fn write_bin_record<W: Write>(out: &mut W, data: &[u8]) -> Result<(), ValueError> {
+    out.write_all(&(data.len() as u32).to_le_bytes())?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+/// Writes `entries` as one binary extract record, keys compressed as they would be in a block
+fn write_bin_entries<W: Write>(out: &mut W, entries: &[Entry]) -> Result<(), ValueError> {
+    let size = block_size(entries);
+    let raw = build_block(0, 0, entries, size);
+    write_bin_record(out, &raw[mem::size_of::<blk_hdr>()..])
+}
+
+impl Database {
+    /// The header record of a binary extract of this database
+    fn bin_header(&self) -> Vec<u8> {
+        let (year, month, day, hour, minute, second) = now();
+        let mut ret = format!("{}{:04}{:02}{:02}{:02}{:02}{:02}", BIN_HEADER_LABEL, year, month,
+                              day, hour, minute, second);
+        for n in [self.fhead.blk_size as usize, self.fhead.max_rec_size as usize,
+                  self.fhead.max_key_size as usize, self.std_null_coll() as usize].iter() {
+            ret.push_str(&format!("{:0width$}", n, width = BIN_HEADER_NUMSZ));
+        }
+        ret.push_str(&format!("{:<width$}", "YottaDB MUPIP EXTRACT", width = BIN_HEADER_LABSZ));
+        ret.into_bytes()
+    }
+
+    /// Writes every node of the globals matching `select` (or of every global, if it is None)
+    /// to `out` in `format`, global by global in collation order, with the header MUPIP
+    /// EXTRACT writes. Binary records hold as many nodes as fit in a block of this database.
+    /// Returns counts for each global written
+    pub fn extract<W: Write>(&self, out: &mut W, format: ExtractFormat,
+                             select: Option<&[Vec<u8>]>) -> Result<Vec<ExtractStats>, ValueError> {
+        self.extract_with_progress(out, format, select, None, &CancelToken::new())
+    }
+
+    /// Like `extract`, but sends progress after every block read, and stops with Cancelled,
+    /// leaving the output incomplete, once `cancel` is set
+    pub fn extract_with_progress<W: Write>(&self, out: &mut W, format: ExtractFormat,
+                                           select: Option<&[Vec<u8>]>,
+                                           progress: Option<Sender<Progress>>,
+                                           cancel: &CancelToken)
+            -> Result<Vec<ExtractStats>, ValueError> {
+        let globals: Vec<Vec<u8>> = self.globals()?.into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| select.map(|s| s.iter().any(|p| matches_pattern(p, name)))
+                    .unwrap_or(true))
+            .collect();
+        match format {
+            ExtractFormat::Zwr => {
+                writeln!(out, "YottaDB MUPIP EXTRACT")?;
+                writeln!(out, "{} ZWR", extract_timestamp())?;
+            },
+            ExtractFormat::Go => {
+                writeln!(out, "YottaDB MUPIP EXTRACT")?;
+                writeln!(out, "{}", extract_timestamp())?;
+            },
+            ExtractFormat::Binary => write_bin_record(out, &self.bin_header())?,
+        }
+        let capacity = self.block_capacity();
+        let mut blocks_read = 0;
+        let mut ret = Vec::with_capacity(globals.len());
+        for name in globals {
+            let mut stats = ExtractStats { name: name.clone(), ..ExtractStats::default() };
+            let mut start = name;
+            start.push(0);
+            let mut chunk = Vec::new();
+            if format == ExtractFormat::Binary {
+                // Collation method, number of collation tables and version; always the default
+                write_bin_record(out, &[0, 0, 0, 0])?;
+            }
+            let mut nodes = self.nodes(&start)?
+                .watch(progress.clone(), cancel.clone(), blocks_read);
+            for node in nodes.by_ref() {
+                let (key, value) = node?;
+                stats.records += 1;
+                stats.max_key_len = std::cmp::max(stats.max_key_len, key.len());
+                stats.max_value_len = std::cmp::max(stats.max_value_len, value.len());
+                match format {
+                    ExtractFormat::Zwr => {
+                        writeln!(out, "{}={}", format_key(&key), format_value(&value))?;
+                    },
+                    ExtractFormat::Go => {
+                        writeln!(out, "{}", format_key(&key))?;
+                        out.write_all(&value)?;
+                        writeln!(out)?;
+                    },
+                    ExtractFormat::Binary => {
+                        chunk.push(Entry { key: Some(key), value });
+                        if chunk.len() > 1 && block_size(&chunk) > capacity {
+                            let last = chunk.pop().unwrap();
+                            write_bin_entries(out, &chunk)?;
+                            chunk = vec![last];
+                        }
+                    },
+                }
+            }
+            blocks_read = nodes.blocks_read();
+            if !chunk.is_empty() {
+                write_bin_entries(out, &chunk)?;
+            }
+            ret.push(stats);
+        }
+        Ok(ret)
+    }
+}
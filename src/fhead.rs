@@ -0,0 +1,110 @@
+use super::*;
+
+/// Human readable access to the database file header. `sgmnt_data_struct` comes from the
+/// bindings, so this is a trait rather than inherent methods
+pub trait FileHeader {
+    /// Every documented field of the file header as (name, value) pairs, in roughly the order
+    /// DSE DUMP -FILEHEADER shows them
+    fn fields(&self) -> Vec<(&'static str, String)>;
+}
+
+fn tn(tn: u64) -> String {
+    format!("0x{:016X}", tn)
+}
+
+fn boolean(value: i32) -> String {
+    String::from(if value != 0 { "TRUE" } else { "FALSE" })
+}
+
+impl FileHeader for sgmnt_data_struct {
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let label_len = self.label.iter().position(|c| *c == 0).unwrap_or(self.label.len());
+        let jnl_len = std::cmp::min(self.jnl_file_len as usize, self.jnl_file_name.len());
+        let access_method = match self.acc_meth {
+            1 => String::from("BG"),
+            2 => String::from("MM"),
+            x => format!("Unknown ({})", x),
+        };
+        let jnl_state = match self.jnl_state {
+            0 => String::from("DISABLED"),
+            1 => String::from("OFF"),
+            2 => String::from("ON"),
+            x => format!("Unknown ({})", x),
+        };
+        vec![
+            ("Label", String::from_utf8_lossy(&self.label[..label_len]).into_owned()),
+            ("Access method", access_method),
+            ("Block size (in bytes)", self.blk_size.to_string()),
+            ("Blocks per local map", self.bplmap.to_string()),
+            ("Master bitmap size", self.master_map_len.to_string()),
+            ("Starting VBN", self.start_vbn.to_string()),
+            ("Total blocks", self.trans_hist.total_blks.to_string()),
+            ("Free blocks", self.trans_hist.free_blocks.to_string()),
+            ("Extension count", self.extension_size.to_string()),
+            ("Current transaction", tn(self.trans_hist.curr_tn)),
+            ("Early transaction", tn(self.trans_hist.early_tn)),
+            ("Maximum TN", tn(self.max_tn)),
+            ("Maximum TN warn", tn(self.max_tn_warn)),
+            ("Last incremental backup", tn(self.last_inc_backup)),
+            ("Last comprehensive backup", tn(self.last_com_backup)),
+            ("Last record backup", tn(self.last_rec_backup)),
+            ("Reserved bytes", self.reserved_bytes.to_string()),
+            ("Maximum record size", self.max_rec_size.to_string()),
+            ("Maximum key size", self.max_key_size.to_string()),
+            ("Lock space (in bytes)", self.lock_space_size.to_string()),
+            ("Global buffers", self.n_bts.to_string()),
+            ("Default collation", self.def_coll.to_string()),
+            ("Collation version", self.def_coll_ver.to_string()),
+            ("Standard null collation", boolean(self.std_null_coll)),
+            ("Null subscripts", self.null_subs.to_string()),
+            ("Minor database version", self.minor_dbver.to_string()),
+            ("Database file corrupt", boolean(self.file_corrupt)),
+            ("Create in progress", boolean(self.createinprogress)),
+            ("Journal state", jnl_state),
+            ("Journal before imaging", boolean(self.jnl_before_image)),
+            ("Journal allocation", self.jnl_alq.to_string()),
+            ("Journal extension", self.jnl_deq.to_string()),
+            ("Journal buffer size", self.jnl_buffer_size.to_string()),
+            ("Journal alignsize", self.alignsize.to_string()),
+            ("Journal autoswitchlimit", self.autoswitchlimit.to_string()),
+            ("Journal epoch interval", self.epoch_interval.to_string()),
+            ("Journal yield limit", self.yield_lmt.to_string()),
+            ("Journal sync IO", boolean(self.jnl_sync_io)),
+            ("Journal file", String::from_utf8_lossy(&self.jnl_file_name[..jnl_len]).into_owned()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key};
+
+    fn field(db: &Database, name: &str) -> String {
+        db.fhead.fields().into_iter().find(|(n, _)| *n == name).unwrap().1
+    }
+
+    #[test]
+    fn shows_header_fields() {
+        let mut db = TempDb::small();
+        assert_eq!(field(&db, "Label"), "GDSDYNUNX03");
+        assert_eq!(field(&db, "Access method"), "BG");
+        assert_eq!(field(&db, "Block size (in bytes)"), "1024");
+        // 2000 blocks and 4 local bitmaps, with the directory tree using 2 blocks
+        assert_eq!(field(&db, "Total blocks"), "2004");
+        assert_eq!(field(&db, "Free blocks"), "1998");
+        assert_eq!(field(&db, "Current transaction"), "0x0000000000000001");
+        assert_eq!(field(&db, "Standard null collation"), "TRUE");
+        assert_eq!(field(&db, "Journal state"), "DISABLED");
+        assert_eq!(field(&db, "Journal file"), "");
+        db.set(&key("^x(1)"), b"1").unwrap();
+        assert_eq!(field(&db, "Current transaction"), "0x0000000000000002");
+        assert_eq!(field(&db, "Free blocks"), "1996");
+        // Every field has a distinct name
+        let fields = db.fhead.fields();
+        let mut names: Vec<_> = fields.iter().map(|(n, _)| *n).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), fields.len());
+    }
+}
@@ -0,0 +1,576 @@
+use super::*;
+
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender};
+use progress::{Progress, CancelToken};
+use fnv::FnvHashSet;
+use spin::Mutex;
+use threadpool::ThreadPool;
+
+/// Which part of the database `Database::integ` checks
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum IntegScope {
+    /// Every block, starting from the directory tree
+    #[default]
+    All,
+    /// Only the trees of these globals, starting from their roots in the directory tree. Like
+    /// MUPIP INTEG -SUBSCRIPT, the bitmaps are not checked
+    Globals(Vec<Vec<u8>>),
+    /// Only blocks numbered within this range. Index blocks outside of it are still read to
+    /// find the blocks inside it, but nothing is reported for them
+    Blocks(Range<usize>),
+}
+
+impl IntegScope {
+    fn contains(&self, blk_num: usize) -> bool {
+        match self {
+            IntegScope::Blocks(range) => range.contains(&blk_num),
+            _ => true,
+        }
+    }
+}
+
+/// Controls how `Database::integ` scans the database
+#[derive(Debug, Clone)]
+pub struct IntegOptions {
+    /// Number of worker threads used to read and check blocks
+    pub threads: usize,
+    /// If set, blocks updated after this transaction number are listed in the report
+    pub modified_after: Option<u64>,
+    /// Restricts the check to part of the database
+    pub scope: IntegScope,
+    /// Like MUPIP INTEG -FAST, only check the index structure and skip reading data blocks
+    pub fast: bool,
+    /// If set, progress is sent here after every block is checked
+    pub progress: Option<Sender<Progress>>,
+    /// Stops the check early; the report then only covers the blocks checked so far
+    pub cancel: CancelToken,
+}
+
+impl Default for IntegOptions {
+    fn default() -> Self {
+        IntegOptions {
+            threads: 4,
+            modified_after: None,
+            scope: IntegScope::All,
+            fast: false,
+            progress: None,
+            cancel: CancelToken::new(),
+        }
+    }
+}
+
+/// Everything found by `Database::integ`
+#[derive(Debug, Default)]
+pub struct IntegReport {
+    /// Every problem found, in the order the blocks were checked
+    pub findings: Vec<IntegFinding>,
+    /// Blocks updated after `IntegOptions::modified_after`, with the tn they were updated at
+    pub modified: Vec<(usize, u64)>,
+    /// Number of tree blocks which were read and checked
+    pub blocks_checked: usize,
+    /// True if the check was stopped by `IntegOptions::cancel` before finishing
+    pub cancelled: bool,
+    /// Blocks still in the V4 format, which aren't problems until the database is fully
+    /// upgraded but can't be checked, nor the blocks under them
+    pub needs_upgrade: Vec<usize>,
+    /// The part of the database that was checked
+    pub scope: IntegScope,
+}
+
+/// The outcome of checking a single block on a worker thread
+struct BlockResult {
+    blk_num: usize,
+    checked: bool,
+    tn: Option<u64>,
+    children: Vec<IntegBlock>,
+    findings: Vec<IntegFinding>,
+    needs_upgrade: bool,
+}
+
+impl BlockResult {
+    fn new(blk_num: usize) -> BlockResult {
+        BlockResult {
+            blk_num,
+            checked: false,
+            tn: None,
+            children: Vec::new(),
+            findings: Vec::new(),
+            needs_upgrade: false,
+        }
+    }
+}
+
+fn check_block(database: &Mutex<Database>, blk_num: usize, next: &IntegBlock,
+               cancel: &CancelToken) -> BlockResult {
+    let mut result = BlockResult::new(blk_num);
+    // Blocks still waiting in the pool when the check is cancelled report back without reading
+    if cancel.is_cancelled() {
+        return result;
+    }
+    result.checked = true;
+    let (raw, curr_tn, max_key_size, fully_upgraded) = {
+        let database = database.lock();
+        (database.get_block(blk_num), database.current_tn(), database.max_key_size(),
+         database.fully_upgraded())
+    };
+    let raw = match raw {
+        Ok(x) => x,
+        Err(e) => {
+            result.findings.push(IntegFinding { blk_num, error: ValueError::from(e) });
+            return result;
+        }
+    };
+    let blk = match get_valid_block(&raw, blk_num, next.typ.clone(), next.levl, fully_upgraded) {
+        Ok(x) => x,
+        Err(ValueError::BlockError(BlockError::NeedsUpgrade(_))) => {
+            result.needs_upgrade = true;
+            return result;
+        },
+        Err(e) => {
+            result.findings.push(IntegFinding { blk_num, error: e });
+            return result;
+        }
+    };
+    result.tn = Some(blk.header().tn);
+    if let Err(e) = blk.check_tn(curr_tn) {
+        result.findings.push(IntegFinding { blk_num, error: e });
+    }
+    match blk.integ(&next.start, max_key_size) {
+        Ok(children) => result.children = children,
+        Err(e) => result.findings.push(IntegFinding { blk_num, error: e }),
+    }
+    result
+}
+
+fn queue_block(database: &Arc<Mutex<Database>>,
+               pool: &ThreadPool,
+               tx: &Sender<BlockResult>,
+               cancel: &CancelToken,
+               blk_num: usize,
+               blk: IntegBlock) {
+    let database = database.clone();
+    let tx = tx.clone();
+    let cancel = cancel.clone();
+    pool.execute(move || {
+        // A panic checking one block is reported as a finding for it; the walk waits for every
+        // queued block to report back, so one that never did would hang it
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            check_block(&database, blk_num, &blk, &cancel)
+        }));
+        let result = result.unwrap_or_else(|cause| {
+            let message = cause.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| cause.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            let mut result = BlockResult::new(blk_num);
+            result.checked = true;
+            let error = ValueError::CheckPanicked(message);
+            result.findings.push(IntegFinding { blk_num, error });
+            result
+        });
+        // The receiver only goes away once every queued block has reported back
+        tx.send(result).unwrap();
+    });
+}
+
+impl Database {
+    /// Opens a second handle on the same database file, so it can be handed to worker threads
+    pub fn try_clone(&self) -> std::io::Result<Database> {
+        Ok(Database {
+            fhead: self.fhead,
+            master_bitmap: self.master_bitmap.clone(),
+            handle: self.handle.try_clone()?,
+            fill_factor: self.fill_factor,
+            master_map_dirty: None,
+        })
+    }
+
+    /// Runs an integrity check over `options.scope`. The bitmaps are checked first, then the
+    /// directory tree and global trees are walked using `options.threads` workers. Busy blocks
+    /// never reached by the walk are reported as incorrectly marked busy. If the check is
+    /// cancelled, the workers finish the block they are on and the partial report is returned.
+    pub fn integ(&self, options: &IntegOptions) -> Result<IntegReport, ValueError> {
+        let scope = &options.scope;
+        let partial = matches!(scope, IntegScope::Globals(_));
+        let mut report = IntegReport {
+            scope: scope.clone(),
+            ..IntegReport::default()
+        };
+        if !partial {
+            report.findings = self.check_bitmaps()?.into_iter()
+                .filter(|f| scope.contains(f.blk_num))
+                .collect();
+        }
+
+        // Note every block marked busy so we can verify each one is reachable from the
+        // directory tree
+        let mut busy = FnvHashSet::default();
+        let bplmap = self.blocks_per_map();
+        for i in 0..self.local_bitmap_count() {
+            // The 0th block is the local bitmap; skip it
+            for (j, status) in self.local_bitmap(i)?.iter().enumerate().skip(1) {
+                if *status == LocalBitmapStatus::Busy {
+                    busy.insert(i * bplmap + j);
+                }
+            }
+        }
+
+        let root = |blk_num, typ| (blk_num, IntegBlock {
+            blk_num: BlkNum::Block(blk_num),
+            typ,
+            start: vec![],
+            end: vec![],
+            levl: None,
+        });
+        let roots = match scope {
+            IntegScope::Globals(globals) => {
+                let mut roots = Vec::with_capacity(globals.len());
+                for global in globals {
+                    roots.push(root(self.find_global_root(global)?, BlkType::IndexBlock));
+                }
+                roots
+            },
+            _ => vec![root(1, BlkType::DirectoryTree)],
+        };
+
+        let database = Arc::new(Mutex::new(self.try_clone()?));
+        let pool = ThreadPool::new(std::cmp::max(options.threads, 1));
+        let (tx, rx) = channel();
+        let mut visited = FnvHashSet::default();
+        // Each queued block sends exactly one result, so once nothing is outstanding the walk
+        // is complete
+        let mut outstanding = 0;
+        for (blk_num, root) in roots {
+            visited.insert(blk_num);
+            queue_block(&database, &pool, &tx, &options.cancel, blk_num, root);
+            outstanding += 1;
+        }
+        while outstanding > 0 {
+            let result = rx.recv().unwrap();
+            outstanding -= 1;
+            // Only blocks skipped because of a cancel come back unchecked
+            if !result.checked {
+                report.cancelled = true;
+                continue;
+            }
+            // Blocks outside of the scope were only read to find the blocks under them
+            if scope.contains(result.blk_num) {
+                report.blocks_checked += 1;
+                if let (Some(after), Some(tn)) = (options.modified_after, result.tn) {
+                    if tn > after {
+                        report.modified.push((result.blk_num, tn));
+                    }
+                }
+                report.findings.extend(result.findings);
+                if result.needs_upgrade {
+                    report.needs_upgrade.push(result.blk_num);
+                }
+            }
+            if let Some(progress) = &options.progress {
+                // Nobody listening is not a reason to stop checking
+                let _ = progress.send(Progress {
+                    blocks_processed: report.blocks_checked,
+                    total_blocks: self.total_blocks(),
+                });
+            }
+            // Let the blocks already queued drain, but don't queue any more
+            if options.cancel.is_cancelled() {
+                report.cancelled = true;
+                continue;
+            }
+            for child in result.children {
+                let blk_num = match child.blk_num {
+                    BlkNum::Block(x) => x,
+                    // Only records being built for a write point at new or unknown blocks
+                    _ => {
+                        if scope.contains(result.blk_num) {
+                            report.findings.push(IntegFinding {
+                                blk_num: result.blk_num,
+                                error: ValueError::MalformedRecord,
+                            });
+                        }
+                        continue;
+                    },
+                };
+                // TODO: a block referenced twice means the tree has a loop or shared subtree
+                if !visited.insert(blk_num) {
+                    continue;
+                }
+                if !busy.contains(&blk_num) && scope.contains(blk_num) {
+                    report.findings.push(IntegFinding {
+                        blk_num,
+                        error: ValueError::BlockIncorrectlyMarkedFree,
+                    });
+                }
+                // Data blocks are the bulk of the database, so skip them unless needed
+                if child.typ == BlkType::DataBlock
+                        && (options.fast || !scope.contains(blk_num)) {
+                    continue;
+                }
+                queue_block(&database, &pool, &tx, &options.cancel, blk_num, child);
+                outstanding += 1;
+            }
+        }
+
+        // A cancel that lands after the last block was queued still leaves the walk incomplete
+        if options.cancel.is_cancelled() {
+            report.cancelled = true;
+        }
+        // Checking a handful of globals can't tell us who owns the rest of the busy blocks, nor
+        // can a walk which couldn't read the V4 blocks it found
+        if partial || report.cancelled || !report.needs_upgrade.is_empty() {
+            return Ok(report);
+        }
+        let mut unreached: Vec<usize> = busy.into_iter()
+            .filter(|b| !visited.contains(b) && scope.contains(*b))
+            .collect();
+        unreached.sort();
+        for blk_num in unreached {
+            report.findings.push(IntegFinding {
+                blk_num,
+                error: ValueError::BlockIncorrectlyMarkedBusy,
+            });
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use test_util::{TempDb, key, sample_nodes};
+
+    /// A database with a multi-level ^x and a small ^y
+    fn sample_db() -> TempDb {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(1500) {
+            db.set(&key, &value).unwrap();
+        }
+        for i in 0..10 {
+            db.set(&key(&format!("^y({})", i)), b"y").unwrap();
+        }
+        db
+    }
+
+    /// The blocks of `name`'s tree at level `levl`
+    fn blocks_at(db: &Database, name: &[u8], levl: u8) -> Vec<usize> {
+        let root = db.find_global_root(name).unwrap();
+        let mut ret = Vec::new();
+        db.walk_index(root, BlkType::IndexBlock, &mut |blk_num, l| if l == levl {
+            ret.push(blk_num);
+        }).unwrap();
+        ret
+    }
+
+    fn corrupt<F: FnOnce(&mut Vec<u8>)>(db: &mut Database, blk_num: usize, change: F) {
+        let mut raw = db.get_block(blk_num).unwrap();
+        change(&mut raw);
+        db.write_block(blk_num, &raw).unwrap();
+    }
+
+    /// The findings of a check, sorted since the workers finish in any order
+    fn findings(db: &Database, options: &IntegOptions) -> Vec<(usize, String)> {
+        let report = db.integ(options).unwrap();
+        let mut ret: Vec<_> = report.findings.iter()
+            .map(|f| (f.blk_num, format!("{:?}", f.error)))
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    #[test]
+    fn finds_corrupt_records() {
+        let mut db = sample_db();
+        let data = blocks_at(&db, b"x", 0);
+        // The second record of a data block must share some of the first one's key
+        corrupt(&mut db, data[1], |raw| {
+            let first = u16::from_le_bytes([raw[16], raw[17]]) as usize;
+            raw[16 + first + 2] = 0;
+        });
+        // A record running past the end of the block
+        corrupt(&mut db, data[2], |raw| raw[16..18].copy_from_slice(&2000u16.to_le_bytes()));
+        assert_eq!(findings(&db, &IntegOptions::default()), vec![
+            (data[1], String::from("RecordError(ZeroCompressionCount)")),
+            (data[2], String::from("RecordError(TooBig)")),
+        ]);
+    }
+
+    #[test]
+    fn finds_bad_block_headers() {
+        let mut db = sample_db();
+        let data = blocks_at(&db, b"x", 0);
+        corrupt(&mut db, data[0], |raw| raw[4..8].copy_from_slice(&8u32.to_le_bytes()));
+        corrupt(&mut db, data[1], |raw| raw[4..8].copy_from_slice(&2000u32.to_le_bytes()));
+        corrupt(&mut db, data[2], |raw| raw[3] = 1);
+        assert_eq!(findings(&db, &IntegOptions::default()), vec![
+            (data[0], String::from("BlockError(SizeTooSmall(8))")),
+            (data[1], String::from("BlockError(SizeTooLarge(2000))")),
+            (data[2], String::from("BlockError(IncorrectLevel { expected: 0, found: 1 })")),
+        ]);
+    }
+
+    #[test]
+    fn v4_blocks_need_upgrading_until_fully_upgraded() {
+        let mut db = sample_db();
+        let data = blocks_at(&db, b"x", 0);
+        corrupt(&mut db, data[0], |raw| raw[0..2].copy_from_slice(&block::GDSV4.to_le_bytes()));
+        db.fhead.fully_upgraded = 0;
+        let report = db.integ(&IntegOptions::default()).unwrap();
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        assert_eq!(report.needs_upgrade, [data[0]]);
+        db.fhead.fully_upgraded = 1;
+        assert_eq!(findings(&db, &IntegOptions::default()),
+                   [(data[0], String::from("BlockError(UnsupportedVersion(0))"))]);
+    }
+
+    #[test]
+    fn finds_bitmap_problems() {
+        let mut db = sample_db();
+        assert_eq!(db.local_bitmap_count(), 4);
+        // A map with free blocks which the master bitmap says is full
+        db.set_master_map_free(1, false);
+        db.write_header().unwrap();
+        // A full map which the master bitmap says has free blocks
+        corrupt(&mut db, 1024, |raw| {
+            for i in 1..512 {
+                bitmap::set_local_status(raw, i, &LocalBitmapStatus::Busy);
+            }
+        });
+        // A local map header with the wrong level and size
+        corrupt(&mut db, 1536, |raw| {
+            raw[3] = 0;
+            raw[4..8].copy_from_slice(&99u32.to_le_bytes());
+        });
+        let bitmap_findings: Vec<_> = findings(&db, &IntegOptions::default()).into_iter()
+            .filter(|(_, error)| error.starts_with("BitmapError"))
+            .collect();
+        assert_eq!(bitmap_findings, vec![
+            (512, String::from("BitmapError(MasterMapIncorrectlyMarkedFull)")),
+            (1024, String::from("BitmapError(MasterMapIncorrectlyMarkedFree)")),
+            (1536, String::from("BitmapError(IncorrectLevel(0))")),
+            (1536, String::from("BitmapError(IncorrectSize(99))")),
+        ]);
+    }
+
+    #[test]
+    fn checks_transaction_numbers() {
+        let mut db = sample_db();
+        let before = db.current_tn();
+        db.set(&key("^y(99)"), b"new").unwrap();
+        let y = blocks_at(&db, b"y", 0);
+        let options = IntegOptions { modified_after: Some(before - 1), ..IntegOptions::default() };
+        let report = db.integ(&options).unwrap();
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        assert_eq!(report.modified, [(y[0], before)]);
+
+        // A block written at a tn the database hasn't reached yet
+        let data = blocks_at(&db, b"x", 0);
+        let future = db.current_tn() + 100;
+        corrupt(&mut db, data[0], |raw| raw[8..16].copy_from_slice(&future.to_le_bytes()));
+        assert_eq!(findings(&db, &IntegOptions::default()), [
+            (data[0], format!("BlockError(TransactionNumberTooLarge({}))", future)),
+        ]);
+    }
+
+    #[test]
+    fn restricts_to_globals_blocks_or_index_levels() {
+        let mut db = sample_db();
+        let x = blocks_at(&db, b"x", 0);
+        let y = blocks_at(&db, b"y", 0);
+        let zero_cmpc = |raw: &mut Vec<u8>| {
+            let first = u16::from_le_bytes([raw[16], raw[17]]) as usize;
+            raw[16 + first + 2] = 0;
+        };
+        corrupt(&mut db, x[5], zero_cmpc);
+        corrupt(&mut db, y[0], zero_cmpc);
+        db.set_master_map_free(1, false);
+        db.write_header().unwrap();
+        let bad_record = String::from("RecordError(ZeroCompressionCount)");
+        let bad_map = String::from("BitmapError(MasterMapIncorrectlyMarkedFull)");
+        let mut all = vec![(y[0], bad_record.clone()), (x[5], bad_record.clone()),
+                           (512, bad_map.clone())];
+        all.sort();
+        assert_eq!(findings(&db, &IntegOptions::default()), all);
+
+        // Only ^y's tree, and no bitmaps
+        let options = IntegOptions {
+            scope: IntegScope::Globals(vec![b"y".to_vec()]),
+            ..IntegOptions::default()
+        };
+        assert_eq!(findings(&db, &options), [(y[0], bad_record.clone())]);
+        assert_eq!(db.integ(&options).unwrap().blocks_checked, 2);
+
+        // Only the blocks in range, though the index blocks above them are read to find them
+        let options = IntegOptions {
+            scope: IntegScope::Blocks(x[5]..x[5] + 1),
+            ..IntegOptions::default()
+        };
+        assert_eq!(findings(&db, &options), [(x[5], bad_record.clone())]);
+        assert_eq!(db.integ(&options).unwrap().blocks_checked, 1);
+        let options = IntegOptions { scope: IntegScope::Blocks(0..1000), ..options };
+        assert!(findings(&db, &options).iter().all(|(b, _)| *b < 1000));
+
+        // Fast skips the data blocks, but still checks the bitmaps and what points to them
+        let index = blocks_at(&db, b"x", 1).len() + blocks_at(&db, b"x", 2).len()
+            + blocks_at(&db, b"y", 1).len();
+        let options = IntegOptions { fast: true, ..IntegOptions::default() };
+        assert_eq!(findings(&db, &options), [(512, bad_map)]);
+        // Plus the directory tree's root and its one leaf
+        assert_eq!(db.integ(&options).unwrap().blocks_checked, index + 2);
+    }
+
+    #[test]
+    fn reports_progress() {
+        let db = sample_db();
+        let (tx, rx) = channel();
+        let report = db.integ(&IntegOptions { progress: Some(tx), ..IntegOptions::default() })
+            .unwrap();
+        let sent: Vec<Progress> = rx.try_iter().collect();
+        assert_eq!(sent.len(), report.blocks_checked);
+        assert!(sent.iter().enumerate().all(|(i, p)| p.blocks_processed == i + 1));
+        assert!(sent.iter().all(|p| p.total_blocks == db.total_blocks()));
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(20000) {
+            db.set(&key, &value).unwrap();
+        }
+        let full = db.integ(&IntegOptions::default()).unwrap().blocks_checked;
+
+        // Cancelled before starting, nothing is read
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let report = db.integ(&IntegOptions { cancel, ..IntegOptions::default() }).unwrap();
+        assert!(report.cancelled);
+        assert_eq!(report.blocks_checked, 0);
+        assert!(report.findings.is_empty());
+
+        // Cancelled once the first block is done, the blocks already queued drain and the
+        // unreached busy blocks aren't reported
+        let cancel = CancelToken::new();
+        let (tx, rx) = channel();
+        let canceller = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                rx.recv().unwrap();
+                cancel.cancel();
+            })
+        };
+        let options = IntegOptions {
+            threads: 1,
+            progress: Some(tx),
+            cancel,
+            ..IntegOptions::default()
+        };
+        let report = db.integ(&options).unwrap();
+        canceller.join().unwrap();
+        assert!(report.cancelled);
+        assert!(report.blocks_checked > 0 && report.blocks_checked < full);
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+    }
+}
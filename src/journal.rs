@@ -0,0 +1,535 @@
+//! Reading of journal (.mjl) files, for looking at what was done to a database without MUPIP
+//! JOURNAL -EXTRACT.
+//!
+//! A journal file starts with a header, padded to JNL_FILE_FIRST_RECORD bytes, followed by
+//! records aligned to JNL_REC_ALIGN bytes. Each record starts with a prefix giving its type,
+//! its length and the transaction number it belongs to, and ends with a suffix repeating the
+//! length.
+//!
+//! Only the label of the header is decoded. The fields MUPIP JOURNAL -SHOW=HEADER prints, such
+//! as the database file name, the begin and end tn and the previous journal file, sit at offsets
+//! which move between journal versions, and without the jnl_file_header definition or a journal
+//! written by YottaDB to check against, decoding them would mean guessing. The tn range can be
+//! had from the records instead.
+
+use super::*;
+
+use std::io::BufReader;
+
+/// The label at the start of every journal file header, followed by a two digit version
+pub const JNL_LABEL: &[u8] = b"GDSJNL";
+/// Offset of the first record; the header is padded to this size
+pub const JNL_FILE_FIRST_RECORD: usize = 65536;
+/// Every record starts on a multiple of this many bytes
+pub const JNL_REC_ALIGN: usize = 8;
+/// The last byte of every record
+pub const JNL_REC_SUFFIX_CODE: u8 = 0xFE;
+/// Size of the prefix at the start of every record
+const JREC_PREFIX_SIZE: usize = 24;
+/// Size of the suffix at the end of every record
+const JREC_SUFFIX_SIZE: usize = 4;
+/// Offset of the key in SET, KILL and ZKILL records
+const JREC_UPD_KEY: usize = 48;
+/// Offset of the block image in PBLK and AIMG records
+const JREC_BLK_CONTENTS: usize = 40;
+
+/// Record types, as numbered by YottaDB
+const JRT_EOF: u8 = 3;
+const JRT_KILL: u8 = 4;
+const JRT_SET: u8 = 9;
+const JRT_PBLK: u8 = 14;
+const JRT_EPOCH: u8 = 15;
+const JRT_TCOM: u8 = 16;
+const JRT_AIMG: u8 = 18;
+const JRT_ZKILL: u8 = 20;
+const JRT_ALIGN: u8 = 43;
+
+/// The decoded part of a journal file header
+#[derive(Debug, Clone)]
+pub struct JnlHeader {
+    /// The two digits after JNL_LABEL
+    pub version: u32,
+    pub is_little_endian: bool,
+}
+
+/// The fields at the start of every record
+#[derive(Debug, Clone)]
+pub struct JnlPrefix {
+    pub jrec_type: u8,
+    /// Length of the record, prefix and suffix included
+    pub forwptr: usize,
+    /// Offset of the PINI record of the process which wrote the record
+    pub pini_addr: u32,
+    /// Seconds since the Unix epoch
+    pub time: u32,
+    pub checksum: u32,
+    pub tn: u64,
+}
+
+/// Which kind of transaction an update was part of, going by its record type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fence {
+    /// Not in a transaction
+    None,
+    /// In a TSTART transaction; `first` is set on its first update
+    Tp { first: bool },
+    /// In a ZTSTART transaction
+    Ztp { first: bool },
+}
+
+/// A SET, KILL or ZKILL of one node
+#[derive(Debug, Clone)]
+pub struct JnlUpdate {
+    pub fence: Fence,
+    /// Replication sequence number, or the token tying the updates of a transaction together
+    pub token_seq: u64,
+    pub strm_seqno: u64,
+    /// Position of the update within its transaction
+    pub update_num: u32,
+    /// Number of regions taking part in the transaction
+    pub num_participants: u16,
+    /// The node, as a key in the internal format
+    pub key: Vec<u8>,
+}
+
+/// A journal record, decoded as far as its type allows
+#[derive(Debug, Clone)]
+pub enum JnlRecord {
+    /// An image of a block before it was updated
+    Pblk { prefix: JnlPrefix, blk_num: usize, contents: Vec<u8> },
+    /// An image of a block after it was updated
+    Aimg { prefix: JnlPrefix, blk_num: usize, contents: Vec<u8> },
+    Set { prefix: JnlPrefix, update: JnlUpdate, value: Vec<u8> },
+    Kill { prefix: JnlPrefix, update: JnlUpdate },
+    Zkill { prefix: JnlPrefix, update: JnlUpdate },
+    /// The commit of a transaction in one region
+    Tcom { prefix: JnlPrefix, token_seq: u64, strm_seqno: u64, num_participants: u16,
+           tid: Vec<u8> },
+    /// A point at which the database and journal were consistent
+    Epoch { prefix: JnlPrefix, jnl_seqno: u64, free_blocks: u32, total_blks: u32 },
+    /// The end of the journal
+    Eof { prefix: JnlPrefix, jnl_seqno: u64 },
+    /// Any other type of record, such as PINI, PFIN or INCTN, with everything after the prefix
+    Other { prefix: JnlPrefix, data: Vec<u8> },
+}
+
+impl JnlRecord {
+    pub fn prefix(&self) -> &JnlPrefix {
+        match self {
+            JnlRecord::Pblk { prefix, .. } | JnlRecord::Aimg { prefix, .. }
+                | JnlRecord::Set { prefix, .. } | JnlRecord::Kill { prefix, .. }
+                | JnlRecord::Zkill { prefix, .. } | JnlRecord::Tcom { prefix, .. }
+                | JnlRecord::Epoch { prefix, .. } | JnlRecord::Eof { prefix, .. }
+                | JnlRecord::Other { prefix, .. } => prefix,
+        }
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut ret = [0; 4];
+    ret.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(ret)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut ret = [0; 8];
+    ret.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(ret)
+}
+
+/// Checks `len` bytes from `offset` are within the body of `data`, before the suffix
+fn check_len(data: &[u8], offset: usize, len: usize) -> Result<(), ValueError> {
+    if offset + len + JREC_SUFFIX_SIZE > data.len() {
+        return Err(ValueError::InvalidJournal);
+    }
+    Ok(())
+}
+
+/// Decodes the fields shared by SET, KILL and ZKILL records. `data` is the whole record;
+/// returns the update and the offset just past its key
+fn read_update(data: &[u8], offset: u8) -> Result<(JnlUpdate, usize), ValueError> {
+    check_len(data, JREC_UPD_KEY, 4)?;
+    // The length is the low 24 bits; the rest hold flags
+    let len = (u32_at(data, JREC_UPD_KEY) & 0xFF_FFFF) as usize;
+    let start = JREC_UPD_KEY + 4;
+    check_len(data, start, len)?;
+    let mut key = data[start..start + len].to_vec();
+    // The final terminator is left out of the key
+    while !key.ends_with(&[0, 0]) {
+        key.push(0);
+    }
+    let fence = match offset {
+        1 => Fence::Ztp { first: true },
+        2 => Fence::Ztp { first: false },
+        3 => Fence::Tp { first: true },
+        4 => Fence::Tp { first: false },
+        _ => Fence::None,
+    };
+    let update = JnlUpdate {
+        fence,
+        token_seq: u64_at(data, 24),
+        strm_seqno: u64_at(data, 32),
+        update_num: u32_at(data, 40),
+        num_participants: u16_at(data, 46),
+        key,
+    };
+    Ok((update, start + len))
+}
+
+/// Decodes one whole record
+fn read_record(data: Vec<u8>) -> Result<JnlRecord, ValueError> {
+    let prefix = JnlPrefix {
+        jrec_type: data[0],
+        forwptr: data.len(),
+        pini_addr: u32_at(&data, 4),
+        time: u32_at(&data, 8),
+        checksum: u32_at(&data, 12),
+        tn: u64_at(&data, 16),
+    };
+    let ret = match prefix.jrec_type {
+        JRT_PBLK | JRT_AIMG => {
+            check_len(&data, 24, JREC_BLK_CONTENTS - 24)?;
+            let blk_num = u32_at(&data, 24) as usize;
+            let bsiz = u32_at(&data, 28) as usize;
+            check_len(&data, JREC_BLK_CONTENTS, bsiz)?;
+            let contents = data[JREC_BLK_CONTENTS..JREC_BLK_CONTENTS + bsiz].to_vec();
+            if prefix.jrec_type == JRT_PBLK {
+                JnlRecord::Pblk { prefix, blk_num, contents }
+            } else {
+                JnlRecord::Aimg { prefix, blk_num, contents }
+            }
+        },
+        t if (JRT_SET..JRT_SET + 5).contains(&t) => {
+            let (update, end) = read_update(&data, t - JRT_SET)?;
+            check_len(&data, end, 4)?;
+            let len = u32_at(&data, end) as usize;
+            check_len(&data, end + 4, len)?;
+            let value = data[end + 4..end + 4 + len].to_vec();
+            JnlRecord::Set { prefix, update, value }
+        },
+        t if (JRT_KILL..JRT_KILL + 5).contains(&t) => {
+            let (update, _) = read_update(&data, t - JRT_KILL)?;
+            JnlRecord::Kill { prefix, update }
+        },
+        t if (JRT_ZKILL..JRT_ZKILL + 5).contains(&t) => {
+            let (update, _) = read_update(&data, t - JRT_ZKILL)?;
+            JnlRecord::Zkill { prefix, update }
+        },
+        JRT_TCOM => {
+            check_len(&data, 24, 28)?;
+            let tid = &data[44..52];
+            JnlRecord::Tcom {
+                prefix,
+                token_seq: u64_at(&data, 24),
+                strm_seqno: u64_at(&data, 32),
+                num_participants: u16_at(&data, 42),
+                tid: tid[..tid.iter().position(|c| *c == 0).unwrap_or(tid.len())].to_vec(),
+            }
+        },
+        JRT_EPOCH => {
+            check_len(&data, 24, 20)?;
+            JnlRecord::Epoch {
+                prefix,
+                jnl_seqno: u64_at(&data, 24),
+                free_blocks: u32_at(&data, 36),
+                total_blks: u32_at(&data, 40),
+            }
+        },
+        JRT_EOF => {
+            check_len(&data, 24, 8)?;
+            JnlRecord::Eof { prefix, jnl_seqno: u64_at(&data, 24) }
+        },
+        _ => {
+            let end = data.len() - JREC_SUFFIX_SIZE;
+            JnlRecord::Other { data: data[JREC_PREFIX_SIZE..end].to_vec(), prefix }
+        },
+    };
+    Ok(ret)
+}
+
+/// Reads the records of a journal file in order. ALIGN records, which only pad the file, are
+/// skipped, and iteration stops after the EOF record or at the end of what has been written
+pub struct JnlReader<R: Read> {
+    input: R,
+    pub header: JnlHeader,
+    /// Offset of the next record in the file
+    offset: usize,
+    done: bool,
+}
+
+impl<R: Read> JnlReader<R> {
+    /// Reads the header from `input`, which must be at the start of a journal file
+    pub fn new(mut input: R) -> Result<JnlReader<R>, ValueError> {
+        let mut label = [0; 9];
+        input.read_exact(&mut label)?;
+        if !label.starts_with(JNL_LABEL) {
+            return Err(ValueError::InvalidJournal);
+        }
+        let version = std::str::from_utf8(&label[JNL_LABEL.len()..JNL_LABEL.len() + 2]).ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or(ValueError::InvalidJournal)?;
+        let header = JnlHeader { version, is_little_endian: label[8] != 0 };
+        if !header.is_little_endian {
+            return Err(ValueError::InvalidJournal);
+        }
+        let skip = (JNL_FILE_FIRST_RECORD - label.len()) as u64;
+        if std::io::copy(&mut (&mut input).take(skip), &mut std::io::sink())? != skip {
+            return Err(ValueError::InvalidJournal);
+        }
+        Ok(JnlReader { input, header, offset: JNL_FILE_FIRST_RECORD, done: false })
+    }
+
+    /// Offset in the file of the next record to be read
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Reads the next record, whatever its type. Returns None at the end of the journal
+    fn next_record(&mut self) -> Result<Option<JnlRecord>, ValueError> {
+        let mut data = vec![0; JREC_PREFIX_SIZE];
+        // Running out exactly between records is the end of the file; part way through a
+        // prefix, the file was cut short
+        let mut filled = 0;
+        while filled < data.len() {
+            match self.input.read(&mut data[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(ValueError::from(e)),
+            }
+        }
+        if filled == 0 {
+            return Ok(None);
+        }
+        if filled < data.len() {
+            return Err(ValueError::InvalidJournal);
+        }
+        // The type is the low 8 bits of the first word and the length the rest
+        let len = (u32_at(&data, 0) >> 8) as usize;
+        if len == 0 {
+            // Space allocated for the journal but not yet written
+            return Ok(None);
+        }
+        if !len.is_multiple_of(JNL_REC_ALIGN) || len < JREC_PREFIX_SIZE + JREC_SUFFIX_SIZE {
+            return Err(ValueError::InvalidJournal);
+        }
+        data.resize(len, 0);
+        match self.input.read_exact(&mut data[JREC_PREFIX_SIZE..]) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(ValueError::InvalidJournal);
+            },
+            x => x?,
+        }
+        let suffix = u32_at(&data, len - JREC_SUFFIX_SIZE);
+        if (suffix >> 24) as u8 != JNL_REC_SUFFIX_CODE || (suffix & 0xFF_FFFF) as usize != len {
+            return Err(ValueError::InvalidJournal);
+        }
+        self.offset += len;
+        read_record(data).map(Some)
+    }
+}
+
+impl<R: Read> Iterator for JnlReader<R> {
+    type Item = Result<JnlRecord, ValueError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let ret = match self.next_record() {
+                Ok(Some(ref record)) if record.prefix().jrec_type == JRT_ALIGN => continue,
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            };
+            if ret.prefix().jrec_type == JRT_EOF {
+                self.done = true;
+            }
+            return Some(Ok(ret));
+        }
+        self.done = true;
+        None
+    }
+}
+
+/// Opens the journal file at `path` and reads its header
+pub fn open_journal(path: &str) -> Result<JnlReader<BufReader<File>>, ValueError> {
+    JnlReader::new(BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    //! No journal file written by YottaDB is available to these tests, so the journals here are
+    //! built by hand, following the same record layouts the reader assumes
+    use super::*;
+    use test_util::key;
+
+    fn header() -> Vec<u8> {
+        let mut ret = Vec::from(JNL_LABEL);
+        ret.extend(b"44\x01");
+        ret.resize(JNL_FILE_FIRST_RECORD, 0);
+        ret
+    }
+
+    /// A record of type `jrec_type` with `body` after the prefix, padded and given its suffix
+    fn record(jrec_type: u8, tn: u64, body: &[u8]) -> Vec<u8> {
+        let len = (JREC_PREFIX_SIZE + body.len() + JREC_SUFFIX_SIZE)
+            .next_multiple_of(JNL_REC_ALIGN);
+        let mut ret = (jrec_type as u32 | (len as u32) << 8).to_le_bytes().to_vec();
+        ret.extend(&7u32.to_le_bytes());
+        ret.extend(&1_700_000_000u32.to_le_bytes());
+        ret.extend(&0u32.to_le_bytes());
+        ret.extend(&tn.to_le_bytes());
+        ret.extend(body);
+        ret.resize(len - JREC_SUFFIX_SIZE, 0);
+        ret.extend(&(len as u32 | (JNL_REC_SUFFIX_CODE as u32) << 24).to_le_bytes());
+        ret
+    }
+
+    /// The body of a SET, KILL or ZKILL of `key`, which is stored without its final terminator
+    fn update(token_seq: u64, update_num: u32, key: &[u8]) -> Vec<u8> {
+        let mut ret = token_seq.to_le_bytes().to_vec();
+        ret.extend(&0u64.to_le_bytes());
+        ret.extend(&update_num.to_le_bytes());
+        ret.extend(&[0, 0]);
+        ret.extend(&1u16.to_le_bytes());
+        let key = &key[..key.len() - 1];
+        ret.extend(&(key.len() as u32).to_le_bytes());
+        ret.extend(key);
+        ret
+    }
+
+    fn journal() -> Vec<u8> {
+        let mut ret = header();
+        let mut epoch = 5u64.to_le_bytes().to_vec();
+        epoch.extend(&[0; 4]);
+        epoch.extend(&100u32.to_le_bytes());
+        epoch.extend(&120u32.to_le_bytes());
+        ret.extend(record(JRT_EPOCH, 10, &epoch));
+        let mut pblk = 3u32.to_le_bytes().to_vec();
+        pblk.extend(&4u32.to_le_bytes());
+        pblk.extend(&[0; 8]);
+        pblk.extend(b"blk!");
+        ret.extend(record(JRT_PBLK, 10, &pblk));
+        // A transaction: a SET and a KILL, then the commit
+        let mut set = update(42, 1, &key("^x(1,\"a\")"));
+        set.extend(&5u32.to_le_bytes());
+        set.extend(b"hello");
+        ret.extend(record(JRT_SET + 3, 10, &set));
+        ret.extend(record(JRT_KILL + 4, 10, &update(42, 2, &key("^y"))));
+        let mut tcom = 42u64.to_le_bytes().to_vec();
+        tcom.extend(&0u64.to_le_bytes());
+        tcom.extend(&[0, 0]);
+        tcom.extend(&1u16.to_le_bytes());
+        tcom.extend(b"BATCH\0\0\0");
+        ret.extend(record(JRT_TCOM, 10, &tcom));
+        ret.extend(record(JRT_ALIGN, 0, &[0; 64]));
+        ret.extend(record(JRT_ZKILL, 11, &update(0, 0, &key("^x(2)"))));
+        ret.extend(record(JRT_EOF, 12, &6u64.to_le_bytes()));
+        // Space allocated past the end of the journal
+        ret.extend(&[0; 256]);
+        ret
+    }
+
+    #[test]
+    fn decodes_each_record_type() {
+        let journal = journal();
+        let mut reader = JnlReader::new(journal.as_slice()).unwrap();
+        assert_eq!(reader.header.version, 44);
+        assert_eq!(reader.offset(), JNL_FILE_FIRST_RECORD);
+        let records: Vec<_> = reader.by_ref().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 7);
+        match &records[0] {
+            JnlRecord::Epoch { prefix, jnl_seqno, free_blocks, total_blks } => {
+                assert_eq!((prefix.tn, prefix.pini_addr, prefix.time), (10, 7, 1_700_000_000));
+                assert_eq!((*jnl_seqno, *free_blocks, *total_blks), (5, 100, 120));
+            },
+            other => panic!("{:?}", other),
+        }
+        match &records[1] {
+            JnlRecord::Pblk { blk_num, contents, .. } => {
+                assert_eq!((*blk_num, contents.as_slice()), (3, &b"blk!"[..]));
+            },
+            other => panic!("{:?}", other),
+        }
+        match &records[2] {
+            JnlRecord::Set { update, value, .. } => {
+                assert_eq!(update.fence, Fence::Tp { first: true });
+                assert_eq!((update.token_seq, update.update_num), (42, 1));
+                assert_eq!(update.key, key("^x(1,\"a\")"));
+                assert_eq!(value, b"hello");
+            },
+            other => panic!("{:?}", other),
+        }
+        match &records[3] {
+            JnlRecord::Kill { update, .. } => {
+                assert_eq!(update.fence, Fence::Tp { first: false });
+                assert_eq!(update.key, key("^y"));
+            },
+            other => panic!("{:?}", other),
+        }
+        match &records[4] {
+            JnlRecord::Tcom { token_seq, num_participants, tid, .. } => {
+                assert_eq!((*token_seq, *num_participants, tid.as_slice()), (42, 1, &b"BATCH"[..]));
+            },
+            other => panic!("{:?}", other),
+        }
+        // The ALIGN record is skipped
+        match &records[5] {
+            JnlRecord::Zkill { prefix, update } => {
+                assert_eq!(prefix.tn, 11);
+                assert_eq!(update.fence, Fence::None);
+                assert_eq!(update.key, key("^x(2)"));
+            },
+            other => panic!("{:?}", other),
+        }
+        match &records[6] {
+            JnlRecord::Eof { jnl_seqno, .. } => assert_eq!(*jnl_seqno, 6),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(reader.offset(), journal.len() - 256);
+    }
+
+    #[test]
+    fn stops_where_writing_stopped() {
+        // Without an EOF record, the unwritten space ends the journal
+        let mut journal = journal();
+        let eof = journal.len() - 256 - 40;
+        journal.truncate(eof);
+        journal.extend(&[0; 64]);
+        let records: Vec<_> = JnlReader::new(journal.as_slice()).unwrap().collect();
+        assert_eq!(records.len(), 6);
+        assert!(records.iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn rejects_damaged_journals() {
+        let journal = journal();
+        // Not a journal at all
+        assert!(matches!(JnlReader::new(&b"GDSDYNUNX03"[..]), Err(ValueError::InvalidJournal)));
+        // Cut off in the middle of the PBLK record, after its prefix and inside it
+        for len in [30, 10].iter() {
+            let cut = &journal[..JNL_FILE_FIRST_RECORD + 48 + len];
+            let records: Vec<_> = JnlReader::new(cut).unwrap().collect();
+            assert_eq!(records.len(), 2);
+            assert!(records[0].is_ok());
+            assert!(matches!(records[1], Err(ValueError::InvalidJournal)));
+        }
+        // Cut off between records, the file just ends
+        let cut = &journal[..JNL_FILE_FIRST_RECORD + 48];
+        let records: Vec<_> = JnlReader::new(cut).unwrap().collect();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_ok());
+        // A suffix that doesn't match the prefix's length
+        let mut bad = journal.clone();
+        bad[JNL_FILE_FIRST_RECORD + 48 - 1] = 0;
+        let mut reader = JnlReader::new(bad.as_slice()).unwrap();
+        assert!(matches!(reader.next(), Some(Err(ValueError::InvalidJournal))));
+        assert!(reader.next().is_none());
+    }
+}
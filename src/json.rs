@@ -0,0 +1,227 @@
+//! Export of a global subtree as JSON, for consumers that don't speak M.
+//!
+//! The nested format turns each node into an object keyed by its subscripts, with the node's
+//! own value, if it has one, under JSON_VALUE_FIELD. Leaves are objects too, so a value can
+//! never be mistaken for a subtree. The lines format writes one object per node instead,
+//! holding the full list of subscripts and the value, so it can be processed a line at a time.
+//! Canonical numbers are written as JSON numbers and everything else as strings.
+
+use super::*;
+
+use std::io::Write;
+
+use self::key::{decode_key, is_canonical_number, Subscript};
+
+/// The field holding the value of a node, in the nested format
+pub const JSON_VALUE_FIELD: &str = "$value";
+
+/// How `Database::export_json` lays out the nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonFormat {
+    /// A single document nesting each subscript inside its parent
+    Nested,
+    /// Newline delimited JSON: one {"key": [...], "value": ...} object per node
+    Lines,
+}
+
+/// Writes `s` as a JSON string. Text that isn't UTF-8 is read a byte at a time, as Latin-1
+fn write_string<W: Write>(out: &mut W, s: &[u8]) -> std::io::Result<()> {
+    let text = match std::str::from_utf8(s) {
+        Ok(text) => std::borrow::Cow::Borrowed(text),
+        Err(_) => std::borrow::Cow::Owned(s.iter().map(|c| *c as char).collect::<String>()),
+    };
+    let mut ret = String::with_capacity(text.len() + 2);
+    ret.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    out.write_all(ret.as_bytes())
+}
+
+/// Writes a canonical M number as a JSON number, which needs a digit before the point
+fn write_number<W: Write>(out: &mut W, n: &[u8]) -> std::io::Result<()> {
+    match n {
+        [b'-', b'.', ..] => write!(out, "-0{}", String::from_utf8_lossy(&n[1..])),
+        [b'.', ..] => write!(out, "0{}", String::from_utf8_lossy(n)),
+        _ => out.write_all(n),
+    }
+}
+
+/// Writes a node value: a JSON number if M would treat it as one, otherwise a string
+fn write_value<W: Write>(out: &mut W, value: &[u8]) -> std::io::Result<()> {
+    if is_canonical_number(value) {
+        write_number(out, value)
+    } else {
+        write_string(out, value)
+    }
+}
+
+/// Writes a subscript as an object key in the nested format
+fn write_field<W: Write>(out: &mut W, sub: &Subscript) -> std::io::Result<()> {
+    match sub {
+        Subscript::Number(n) => write_string(out, n.as_bytes())?,
+        Subscript::Str(s) => write_string(out, s)?,
+    }
+    out.write_all(b":")
+}
+
+impl Database {
+    /// Writes the node `key` and everything under it to `out` as JSON, reading the nodes in
+    /// order without holding more than one block's worth in memory. Returns the number of nodes
+    /// written; if there are none, the nested format writes null
+    pub fn export_json<W: Write>(&self, out: &mut W, key: &[u8], format: JsonFormat)
+            -> Result<usize, ValueError> {
+        // Descendants share everything but the final terminator
+        let prefix = &key[..key.len().saturating_sub(1)];
+        let depth = decode_key(key).1.len();
+        let nodes = self.nodes(prefix)?
+            .take_while(|node| node.as_ref().map(|(k, _)| k.starts_with(prefix)).unwrap_or(true));
+        let mut count = 0;
+        // Subscripts of the objects currently open, below `key`, and whether each has a member
+        let mut open: Vec<Subscript> = Vec::new();
+        let mut members = vec![false];
+        for node in nodes {
+            let (k, value) = node?;
+            count += 1;
+            let subs = decode_key(&k).1;
+            if format == JsonFormat::Lines {
+                out.write_all(b"{\"key\":[")?;
+                for (i, sub) in subs.iter().enumerate() {
+                    if i > 0 {
+                        out.write_all(b",")?;
+                    }
+                    match sub {
+                        Subscript::Number(n) => write_number(out, n.as_bytes())?,
+                        Subscript::Str(s) => write_string(out, s)?,
+                    }
+                }
+                out.write_all(b"],\"value\":")?;
+                write_value(out, &value)?;
+                out.write_all(b"}\n")?;
+                continue;
+            }
+            if count == 1 {
+                out.write_all(b"{")?;
+            }
+            let subs = &subs[depth..];
+            let shared = open.iter().zip(subs).take_while(|(a, b)| a == b).count();
+            while open.len() > shared {
+                open.pop();
+                members.pop();
+                out.write_all(b"}")?;
+            }
+            for sub in subs[shared..].iter() {
+                if *members.last().unwrap() {
+                    out.write_all(b",")?;
+                }
+                *members.last_mut().unwrap() = true;
+                write_field(out, sub)?;
+                out.write_all(b"{")?;
+                open.push(sub.clone());
+                members.push(false);
+            }
+            // A node sorts before its children, so its value is always the first member
+            write_string(out, JSON_VALUE_FIELD.as_bytes())?;
+            out.write_all(b":")?;
+            write_value(out, &value)?;
+            *members.last_mut().unwrap() = true;
+        }
+        if format == JsonFormat::Nested {
+            for _ in 0..open.len() {
+                out.write_all(b"}")?;
+            }
+            match count {
+                0 => out.write_all(b"null")?,
+                _ => out.write_all(b"}")?,
+            }
+            out.write_all(b"\n")?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key};
+
+    fn export(db: &Database, reference: &str, format: JsonFormat) -> (String, usize) {
+        let mut out = Vec::new();
+        let count = db.export_json(&mut out, &key(reference), format).unwrap();
+        (String::from_utf8(out).unwrap(), count)
+    }
+
+    fn sample() -> TempDb {
+        let mut db = TempDb::small();
+        for (reference, value) in [("^x", "1"), ("^x(1)", "a"), ("^x(1,\"b\")", ".5"),
+                                   ("^x(2,\"c\",\"d\")", "-.5"), ("^x(\"s\")", "text"),
+                                   ("^y(1)", "other")].iter() {
+            db.set(&key(reference), value.as_bytes()).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn nests_every_node_as_an_object() {
+        let db = sample();
+        assert_eq!(export(&db, "^x", JsonFormat::Nested),
+                   (String::from("{\"$value\":1,\"1\":{\"$value\":\"a\",\"b\":{\"$value\":0.5}},\
+                                  \"2\":{\"c\":{\"d\":{\"$value\":-0.5}}},\
+                                  \"s\":{\"$value\":\"text\"}}\n"), 5));
+        // Nodes with only descendants have no $value
+        assert_eq!(export(&db, "^x(2)", JsonFormat::Nested).0,
+                   "{\"c\":{\"d\":{\"$value\":-0.5}}}\n");
+        // A leaf is still an object
+        assert_eq!(export(&db, "^x(1,\"b\")", JsonFormat::Nested).0, "{\"$value\":0.5}\n");
+        assert_eq!(export(&db, "^x(9)", JsonFormat::Nested), (String::from("null\n"), 0));
+        assert_eq!(export(&db, "^nosuch", JsonFormat::Nested), (String::from("null\n"), 0));
+    }
+
+    #[test]
+    fn writes_a_line_per_node() {
+        let db = sample();
+        let (lines, count) = export(&db, "^x(1)", JsonFormat::Lines);
+        assert_eq!(count, 2);
+        assert_eq!(lines, "{\"key\":[1],\"value\":\"a\"}\n\
+                           {\"key\":[1,\"b\"],\"value\":0.5}\n");
+        let (lines, _) = export(&db, "^x(2)", JsonFormat::Lines);
+        assert_eq!(lines, "{\"key\":[2,\"c\",\"d\"],\"value\":-0.5}\n");
+        assert_eq!(export(&db, "^x(9)", JsonFormat::Lines), (String::new(), 0));
+    }
+
+    #[test]
+    fn escapes_strings() {
+        let mut out = Vec::new();
+        write_string(&mut out, b"q\"b\\n\nr\rt\t\x01\x1F\x7Fe").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "\"q\\\"b\\\\n\\nr\\rt\\t\\u0001\\u001f\x7Fe\"");
+        // UTF-8 passes through; anything else is taken as Latin-1
+        let mut out = Vec::new();
+        write_string(&mut out, "caf\u{e9}".as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\"caf\u{e9}\"");
+        let mut out = Vec::new();
+        write_string(&mut out, b"caf\xE9\xFF").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\"caf\u{e9}\u{ff}\"");
+    }
+
+    #[test]
+    fn writes_json_numbers() {
+        let cases = [(".5", "0.5"), ("-.5", "-0.5"), ("-.001", "-0.001"), ("12", "12"),
+                     ("-3.25", "-3.25"), ("0", "0"), ("0.5", "\"0.5\""), ("1E3", "\"1E3\""),
+                     ("007", "\"007\"")];
+        for (value, expected) in cases.iter() {
+            let mut out = Vec::new();
+            write_value(&mut out, value.as_bytes()).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), *expected, "{}", value);
+        }
+    }
+}
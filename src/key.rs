@@ -0,0 +1,694 @@
+//! Conversion between the internal key format stored in blocks and M global references.
+//!
+//! A key is the global name followed by a 0 byte, then each subscript followed by a 0 byte, with
+//! one more 0 byte at the end. String subscripts start with 0xFF and escape 0 and 1 bytes with a
+//! 1 prefix. Numbers start with an exponent byte above 0x80, followed by pairs of decimal
+//! digits packed so that no byte is ever 0; negative numbers are stored complemented so they
+//! sort before positive ones.
+
+/// Prefix for a string subscript
+const STR_SUB_PREFIX: u8 = 0xFF;
+/// Escapes a 0 or 1 inside a string subscript
+const STR_SUB_ESCAPE: u8 = 0x01;
+/// The empty string subscript under standard null collation
+const SUBSCRIPT_STDCOL_NULL: u8 = 0x01;
+/// The number zero
+const SUBSCRIPT_ZERO: u8 = 0x80;
+/// Exponent byte of a positive number between 0.1 and 1
+const SUBSCRIPT_BIAS: u8 = 0xBE;
+/// Ends the mantissa of a negative number
+const NEG_MNTSSA_END: u8 = 0xFF;
+/// Numbers with more significant digits than this are stored as strings
+const NUMERIC_PRECISION: usize = 18;
+/// Numbers must be below 1E47; M reports NUMOFLOW for anything larger
+const MAX_INT_DIGITS: usize = 47;
+/// Numbers below 1E-43 underflow to 0, so at most this many zeros can follow the point
+const MAX_LEADING_ZEROS: usize = 42;
+
+/// A single decoded subscript
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subscript {
+    /// A number, kept as its canonical M representation
+    Number(String),
+    Str(Vec<u8>),
+}
+
+/// Returns true if `s` is a number in canonical M form, which is how M decides whether a
+/// subscript or value is stored as a number. Numbers outside the range M can hold, 1E-43 to
+/// 1E47, are never canonical
+pub fn is_canonical_number(s: &[u8]) -> bool {
+    if s == b"0" {
+        return true;
+    }
+    let digits = if s.first() == Some(&b'-') { &s[1..] } else { s };
+    if digits.is_empty() {
+        return false;
+    }
+    let (int, frac) = match digits.iter().position(|c| *c == b'.') {
+        Some(x) => (&digits[..x], Some(&digits[x + 1..])),
+        None => (digits, None),
+    };
+    if !int.iter().all(|c| c.is_ascii_digit()) || int.first() == Some(&b'0') {
+        return false;
+    }
+    let significant = match frac {
+        Some(frac) => {
+            if frac.is_empty() || !frac.iter().all(|c| c.is_ascii_digit())
+                    || frac.last() == Some(&b'0') {
+                return false;
+            }
+            // Leading zeros of a fraction like .001 are not significant
+            let leading = if int.is_empty() {
+                frac.iter().take_while(|c| **c == b'0').count()
+            } else {
+                0
+            };
+            if leading > MAX_LEADING_ZEROS {
+                return false;
+            }
+            int.len() + frac.len() - leading
+        },
+        None => int.len() - int.iter().rev().take_while(|c| **c == b'0').count(),
+    };
+    significant <= NUMERIC_PRECISION && int.len() <= MAX_INT_DIGITS
+}
+
+/// Builds the canonical string for 0.`digits` * 10^`exp`
+fn format_number(negative: bool, digits: &[u8], exp: i32) -> String {
+    let mut digits = digits.to_vec();
+    while digits.last() == Some(&b'0') {
+        digits.pop();
+    }
+    let mut ret = String::new();
+    if negative {
+        ret.push('-');
+    }
+    let digits = String::from_utf8(digits).unwrap();
+    if exp <= 0 {
+        ret.push('.');
+        ret.push_str(&"0".repeat(-exp as usize));
+        ret.push_str(&digits);
+    } else if exp as usize >= digits.len() {
+        ret.push_str(&digits);
+        ret.push_str(&"0".repeat(exp as usize - digits.len()));
+    } else {
+        ret.push_str(&digits[..exp as usize]);
+        ret.push('.');
+        ret.push_str(&digits[exp as usize..]);
+    }
+    ret
+}
+
+/// Decodes a single subscript, without its terminating 0 byte
+pub fn decode_subscript(sub: &[u8]) -> Subscript {
+    match sub.first() {
+        None => Subscript::Str(vec![]),
+        Some(&STR_SUB_PREFIX) => {
+            let mut ret = Vec::with_capacity(sub.len());
+            let mut i = 1;
+            while i < sub.len() {
+                if sub[i] == STR_SUB_ESCAPE && i + 1 < sub.len() {
+                    ret.push(sub[i + 1].wrapping_sub(1));
+                    i += 2;
+                } else {
+                    ret.push(sub[i]);
+                    i += 1;
+                }
+            }
+            Subscript::Str(ret)
+        },
+        Some(&SUBSCRIPT_STDCOL_NULL) if sub.len() == 1 => Subscript::Str(vec![]),
+        Some(&SUBSCRIPT_ZERO) => Subscript::Number(String::from("0")),
+        Some(&exp) => {
+            let negative = exp < SUBSCRIPT_ZERO;
+            let (exp, mantissa) = if negative {
+                let end = sub.iter().position(|c| *c == NEG_MNTSSA_END).unwrap_or(sub.len());
+                (!exp, sub[1..end].iter().map(|c| !c).collect::<Vec<u8>>())
+            } else {
+                (exp, sub[1..].to_vec())
+            };
+            let mut digits = Vec::with_capacity(mantissa.len() * 2);
+            for byte in mantissa {
+                digits.push(b'0' + (byte >> 4));
+                digits.push(b'0' + (byte & 0x0F).saturating_sub(1));
+            }
+            Subscript::Number(format_number(negative, &digits, exp as i32 - SUBSCRIPT_BIAS as i32))
+        },
+    }
+}
+
+/// Splits a key into the global name and its decoded subscripts
+pub fn decode_key(key: &[u8]) -> (Vec<u8>, Vec<Subscript>) {
+    let mut parts = key.split(|c| *c == 0);
+    let name = parts.next().unwrap_or(&[]).to_vec();
+    // The key ends with an empty part for each of the two terminating 0 bytes
+    let subs = parts.take_while(|p| !p.is_empty()).map(decode_subscript).collect();
+    (name, subs)
+}
+
+/// Formats a string the way ZWRITE does: in quotes, with quotes doubled and non-printable
+/// characters written as $C() calls. Bytes past ASCII are written as $ZCH() calls, which the
+/// parser reads back as bytes rather than as code points
+pub fn quote_string(s: &[u8]) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    let mut in_quotes = false;
+    // The function of the call just written, if the string so far ends with one
+    let mut last_call = None;
+    for c in s {
+        let printable = *c >= 0x20 && *c < 0x7F;
+        if printable && !in_quotes {
+            if !ret.is_empty() {
+                ret.push('_');
+            }
+            ret.push('"');
+            in_quotes = true;
+            last_call = None;
+        } else if !printable {
+            if in_quotes {
+                ret.push('"');
+                in_quotes = false;
+            }
+            let function = if *c < 0x80 { "$C" } else { "$ZCH" };
+            if last_call == Some(function) {
+                // Merge runs of non-printable characters into one call
+                ret.pop();
+                ret.push_str(&format!(",{})", c));
+            } else {
+                if !ret.is_empty() {
+                    ret.push('_');
+                }
+                ret.push_str(&format!("{}({})", function, c));
+                last_call = Some(function);
+            }
+            continue;
+        }
+        if *c == b'"' {
+            ret.push('"');
+        }
+        ret.push(*c as char);
+    }
+    if in_quotes {
+        ret.push('"');
+    }
+    if ret.is_empty() {
+        ret.push_str("\"\"");
+    }
+    ret
+}
+
+/// Formats a value as ZWRITE would: canonical numbers bare, anything else quoted
+pub fn format_value(value: &[u8]) -> String {
+    if is_canonical_number(value) {
+        String::from_utf8_lossy(value).into_owned()
+    } else {
+        quote_string(value)
+    }
+}
+
+/// Formats a subscript as it appears in a reference: numbers bare, strings quoted
+pub fn format_subscript(sub: &Subscript) -> String {
+    match sub {
+        Subscript::Number(n) => n.clone(),
+        Subscript::Str(s) => quote_string(s),
+    }
+}
+
+/// Formats a key as an M global reference, like ^ACCT(1,"name")
+pub fn format_key(key: &[u8]) -> String {
+    let (name, subs) = decode_key(key);
+    let mut ret = format!("^{}", String::from_utf8_lossy(&name));
+    if !subs.is_empty() {
+        let subs: Vec<String> = subs.iter().map(format_subscript).collect();
+        ret.push('(');
+        ret.push_str(&subs.join(","));
+        ret.push(')');
+    }
+    ret
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyError {
+    /// The reference does not start with a valid global name
+    InvalidName,
+    /// A subscript or value could not be parsed; holds the offset where parsing stopped
+    InvalidExpression(usize),
+    /// Something other than the end of input followed the reference; holds its offset
+    TrailingCharacters(usize),
+    /// A numeric literal is 1E47 or larger, which M reports as NUMOFLOW; holds its offset
+    NumericOverflow(usize),
+    /// A key in the internal format doesn't end with the two 0 byte terminators
+    Unterminated,
+}
+
+/// Encodes a single subscript, without its terminating 0 byte. Canonical numbers are stored as
+/// numbers and everything else as strings, as M does. Canonical numbers are in range, so the
+/// exponent always fits between `SUBSCRIPT_ZERO` and 0xFF
+pub fn encode_subscript(sub: &[u8], std_null_coll: bool) -> Vec<u8> {
+    if sub.is_empty() {
+        return vec![if std_null_coll { SUBSCRIPT_STDCOL_NULL } else { STR_SUB_PREFIX }];
+    }
+    if !is_canonical_number(sub) {
+        let mut ret = Vec::with_capacity(sub.len() + 1);
+        ret.push(STR_SUB_PREFIX);
+        for c in sub {
+            if *c == 0 || *c == STR_SUB_ESCAPE {
+                ret.push(STR_SUB_ESCAPE);
+                ret.push(c + 1);
+            } else {
+                ret.push(*c);
+            }
+        }
+        return ret;
+    }
+    if sub == b"0" {
+        return vec![SUBSCRIPT_ZERO];
+    }
+    let negative = sub[0] == b'-';
+    let sub = if negative { &sub[1..] } else { sub };
+    let (int, frac) = match sub.iter().position(|c| *c == b'.') {
+        Some(x) => (&sub[..x], &sub[x + 1..]),
+        None => (sub, &sub[sub.len()..]),
+    };
+    // Normalize to 0.digits * 10^exp
+    let (mut digits, exp) = if int.is_empty() {
+        let leading = frac.iter().take_while(|c| **c == b'0').count();
+        (frac[leading..].to_vec(), -(leading as i32))
+    } else {
+        let mut digits = int.to_vec();
+        digits.extend(frac);
+        (digits, int.len() as i32)
+    };
+    while digits.last() == Some(&b'0') {
+        digits.pop();
+    }
+    let mut ret = Vec::with_capacity(digits.len() / 2 + 3);
+    debug_assert!(-(MAX_LEADING_ZEROS as i32) <= exp && exp <= MAX_INT_DIGITS as i32);
+    ret.push((SUBSCRIPT_BIAS as i32 + exp) as u8);
+    for pair in digits.chunks(2) {
+        let high = pair[0] - b'0';
+        let low = pair.get(1).map(|c| c - b'0').unwrap_or(0);
+        ret.push(high << 4 | (low + 1));
+    }
+    if negative {
+        for byte in ret.iter_mut() {
+            *byte = !*byte;
+        }
+        ret.push(NEG_MNTSSA_END);
+    }
+    ret
+}
+
+/// Builds the internal key for a global name and subscripts
+pub fn encode_key(name: &[u8], subs: &[Vec<u8>], std_null_coll: bool) -> Vec<u8> {
+    let mut key = Vec::from(name);
+    key.push(0);
+    for sub in subs {
+        key.extend(encode_subscript(sub, std_null_coll));
+        key.push(0);
+    }
+    key.push(0);
+    key
+}
+
+/// Parses M references and the values in ZWRITE output
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self) -> KeyError {
+        KeyError::InvalidExpression(self.pos)
+    }
+
+    fn name(&mut self) -> Result<Vec<u8>, KeyError> {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c == b'%' || c.is_ascii_alphabetic() => self.pos += 1,
+            _ => return Err(KeyError::InvalidName),
+        }
+        while self.peek().map(|c| c.is_ascii_alphanumeric()).unwrap_or(false) {
+            self.pos += 1;
+        }
+        Ok(self.s[start..self.pos].to_vec())
+    }
+
+    /// A quoted string, with "" standing for a single quote
+    fn string(&mut self) -> Result<Vec<u8>, KeyError> {
+        let mut ret = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error()),
+                Some(b'"') => {
+                    self.pos += 1;
+                    if !self.eat(b'"') {
+                        return Ok(ret);
+                    }
+                    ret.push(b'"');
+                },
+                Some(c) => {
+                    ret.push(c);
+                    self.pos += 1;
+                },
+            }
+        }
+    }
+
+    /// A numeric literal, converted to its canonical form. Literals too small for M to hold
+    /// become 0, and those too large are an error
+    fn number(&mut self) -> Result<Vec<u8>, KeyError> {
+        let literal = self.pos;
+        let mut negative = false;
+        while let Some(c) = self.peek() {
+            match c {
+                b'-' => negative = !negative,
+                b'+' => {},
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let start = self.pos;
+        while self.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+            self.pos += 1;
+        }
+        let mut int = &self.s[start..self.pos];
+        let mut frac: &[u8] = &[];
+        if self.eat(b'.') {
+            let start = self.pos;
+            while self.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                self.pos += 1;
+            }
+            frac = &self.s[start..self.pos];
+        }
+        if int.is_empty() && frac.is_empty() {
+            return Err(self.error());
+        }
+        while int.first() == Some(&b'0') {
+            int = &int[1..];
+        }
+        while frac.last() == Some(&b'0') {
+            frac = &frac[..frac.len() - 1];
+        }
+        if int.len() > MAX_INT_DIGITS {
+            return Err(KeyError::NumericOverflow(literal));
+        }
+        if int.is_empty() && frac.iter().take_while(|c| **c == b'0').count() > MAX_LEADING_ZEROS {
+            frac = &[];
+        }
+        if int.is_empty() && frac.is_empty() {
+            return Ok(Vec::from("0"));
+        }
+        let mut ret = Vec::with_capacity(int.len() + frac.len() + 2);
+        if negative {
+            ret.push(b'-');
+        }
+        ret.extend(int);
+        if !frac.is_empty() {
+            ret.push(b'.');
+            ret.extend(frac);
+        }
+        Ok(ret)
+    }
+
+    /// $C(), $CHAR(), $ZCH() or $ZCHAR() with a list of character codes
+    fn char_function(&mut self) -> Result<Vec<u8>, KeyError> {
+        let start = self.pos;
+        while self.peek().map(|c| c.is_ascii_alphabetic()).unwrap_or(false) {
+            self.pos += 1;
+        }
+        let function = self.s[start..self.pos].to_ascii_uppercase();
+        let bytes = match &function[..] {
+            b"C" | b"CHAR" => false,
+            b"ZCH" | b"ZCHAR" => true,
+            _ => return Err(self.error()),
+        };
+        if !self.eat(b'(') {
+            return Err(self.error());
+        }
+        let mut ret = Vec::new();
+        loop {
+            let start = self.pos;
+            while self.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                self.pos += 1;
+            }
+            let code: u32 = std::str::from_utf8(&self.s[start..self.pos]).unwrap()
+                .parse().map_err(|_| self.error())?;
+            if code < 0x100 && (bytes || code < 0x80) {
+                ret.push(code as u8);
+            } else {
+                // $C() takes code points; anything past ASCII is stored as UTF-8
+                let c = std::char::from_u32(code).ok_or_else(|| self.error())?;
+                let mut buf = [0; 4];
+                ret.extend(c.encode_utf8(&mut buf).as_bytes());
+            }
+            if self.eat(b')') {
+                return Ok(ret);
+            }
+            if !self.eat(b',') {
+                return Err(self.error());
+            }
+        }
+    }
+
+    /// Strings, numbers and $C() calls joined by the _ concatenation operator
+    fn expression(&mut self) -> Result<Vec<u8>, KeyError> {
+        let mut ret = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    ret.extend(self.string()?);
+                },
+                Some(b'$') => {
+                    self.pos += 1;
+                    ret.extend(self.char_function()?);
+                },
+                Some(c) if c.is_ascii_digit() || c == b'-' || c == b'+' || c == b'.' => {
+                    ret.extend(self.number()?);
+                },
+                _ => return Err(self.error()),
+            }
+            if !self.eat(b'_') {
+                return Ok(ret);
+            }
+        }
+    }
+
+    /// ^NAME or ^NAME(sub,...); the ^ is optional
+    fn reference(&mut self) -> Result<(Vec<u8>, Vec<Vec<u8>>), KeyError> {
+        self.eat(b'^');
+        let name = self.name()?;
+        let mut subs = Vec::new();
+        if self.eat(b'(') {
+            loop {
+                subs.push(self.expression()?);
+                if self.eat(b')') {
+                    break;
+                }
+                if !self.eat(b',') {
+                    return Err(self.error());
+                }
+            }
+        }
+        Ok((name, subs))
+    }
+}
+
+/// Parses a global reference like ^ACCT(1,"name") into the global name and its subscripts.
+/// Subscripts may be quoted strings, numbers, $C() calls, or concatenations of them
+pub fn parse_reference(s: &str) -> Result<(Vec<u8>, Vec<Vec<u8>>), KeyError> {
+    let mut parser = Parser { s: s.as_bytes(), pos: 0 };
+    let ret = parser.reference()?;
+    if parser.pos != parser.s.len() {
+        return Err(KeyError::TrailingCharacters(parser.pos));
+    }
+    Ok(ret)
+}
+
+/// A global name, its subscripts and a value
+pub type ZwrNode = (Vec<u8>, Vec<Vec<u8>>, Vec<u8>);
+
+/// Parses a line of ZWRITE output like ^ACCT(1)="Bob" into the global name, its subscripts
+/// and the value
+pub fn parse_zwr(line: &[u8]) -> Result<ZwrNode, KeyError> {
+    let mut parser = Parser { s: line, pos: 0 };
+    let (name, subs) = parser.reference()?;
+    if !parser.eat(b'=') {
+        return Err(parser.error());
+    }
+    let value = parser.expression()?;
+    if parser.pos != parser.s.len() {
+        return Err(KeyError::TrailingCharacters(parser.pos));
+    }
+    Ok((name, subs, value))
+}
+
+/// Parses a comma separated list of subscripts, like 1,"name", as found between the
+/// parentheses of a reference
+pub fn parse_subscripts(s: &str) -> Result<Vec<Vec<u8>>, KeyError> {
+    let mut parser = Parser { s: s.as_bytes(), pos: 0 };
+    let mut subs = vec![parser.expression()?];
+    while parser.eat(b',') {
+        subs.push(parser.expression()?);
+    }
+    if parser.pos != parser.s.len() {
+        return Err(KeyError::TrailingCharacters(parser.pos));
+    }
+    Ok(subs)
+}
+
+/// The value of a decoded subscript as M sees it: numbers in canonical form, strings as is
+pub fn subscript_value(sub: &Subscript) -> Vec<u8> {
+    match sub {
+        Subscript::Number(n) => n.clone().into_bytes(),
+        Subscript::Str(s) => s.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subs(values: &[&str]) -> Vec<Vec<u8>> {
+        values.iter().map(|v| v.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn encodes_numbers_like_gtm() {
+        assert_eq!(encode_key(b"x", &subs(&["1"]), true), b"x\0\xBF\x11\0\0");
+        assert_eq!(encode_subscript(b"0", true), vec![SUBSCRIPT_ZERO]);
+        assert_eq!(encode_subscript(b"", true), vec![SUBSCRIPT_STDCOL_NULL]);
+        assert_eq!(encode_subscript(b"", false), vec![STR_SUB_PREFIX]);
+    }
+
+    #[test]
+    fn numbers_stay_in_range() {
+        let largest = "9".repeat(18) + &"0".repeat(29);
+        let too_large = format!("1{}", "0".repeat(47));
+        let smallest = format!(".{}1", "0".repeat(42));
+        let too_small = format!(".{}1", "0".repeat(43));
+        assert!(is_canonical_number(largest.as_bytes()));
+        assert!(is_canonical_number(smallest.as_bytes()));
+        assert!(!is_canonical_number(too_large.as_bytes()));
+        assert!(!is_canonical_number(too_small.as_bytes()));
+        // The extremes keep their exponent byte inside the number range, and still sort in order
+        let encoded: Vec<_> = [format!("-{}", largest), format!("-{}", smallest),
+                               smallest.clone(), largest.clone()].iter()
+            .map(|n| encode_subscript(n.as_bytes(), true))
+            .collect();
+        assert_eq!(encoded[3][0], 0xBE + 47);
+        assert_eq!(encoded[2][0], 0xBE - 42);
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(encoded[1] < vec![SUBSCRIPT_ZERO] && vec![SUBSCRIPT_ZERO] < encoded[2]);
+        for (value, encoded) in [&smallest, &largest].iter().zip(&encoded[2..]) {
+            assert_eq!(decode_subscript(encoded), Subscript::Number(value.to_string()));
+        }
+        // Out of range numbers are kept as strings rather than wrapping the exponent
+        assert_eq!(encode_subscript(too_large.as_bytes(), true)[0], STR_SUB_PREFIX);
+        assert_eq!(encode_subscript(too_small.as_bytes(), true)[0], STR_SUB_PREFIX);
+        // As literals, M overflows on one and rounds the other to 0
+        assert_eq!(parse_subscripts(&format!("1,{}", too_large)),
+                   Err(KeyError::NumericOverflow(2)));
+        assert_eq!(parse_subscripts(&too_small).unwrap(), subs(&["0"]));
+        assert_eq!(parse_subscripts(&largest).unwrap(), vec![largest.into_bytes()]);
+    }
+
+    #[test]
+    fn subscripts_round_trip() {
+        let values = ["0", "1", "-1", "10", "123456789012345678", "0.5", ".5", "-.001", "1E3",
+                      "007", "abc", "a\0b\x01c", ""];
+        for value in values.iter() {
+            for std_null_coll in [true, false].iter() {
+                let encoded = encode_subscript(value.as_bytes(), *std_null_coll);
+                let decoded = decode_subscript(&encoded);
+                // Only canonical numbers come back as numbers; ".5" is canonical, "0.5" isn't
+                let expected = match is_canonical_number(value.as_bytes()) {
+                    true => Subscript::Number(value.to_string()),
+                    false => Subscript::Str(value.as_bytes().to_vec()),
+                };
+                assert_eq!(decoded, expected, "{:?}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_sort_in_m_collation() {
+        let order = ["-10", "-1.5", "-1", "-.5", "0", ".5", "1", "2", "10", "a", "ab", "b"];
+        let keys: Vec<_> = order.iter()
+            .map(|s| encode_key(b"x", &subs(&[s]), true))
+            .collect();
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{} >= {}", format_key(&pair[0]), format_key(&pair[1]));
+        }
+        // The empty string sorts first under standard null collation, and between numbers and
+        // strings under GT.M's
+        let empty = |std_null_coll| encode_key(b"x", &subs(&[""]), std_null_coll);
+        assert!(empty(true) < keys[0]);
+        assert!(empty(false) > keys[8] && empty(false) < keys[9]);
+        // A node sorts before its descendants, which sort before its next sibling
+        let parent = encode_key(b"x", &subs(&["1"]), true);
+        let child = encode_key(b"x", &subs(&["1", "z"]), true);
+        let sibling = encode_key(b"x", &subs(&["2"]), true);
+        assert!(parent < child && child < sibling);
+    }
+
+    #[test]
+    fn decodes_keys() {
+        let key = encode_key(b"ACCT", &subs(&["1", "name"]), true);
+        let (name, decoded) = decode_key(&key);
+        assert_eq!(name, b"ACCT");
+        assert_eq!(decoded, vec![Subscript::Number("1".to_string()),
+                                 Subscript::Str(b"name".to_vec())]);
+        assert_eq!(format_key(&key), "^ACCT(1,\"name\")");
+    }
+
+    #[test]
+    fn zwr_lines_round_trip() {
+        let lines: [&[u8]; 7] = [
+            b"^ACCT=5",
+            b"^ACCT(1,\"name\")=\"Bob\"",
+            b"^ACCT(-1.5,\"\")=\"\"",
+            b"^x(\"say \"\"hi\"\"\")=\"a\"_$C(0,10)_\"b\"",
+            b"^x($C(1))=$C(2)",
+            b"^x(\"caf\"_$ZCH(195,169))=$ZCH(255)_$C(0)_\"!\"",
+            b"^x(.25,\"007\")=\"007\"",
+        ];
+        for line in lines.iter() {
+            let (name, subs, value) = parse_zwr(line).unwrap();
+            let key = encode_key(&name, &subs, true);
+            let formatted = format!("{}={}", format_key(&key), format_value(&value));
+            assert_eq!(formatted.as_bytes(), *line);
+        }
+    }
+
+    #[test]
+    fn parses_expressions() {
+        let (name, parsed, value) = parse_zwr(b"^x(\"a\"_$C(66),3)=\"x\"\"y\"").unwrap();
+        assert_eq!(name, b"x");
+        assert_eq!(parsed, vec![b"aB".to_vec(), b"3".to_vec()]);
+        assert_eq!(value, b"x\"y");
+        assert_eq!(parse_reference("^x(1)").unwrap(), (b"x".to_vec(), vec![b"1".to_vec()]));
+        assert_eq!(parse_subscripts("1,\"a\"").unwrap(), subs(&["1", "a"]));
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(parse_reference("^1x"), Err(KeyError::InvalidName));
+        assert_eq!(parse_reference("^x(1)y"), Err(KeyError::TrailingCharacters(5)));
+        assert!(parse_zwr(b"^x(1)").is_err());
+        assert!(parse_zwr(b"^x(\"a)=1").is_err());
+    }
+}
@@ -2,6 +2,11 @@
 extern crate nom;
 
 extern crate ydb_ng_bridge;
+extern crate fnv;
+extern crate threadpool;
+extern crate spin;
+extern crate serde;
+extern crate bincode;
 //use serde::{Serialize, Deserialize};
 
 use std::collections::VecDeque;
@@ -21,11 +26,62 @@ use ydb_ng_bridge::ydb::{sgmnt_data_struct, blk_hdr, rec_hdr};
 
 pub mod rec;
 pub mod block;
+pub mod bitmap;
+pub mod integ;
+pub mod progress;
+pub mod repair;
+pub mod key;
+pub mod fhead;
+pub mod tree;
+pub mod map;
+pub mod update;
+pub mod order;
+pub mod extract;
+pub mod load;
+pub mod json;
+pub mod bulk;
+pub mod create;
+pub mod reorg;
+pub mod stats;
+pub mod journal;
+#[cfg(test)]
+mod test_util;
 
-pub use block::{Blk, get_block, BlkNum, RecordCursor, BlkType};
-pub use rec::Rec;
+pub use block::{Blk, get_block, get_valid_block, BlkNum, RecordCursor, BlkType, BlockError};
+pub use rec::RawRec;
+pub use bitmap::{BitmapError, set_local_status};
+pub use integ::{IntegOptions, IntegReport, IntegScope};
+pub use progress::{Progress, CancelToken};
+pub use repair::{RepairChange, save_repair_log, load_repair_log};
+pub use fhead::FileHeader;
+pub use map::{BlockOwner, BlockMapEntry};
+pub use tree::PathStep;
+pub use key::KeyError;
+pub use order::Nodes;
+pub use extract::{ExtractFormat, ExtractStats};
+pub use load::{LoadOptions, LoadReport, Rejection};
+pub use json::JsonFormat;
+pub use bulk::BulkReport;
+pub use create::CreateParams;
+pub use reorg::ReorgStats;
+pub use stats::{GlobalStats, LevelStats};
+pub use journal::{JnlReader, JnlRecord, JnlHeader, JnlPrefix, JnlUpdate, Fence, open_journal};
 
 static PHYSICAL_DATABASE_BLOCK_SIZE: i32 = 512;
+/// Size of the master bitmap which follows the file header
+pub const MASTER_MAP_SIZE: usize = 253952;
+
+/// The master bitmap, kept on the heap as it is too big to move around on a thread's stack
+pub type MasterMap = Box<[u8; MASTER_MAP_SIZE]>;
+
+/// The semaphore and shared memory ids of a database no process has open
+pub(crate) const INVALID_SEMID: i32 = -1;
+pub(crate) const INVALID_SHMID: i32 = -1;
+
+/// A master bitmap with every byte set to `fill`
+pub(crate) fn new_master_map(fill: u8) -> MasterMap {
+    std::convert::TryFrom::try_from(vec![fill; MASTER_MAP_SIZE].into_boxed_slice()).unwrap()
+}
 
 pub type IntegQueueType = RwLock<VecDeque<IntegBlock>>;
 
@@ -35,12 +91,25 @@ pub struct IntegBlock {
     pub typ: BlkType,
     pub start: Vec<u8>,
     pub end: Vec<u8>,
+    /// The level the block should be at, if its position in the tree determines it
+    pub levl: Option<u8>,
+}
+
+/// A problem found during an integrity check, and the block it was found in
+#[derive(Debug)]
+pub struct IntegFinding {
+    pub blk_num: usize,
+    pub error: ValueError,
 }
 
 pub struct Database {
     pub fhead: sgmnt_data_struct,
-    pub master_bitmap: [u8; 253952],
+    pub master_bitmap: MasterMap,
     pub handle: File,
+    /// How full, as a percentage, blocks split by updates are left; see `set_fill_factor`
+    pub(crate) fill_factor: usize,
+    /// Byte range of the master bitmap changed since it was last written
+    pub(crate) master_map_dirty: Option<(usize, usize)>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -68,22 +137,71 @@ pub struct State<'a> {
 pub enum ValueError {
     IoError(std::io::Error),
     RecordError(RecordError),
+    BitmapError(BitmapError),
+    BlockError(BlockError),
     GlobalNotFound,
     SubscriptNotFound,
     MalformedRecord,
     BlockIncorrectlyMarkedFree,
     BlockIncorrectlyMarkedBusy,
+    /// The integ report has problems a repair can't safely fix, or didn't cover the database
+    RepairNotSafe,
+    /// Walking a tree reached this block a second time, so the tree loops or shares a subtree
+    TreeLoop(usize),
+    KeyError(KeyError),
+    /// Every block is in use, and the file can't be extended
+    DatabaseFull,
+    /// The record is larger than the database's maximum record size, or can't fit in a block
+    RecordTooLarge,
+    /// The input to a load isn't a valid extract, or isn't in the format asked for
+    InvalidExtract,
+    /// A bulk load was asked to build a global which is already in the database
+    GlobalExists,
+    /// A setting is outside the range allowed; names the setting
+    InvalidParameter(&'static str),
+    /// The file isn't a journal this can read, or has a record which is cut short or corrupt
+    InvalidJournal,
+    /// The operation was stopped early by its CancelToken
+    Cancelled,
+    /// Checking a block panicked, which is a bug in this library; holds the panic message
+    CheckPanicked(String),
+    /// YottaDB has the database open, so it mustn't be written to; see `Database::in_use`
+    DatabaseInUse,
 }
 
 #[derive(Debug)]
 pub enum RecordError {
     IoError(std::io::Error),
+    /// The record's rsiz runs past the end of the block
     TooBig,
+    /// The record's rsiz is too small to hold a record header
     TooSmall,
     LengthZero,
     ZeroCompressionCount,
+    /// The record claims to share more of its key than the previous record had
+    CompressionCountTooLarge,
+    /// The expanded key is longer than the database's maximum key size
+    KeyTooLong,
     IncorrectSort,
     NoTerminatingCharacter,
+    /// An index record holds something other than a 4 byte block pointer after its key
+    IncorrectPointerLength,
+}
+
+/// Reads the file header and master bitmap from the start of a database file
+fn read_header(file: &mut File) -> std::io::Result<(sgmnt_data_struct, MasterMap)> {
+    let mut fhead: sgmnt_data_struct = unsafe { mem::zeroed() };
+    let buffer_size = mem::size_of::<sgmnt_data_struct>();
+    unsafe {
+        let fhead_slice = slice::from_raw_parts_mut(
+            &mut fhead as *mut _ as *mut u8,
+            buffer_size
+            );
+        file.read_exact(fhead_slice)?;
+    }
+    let mut master_bitmap = new_master_map(0);
+    file.read_exact(&mut master_bitmap[..])?;
+    Ok((fhead, master_bitmap))
 }
 
 impl From<std::io::Error> for ValueError {
@@ -107,19 +225,70 @@ impl From<RecordError> for ValueError {
     }
 }
 
+impl From<BitmapError> for ValueError {
+    fn from(error: BitmapError) -> Self {
+        ValueError::BitmapError(error)
+    }
+}
+
+impl From<BlockError> for ValueError {
+    fn from(error: BlockError) -> Self {
+        ValueError::BlockError(error)
+    }
+}
+
+impl From<KeyError> for ValueError {
+    fn from(error: KeyError) -> Self {
+        ValueError::KeyError(error)
+    }
+}
+
 impl Database {
+    /// The transaction number the next update to the database will be given
+    pub fn current_tn(&self) -> u64 {
+        self.fhead.trans_hist.curr_tn
+    }
+
+    /// Whether YottaDB has the database open, according to the semaphore and shared memory
+    /// ids in the file header. Writing to it then would race with YottaDB's own updates, and
+    /// its buffers would hide the changes. A process that crashed can leave these set until
+    /// MUPIP RUNDOWN is run
+    pub fn in_use(&self) -> bool {
+        self.fhead.semid != INVALID_SEMID as _ || self.fhead.shmid != INVALID_SHMID as _
+    }
+
+    /// Whether every block has been upgraded to the V6 format, so any V4 block is corrupt
+    pub fn fully_upgraded(&self) -> bool {
+        self.fhead.fully_upgraded != 0
+    }
+
+    /// The longest key, terminators included, which may be stored in this database
+    pub fn max_key_size(&self) -> usize {
+        self.fhead.max_key_size as usize
+    }
+
+    /// Whether empty string subscripts sort before numbers (standard) or after them, before strings
+    pub fn std_null_coll(&self) -> bool {
+        self.fhead.std_null_coll != 0
+    }
+
+    /// Parses a global reference like ^ACCT(1,"name") into the internal key format used by this
+    /// database
+    pub fn parse_key(&self, reference: &str) -> Result<Vec<u8>, ValueError> {
+        let (name, subs) = key::parse_reference(reference)?;
+        Ok(key::encode_key(&name, &subs, self.std_null_coll()))
+    }
+
     pub fn local_block_status(&self, blk_num: usize) -> Result<LocalBitmapStatus, ValueError> {
-        // Get the local bitmap closest to that block; they occur every 512 blocks, so at 0, 511,
-        // 1023, etc. divide blk_nu by 512, then multiply by 512
-        let bm_blk_num = blk_num / 512;
-        let bm_blk_num = bm_blk_num * 512;
-        let blk = self.get_block(blk_num)?;
-        Ok(match blk[blk_num - bm_blk_num] {
-            0 => LocalBitmapStatus::Busy,
-            1 => LocalBitmapStatus::NeverUsed,
-            3 => LocalBitmapStatus::Free,
-            _ => LocalBitmapStatus::Invalid,
-        })
+        // Get the local bitmap closest to that block; they occur every bplmap blocks, so at 0,
+        // 512, 1024, etc. divide blk_num by bplmap to find which map covers it
+        let bplmap = self.blocks_per_map();
+        let map = self.local_bitmap(blk_num / bplmap)?;
+        match map.get(blk_num % bplmap) {
+            Some(x) => Ok(x.clone()),
+            None => Err(ValueError::from(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof, "block is past the end of the database"))),
+        }
     }
 
     // We should check some sort of cache here for the block
@@ -128,10 +297,7 @@ impl Database {
         let mut handle = self.handle.try_clone()?;
         let blk_size = self.fhead.blk_size as usize;
         let mut raw_block = vec![0; blk_size];
-        handle.seek(SeekFrom::Start(
-            (((self.fhead.start_vbn - 1) * PHYSICAL_DATABASE_BLOCK_SIZE) as usize
-             + blk_size * blk_num) as u64)
-        )?;
+        handle.seek(SeekFrom::Start(self.block_offset(blk_num)))?;
         handle.read_exact(&mut raw_block)?;
         /*let mut block = raw_block.as_slice();
         let mut block_header: blk_hdr = unsafe { mem::zeroed() };
@@ -152,6 +318,66 @@ impl Database {
         Ok(raw_block)
     }
 
+    /// Offset in the database file where block `blk_num` starts
+    pub fn block_offset(&self, blk_num: usize) -> u64 {
+        (((self.fhead.start_vbn - 1) * PHYSICAL_DATABASE_BLOCK_SIZE) as usize
+         + self.fhead.blk_size as usize * blk_num) as u64
+    }
+
+    /// Writes `raw` at `offset` in the database file. Callers are responsible for keeping the
+    /// in memory file header and master bitmap in sync with what they write
+    pub fn write_at(&mut self, offset: u64, raw: &[u8]) -> std::io::Result<()> {
+        let mut handle = self.handle.try_clone()?;
+        handle.seek(SeekFrom::Start(offset))?;
+        handle.write_all(raw)
+    }
+
+    /// Overwrites block `blk_num` with `raw`, which should be a full block as returned by
+    /// `get_block`
+    pub fn write_block(&mut self, blk_num: usize, raw: &[u8]) -> std::io::Result<()> {
+        let offset = self.block_offset(blk_num);
+        self.write_at(offset, raw)
+    }
+
+    /// The file header exactly as it is laid out on disk
+    pub fn header_bytes(&self) -> Vec<u8> {
+        let buffer_size = mem::size_of::<sgmnt_data_struct>();
+        unsafe {
+            slice::from_raw_parts(&self.fhead as *const _ as *const u8, buffer_size).to_vec()
+        }
+    }
+
+    /// Writes the in memory file header back to the database file, along with the bytes of
+    /// the master bitmap changed by `set_master_map_free` since they were last written
+    pub fn write_header(&mut self) -> std::io::Result<()> {
+        let raw = self.header_bytes();
+        self.write_at(0, &raw)?;
+        if let Some((start, end)) = self.master_map_dirty.take() {
+            let offset = (raw.len() + start) as u64;
+            let changed = self.master_bitmap[start..end].to_vec();
+            self.write_at(offset, &changed)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the whole in memory master bitmap back with the file header, for when it has been
+    /// changed directly rather than through `set_master_map_free`
+    pub fn write_header_and_master_map(&mut self) -> std::io::Result<()> {
+        self.master_map_dirty = Some((0, self.master_bitmap.len()));
+        self.write_header()
+    }
+
+    /// Re-reads the file header and master bitmap, discarding the in memory copies
+    pub fn reload_header(&mut self) -> std::io::Result<()> {
+        let mut handle = self.handle.try_clone()?;
+        handle.seek(SeekFrom::Start(0))?;
+        let (fhead, master_bitmap) = read_header(&mut handle)?;
+        self.fhead = fhead;
+        self.master_bitmap = master_bitmap;
+        self.master_map_dirty = None;
+        Ok(())
+    }
+
     /*pub fn write_block(&mut self, old_blk_hdr: &blk_hdr, blk_num: usize, new_value: Vec<u8>) -> std::io::Result<()> {
         /*let mut handle = self.handle.try_clone()?;
         let blk_size = self.fhead.blk_size as usize;
@@ -231,109 +457,61 @@ impl Database {
         Ok(())
     }*/
 
-    /// Given a key, finds the block number with the data for that block
-    pub fn find_value_block(&self, item: &[u8]) -> Result<BlkNum, ValueError> {
-        let root_block = self.get_block(1)?;
-        let root_block = get_block(&root_block, 1, BlkType::DirectoryTree)?;
-        // Scan through the root block until we find one with key
-        // greater than the value we are looking for
-        let mut next_block = 0;
-        let mut global_end = 0;
-        let mut found = false;
-        while global_end < item.len() && item[global_end] != 0 {
-            global_end += 1;
-        }
-        println!("Searching block {}", next_block);
-        let global = &item[0..global_end];
-        let mut state = State{compression: 0, goal: global};
-        for record in RecordCursor::new(&root_block) {
-            let record = record?;
-            found = match RecordCursor::compare(&record, &mut state) {
-                SortOrder::SortsAfter => true,
-                _ => false,
-            };
-            if found == true {
-                next_block = match record.ptr().unwrap() {
-                    BlkNum::Block(x) => x,
-                    _ => 0,
-                };
-                break;
-            }
-        }
-        if !found || next_block == 0 {
-            return Err(ValueError::MalformedRecord);
-        }
-
-        println!("Searching block {}", next_block);
-        let gvt_block = self.get_block(next_block)?;
-        let gvt_block = get_block(&gvt_block, next_block, BlkType::IndexBlock)?;
-        let mut state = State{compression: 0, goal: item};
-        let mut next_block = 0;
-        found = false;
-        for record in RecordCursor::new(&gvt_block) {
-            let record = record?;
-            found = match RecordCursor::compare(&record, &mut state) {
-                SortOrder::SortsAfter => true,
-                SortOrder::SortsEqual => true,
-                _ => false,
-            };
-            if found == true {
-                next_block = match record.ptr().unwrap() {
-                    BlkNum::Block(x) => x,
-                    _ => 0,
-                };
-                break;
-            }
-        }
-        if !found {
-            return Err(ValueError::GlobalNotFound);
-        }
-
+    /// Looks up a global in the directory tree and returns the block number of its root
+    pub fn find_global_root(&self, global: &[u8]) -> Result<usize, ValueError> {
+        let mut goal = Vec::from(global);
+        goal.extend(&[0, 0]);
+        let mut blk_num = 1;
         loop {
-            // Now scan for the specific global we are after
-            println!("Searching block {}", next_block);
-            let gvt_block = self.get_block(next_block)?;
-            let gvt_block = get_block(&gvt_block, next_block, BlkType::IndexBlock)?;
-            println!("Next block level {}", gvt_block.header().levl);
-            if gvt_block.header().levl == 0 {
-                return Ok(BlkNum::Block(next_block));
-            }
-
-            //let mut state = State{compression: 0, matched_so_far: vec![0; 1024]};
-            found = false;
-            for record in RecordCursor::new(&gvt_block) {
+            let raw = self.get_block(blk_num)?;
+            let blk = get_block(&raw, blk_num, BlkType::DirectoryTree)?;
+            let mut key = Vec::new();
+            let mut next = None;
+            for record in RecordCursor::new(&blk) {
                 let record = record?;
-                found = match RecordCursor::compare(&record, &mut state) {
-                    SortOrder::SortsAfter => true,
-                    SortOrder::SortsEqual => true,
-                    _ => false,
-                };
-                if found == true {
-                    next_block = match record.ptr().unwrap() {
-                        BlkNum::Block(x) => x,
-                        _ => 0,
-                    };
+                // The * record has no key, and covers everything after the previous record
+                if record.header.rsiz == 8 {
+                    next = Some(record.ptr()?);
                     break;
                 }
+                RecordCursor::expand_key(&record, &mut key)?;
+                match RecordCursor::compare_strings(&key, &goal) {
+                    SortOrder::SortsBefore => continue,
+                    SortOrder::SortsAfter if blk.header().levl == 0 => break,
+                    _ => {
+                        next = Some(record.ptr()?);
+                        break;
+                    }
+                }
             }
-            if !found {
-                break;
+            blk_num = match next {
+                Some(BlkNum::Block(x)) => x,
+                _ => return Err(ValueError::GlobalNotFound),
+            };
+            if blk.header().levl == 0 {
+                return Ok(blk_num);
             }
         }
-        Err(ValueError::SubscriptNotFound)
+    }
+
+    /// Given a key, finds the block number with the data for that block
+    pub fn find_value_block(&self, item: &[u8]) -> Result<BlkNum, ValueError> {
+        let path = self.find_path(item)?;
+        match path.last() {
+            Some(step) if step.typ == BlkType::DirectoryTree => Err(ValueError::GlobalNotFound),
+            Some(step) => Ok(BlkNum::Block(step.blk_num)),
+            None => Err(ValueError::GlobalNotFound),
+        }
     }
 
     /// Searches block for item, and return the value or not found
-    pub fn find_value<'a>(&self, item: &[u8], block: &'a Blk) -> Result<Vec<u8>, ValueError> {
+    pub fn find_value(&self, item: &[u8], block: &Blk) -> Result<Vec<u8>, ValueError> {
         let mut state = State{compression: 0, goal: item};
-        for record in RecordCursor::new(&block) {
+        for record in RecordCursor::new(block) {
             let record = record?;
-            let found = match RecordCursor::compare(&record, &mut state) {
-                SortOrder::SortsAfter => true,
-                SortOrder::SortsEqual => true,
-                _ => false,
-            };
-            if found == true {
+            let found = matches!(RecordCursor::compare(&record, &mut state),
+                                 SortOrder::SortsAfter | SortOrder::SortsEqual);
+            if found {
                 return Ok(record.data().to_vec());
             }
         }
@@ -342,21 +520,13 @@ impl Database {
 
     pub fn open(path: &str) -> std::io::Result<Database> {
         let mut file = OpenOptions::new().read(true).write(true).open(path)?;
-        let mut fhead: sgmnt_data_struct = unsafe { mem::zeroed() };
-        let buffer_size = mem::size_of::<sgmnt_data_struct>();
-        unsafe {
-            let fhead_slice = slice::from_raw_parts_mut(
-                &mut fhead as *mut _ as *mut u8,
-                buffer_size
-                );
-            file.read_exact(fhead_slice)?;
-        }
-        let mut master_bitmap: [u8; 253952] = unsafe { mem::zeroed() };
-        file.read_exact(&mut master_bitmap)?;
+        let (fhead, master_bitmap) = read_header(&mut file)?;
         Ok(Database{
-            fhead: fhead,
-            master_bitmap: master_bitmap,
+            fhead,
+            master_bitmap,
             handle: file,
+            fill_factor: 100,
+            master_map_dirty: None,
         })
     }
 }
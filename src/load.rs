@@ -0,0 +1,391 @@
+use super::*;
+
+use std::io::BufRead;
+
+use self::bulk::BulkBuilder;
+use self::extract::{BIN_HEADER_LABEL, BIN_HEADER_NUMSZ, BIN_HEADER_SZ};
+use self::key::{decode_key, encode_key, parse_reference, parse_zwr, subscript_value};
+
+/// Controls how `Database::load` reads an extract
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// The format the input must be in; if None, it is worked out from the header
+    pub format: Option<ExtractFormat>,
+    /// First record to load, counting nodes from 1 after the header
+    pub begin: usize,
+    /// Last record to load, if not the end of the input
+    pub end: Option<usize>,
+    /// How full to leave blocks split while loading; see `Database::set_fill_factor`
+    pub fill_factor: usize,
+    /// Build each global with `Database::bulk_load` rather than setting one node at a time.
+    /// The extract must be in key order and none of its globals may already exist
+    pub bulk: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            format: None,
+            begin: 1,
+            end: None,
+            fill_factor: 100,
+            bulk: false,
+        }
+    }
+}
+
+/// A record `Database::load` couldn't store, and why
+#[derive(Debug)]
+pub struct Rejection {
+    pub record: usize,
+    pub error: ValueError,
+}
+
+/// What `Database::load` did
+#[derive(Debug)]
+pub struct LoadReport {
+    pub format: ExtractFormat,
+    /// Records read from the input, including those outside the begin/end range
+    pub read: usize,
+    pub loaded: usize,
+    pub rejected: Vec<Rejection>,
+}
+
+/// A key and its value
+type Node = (Vec<u8>, Vec<u8>);
+
+/// Reads one line, without its line break. Returns None at the end of the input
+fn read_line<R: BufRead>(input: &mut R) -> Result<Option<Vec<u8>>, ValueError> {
+    let mut line = Vec::new();
+    if input.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+/// Reads one length prefixed record of a binary extract. Returns None at the end of the input
+fn read_bin_record<R: BufRead>(input: &mut R) -> Result<Option<Vec<u8>>, ValueError> {
+    let mut len = [0; 4];
+    if input.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    input.read_exact(&mut len)?;
+    let mut ret = vec![0; u32::from_le_bytes(len) as usize];
+    input.read_exact(&mut ret)?;
+    Ok(Some(ret))
+}
+
+/// Expands the records of a binary extract data record into (key, value) pairs
+fn bin_entries(data: &[u8]) -> Result<Vec<Node>, ValueError> {
+    let hdr_size = mem::size_of::<rec_hdr>();
+    let mut ret = Vec::new();
+    let mut key: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if pos + hdr_size > data.len() {
+            return Err(ValueError::InvalidExtract);
+        }
+        let rsiz = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        let cmpc = data[pos + 2] as usize;
+        if rsiz < hdr_size || pos + rsiz > data.len() || cmpc > key.len() {
+            return Err(ValueError::InvalidExtract);
+        }
+        let record = &data[pos + hdr_size..pos + rsiz];
+        // The key ends with two 0 bytes; with compression, the first may be in the shared part
+        let end = (0..record.len())
+            .find(|i| record[*i] == 0 && (if *i == 0 { cmpc > 0 && key[cmpc - 1] == 0 }
+                                          else { record[*i - 1] == 0 }))
+            .ok_or(ValueError::InvalidExtract)?;
+        key.truncate(cmpc);
+        key.extend(&record[..=end]);
+        ret.push((key.clone(), record[end + 1..].to_vec()));
+        pos += rsiz;
+    }
+    Ok(ret)
+}
+
+/// Whether an error only affects the record being loaded, rather than the whole load
+fn rejects_record(error: &ValueError) -> bool {
+    matches!(error, ValueError::KeyError(_) | ValueError::RecordError(_)
+             | ValueError::RecordTooLarge | ValueError::InvalidExtract)
+}
+
+impl Database {
+    /// Reads a ZWR, GO or binary extract, as written by `extract` or MUPIP EXTRACT, and sets
+    /// each node in it, like MUPIP LOAD. Records which can't be parsed or are too big for this
+    /// database are listed in the report and skipped; any other error stops the load, leaving
+    /// the records before it in place
+    pub fn load<R: BufRead>(&mut self, mut input: R, options: &LoadOptions)
+            -> Result<LoadReport, ValueError> {
+        let format = {
+            let start = input.fill_buf()?;
+            let label = BIN_HEADER_LABEL.as_bytes();
+            if start.len() >= 4 + label.len() && start[4..].starts_with(label) {
+                ExtractFormat::Binary
+            } else {
+                ExtractFormat::Zwr
+            }
+        };
+        let mut report = LoadReport { format, read: 0, loaded: 0, rejected: Vec::new() };
+        // Empty string subscripts in a binary extract are encoded as in the database it came from
+        let mut bin_std_null_coll = self.std_null_coll();
+        if format == ExtractFormat::Binary {
+            let header = read_bin_record(&mut input)?.ok_or(ValueError::InvalidExtract)?;
+            if header.len() != BIN_HEADER_SZ {
+                return Err(ValueError::InvalidExtract);
+            }
+            // The label and timestamp are followed by the block, record and key sizes
+            let null_coll = BIN_HEADER_LABEL.len() + 14 + 3 * BIN_HEADER_NUMSZ;
+            bin_std_null_coll = header[null_coll..null_coll + BIN_HEADER_NUMSZ]
+                .iter().any(|c| *c != b'0');
+        } else {
+            read_line(&mut input)?.ok_or(ValueError::InvalidExtract)?;
+            let date = read_line(&mut input)?.ok_or(ValueError::InvalidExtract)?;
+            if !date.ends_with(b" ZWR") {
+                report.format = ExtractFormat::Go;
+            }
+        }
+        if options.format.map(|f| f != report.format).unwrap_or(false) {
+            return Err(ValueError::InvalidExtract);
+        }
+        let fill_factor = self.fill_factor;
+        self.set_fill_factor(options.fill_factor);
+        let mut bulk = if options.bulk { Some(BulkBuilder::new(self)) } else { None };
+        let mut ret = self.load_records(&mut input, options, bin_std_null_coll, &mut bulk,
+                                        &mut report);
+        if let Some(mut builder) = bulk {
+            if ret.is_ok() {
+                ret = builder.finish(self);
+            }
+            if ret.is_err() {
+                // The error which stopped the load matters more than one freeing its blocks
+                let _ = builder.abandon(self);
+            }
+        }
+        // Nodes set one at a time all go in a single transaction, committed here
+        let committed = self.commit();
+        self.fill_factor = fill_factor;
+        ret.and(committed.map_err(ValueError::from)).map(|_| report)
+    }
+
+    fn load_records<R: BufRead>(&mut self, input: &mut R, options: &LoadOptions,
+                                bin_std_null_coll: bool, bulk: &mut Option<BulkBuilder>,
+                                report: &mut LoadReport) -> Result<(), ValueError> {
+        let std_null_coll = self.std_null_coll();
+        let end = options.end.unwrap_or(usize::MAX);
+        while report.read < end {
+            // Each format produces the nodes in its next record, or why they couldn't be read
+            let nodes: Vec<Result<Node, ValueError>> = match report.format {
+                ExtractFormat::Zwr => match read_line(input)? {
+                    None => break,
+                    Some(line) if line.is_empty() => continue,
+                    Some(line) => vec![parse_zwr(&line)
+                        .map(|(name, subs, value)| (encode_key(&name, &subs, std_null_coll), value))
+                        .map_err(ValueError::from)],
+                },
+                ExtractFormat::Go => {
+                    let line = match read_line(input)? {
+                        None => break,
+                        Some(line) => line,
+                    };
+                    let value = read_line(input)?;
+                    vec![std::str::from_utf8(&line).map_err(|_| KeyError::InvalidName)
+                        .and_then(parse_reference)
+                        .map_err(ValueError::from)
+                        .and_then(|(name, subs)| {
+                            let value = value.ok_or(ValueError::InvalidExtract)?;
+                            Ok((encode_key(&name, &subs, std_null_coll), value))
+                        })]
+                },
+                ExtractFormat::Binary => {
+                    let data = match read_bin_record(input)? {
+                        None => break,
+                        Some(data) => data,
+                    };
+                    // Collation records; only the default collation is supported
+                    if data.len() == mem::size_of::<u32>() {
+                        continue;
+                    }
+                    match bin_entries(&data) {
+                        Ok(entries) => entries.into_iter().map(|(key, value)| {
+                            if bin_std_null_coll == std_null_coll {
+                                return Ok((key, value));
+                            }
+                            let (name, subs) = decode_key(&key);
+                            let subs: Vec<_> = subs.iter().map(subscript_value).collect();
+                            Ok((encode_key(&name, &subs, std_null_coll), value))
+                        }).collect(),
+                        Err(e) => vec![Err(e)],
+                    }
+                },
+            };
+            for node in nodes {
+                if report.read >= end {
+                    break;
+                }
+                report.read += 1;
+                self.load_node(node, options, bulk, report)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets one node read by `load`, if it is within the range asked for
+    fn load_node(&mut self, node: Result<Node, ValueError>, options: &LoadOptions,
+                 bulk: &mut Option<BulkBuilder>, report: &mut LoadReport)
+            -> Result<(), ValueError> {
+        if report.read < options.begin {
+            return Ok(());
+        }
+        let stored = node.and_then(|(key, value)| match bulk {
+            Some(builder) => builder.add(self, &key, &value),
+            None => self.set_uncommitted(&key, &value),
+        });
+        match stored {
+            Ok(()) => report.loaded += 1,
+            Err(e) if rejects_record(&e) => {
+                report.rejected.push(Rejection { record: report.read, error: e });
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key, sample_nodes, all_nodes, assert_integ_clean};
+
+    /// Nodes in two globals, with empty, numeric and non-printable subscripts and values
+    fn nodes() -> Vec<Node> {
+        let mut nodes = sample_nodes(1500);
+        nodes.push((key("^x(\"\")"), b"empty subscript".to_vec()));
+        nodes.push((key("^x(-2.5,$C(0,1,255))"), vec![0, 1, 2, 255]));
+        nodes.push((key("^x(\"007\")"), b"".to_vec()));
+        nodes.push((key("^y"), b"12".to_vec()));
+        nodes.push((key("^y(1)"), b"say \"hi\"".to_vec()));
+        nodes.sort();
+        nodes
+    }
+
+    fn source() -> TempDb {
+        let mut db = TempDb::small();
+        for (key, value) in nodes() {
+            db.set(&key, &value).unwrap();
+        }
+        db
+    }
+
+    fn extract_all(db: &Database, format: ExtractFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        db.extract(&mut out, format, None).unwrap();
+        out
+    }
+
+    fn assert_same(db: &Database, expected: &[Node]) {
+        let mut loaded = all_nodes(db, b"x");
+        loaded.extend(all_nodes(db, b"y"));
+        assert_eq!(loaded, expected);
+        assert_integ_clean(db);
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let source = source();
+        let expected = nodes();
+        for format in [ExtractFormat::Zwr, ExtractFormat::Go, ExtractFormat::Binary].iter() {
+            let extract = extract_all(&source, *format);
+            for bulk in [false, true].iter() {
+                let mut db = TempDb::small();
+                let options = LoadOptions { bulk: *bulk, ..LoadOptions::default() };
+                let report = db.load(extract.as_slice(), &options).unwrap();
+                assert_eq!(report.format, *format);
+                assert_eq!(report.loaded, expected.len());
+                assert!(report.rejected.is_empty(), "{:?}", report.rejected);
+                assert_same(&db, &expected);
+                // Extracting the loaded database gives the same nodes back; only the header's
+                // timestamp may differ
+                let again = extract_all(&db, *format);
+                match format {
+                    ExtractFormat::Binary => assert_eq!(again[BIN_HEADER_SZ + 4..],
+                                                        extract[BIN_HEADER_SZ + 4..]),
+                    _ => assert_eq!(again.splitn(3, |c| *c == b'\n').nth(2),
+                                    extract.splitn(3, |c| *c == b'\n').nth(2)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn converts_null_collation_of_binary_extracts() {
+        let source = source();
+        let extract = extract_all(&source, ExtractFormat::Binary);
+        let mut db = TempDb::new(&CreateParams {
+            blk_size: 1024,
+            std_null_coll: false,
+            ..CreateParams::default()
+        });
+        db.load(extract.as_slice(), &LoadOptions::default()).unwrap();
+        let empty = encode_key(b"x", &[vec![]], false);
+        assert_eq!(db.get(&empty).unwrap(), Some(b"empty subscript".to_vec()));
+        assert_eq!(all_nodes(&db, b"x").len() + all_nodes(&db, b"y").len(), nodes().len());
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn loads_only_the_range_asked_for() {
+        let extract = extract_all(&source(), ExtractFormat::Zwr);
+        let mut db = TempDb::small();
+        let options = LoadOptions { begin: 11, end: Some(20), ..LoadOptions::default() };
+        let report = db.load(extract.as_slice(), &options).unwrap();
+        assert_eq!(report.read, 20);
+        assert_eq!(report.loaded, 10);
+        assert_eq!(all_nodes(&db, b"x"), nodes()[10..20].to_vec());
+    }
+
+    #[test]
+    fn rejects_bad_records_and_goes_on() {
+        let input = b"header\n01-JAN-2024 00:00:00 ZWR\n^x(1)=1\n^x(2=2\n^x(3)=\"3\"\n\
+                      ^x(4)=\"too long for one block of this database, surely\"\n";
+        let mut db = TempDb::new(&CreateParams {
+            blk_size: 1024,
+            max_rec_size: 16,
+            ..CreateParams::default()
+        });
+        let report = db.load(&input[..], &LoadOptions::default()).unwrap();
+        assert_eq!(report.format, ExtractFormat::Zwr);
+        assert_eq!(report.loaded, 2);
+        let rejected: Vec<_> = report.rejected.iter().map(|r| r.record).collect();
+        assert_eq!(rejected, vec![2, 4]);
+        assert_eq!(db.get(&key("^x(3)")).unwrap(), Some(b"3".to_vec()));
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn commits_once() {
+        let extract = extract_all(&source(), ExtractFormat::Zwr);
+        let mut db = TempDb::small();
+        let tn = db.current_tn();
+        db.load(extract.as_slice(), &LoadOptions::default()).unwrap();
+        assert_eq!(db.current_tn(), tn + 1);
+        assert_eq!(Database::open(&db.path.0).unwrap().current_tn(), tn + 1);
+    }
+
+    #[test]
+    fn bulk_load_of_an_existing_global_keeps_it() {
+        let source = source();
+        let extract = extract_all(&source, ExtractFormat::Zwr);
+        let mut db = source;
+        let options = LoadOptions { bulk: true, fill_factor: 50, ..LoadOptions::default() };
+        assert!(db.load(extract.as_slice(), &options).is_err());
+        assert_eq!(db.fill_factor, 100);
+        assert_same(&db, &nodes());
+    }
+}
@@ -1,23 +1,21 @@
 extern crate ydb_ng;
 extern crate clap;
+extern crate rustyline;
 extern crate ydb_ng_bridge;
-extern crate fnv;
-extern crate threadpool;
-extern crate spin;
-
-use std::collections::{VecDeque, HashSet};
-
-use clap::{Arg, App, ArgMatches};
-use std::mem;
-use std::sync::{Arc};
-use spin::Mutex;
-use std::time::Duration;
-use std::thread::sleep;
-use fnv::FnvHashSet;
-use threadpool::ThreadPool;
-use ydb_ng_bridge::ydb::{blk_hdr};
+
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
 
 use ydb_ng::*;
+use ydb_ng::key::{format_key, format_value};
+
+mod cli {
+    pub mod shell;
+}
 
 // File format is:
 //  sgmnt_data_struct
@@ -26,196 +24,993 @@ use ydb_ng::*;
 // This is the same as ydb::DISK_BLOCK_SIZE, but given a more descriptive name
 // Note that it is hard-coded to 512 in YDB, and is unlikely to change
 
-fn find_value(matches: &ArgMatches, database: &Database) -> std::io::Result<()> {
-    let global = matches.value_of("global").unwrap_or("hello").as_bytes();
-    let subs: Vec<Vec<u8>> = matches.value_of("subscripts").unwrap_or("")
-        .split(",").map(|s| { Vec::from(s) }).collect();
-    let partial_match: Vec<u8> = vec![0, 0];
-    let mut combined_search = Vec::from(global);
-    if matches.value_of("subscripts").is_some() {
-        for sub in subs {
-            combined_search.extend(&partial_match);
-            combined_search.extend(&sub);
-        }
-    }
-    combined_search.extend(vec![0, 0]);
-    println!("Combined search: {:#?}",
-             String::from_utf8_lossy(&combined_search));
-    let mut set = false;
-    if matches.value_of("value").is_some() {
-        set = true;
-    }
-    if set {
-        // let new_value = Vec::from(matches.value_of("value").unwrap());
-        // database.set_value(&combined_search, new_value).unwrap();
+/// Exit codes shared by every subcommand
+const EXIT_OK: i32 = 0;
+/// The node, global or block asked for doesn't exist, or $ORDER ran off the end
+const EXIT_NOT_FOUND: i32 = 1;
+/// The command line or a global reference couldn't be understood
+const EXIT_USAGE: i32 = 2;
+/// An integrity check found problems
+const EXIT_INTEG: i32 = 3;
+/// The database couldn't be read or updated
+const EXIT_ERROR: i32 = 4;
+
+/// The exit code to use when a command fails with `error`
+fn exit_code(error: &ValueError) -> i32 {
+    match error {
+        ValueError::KeyError(_) => EXIT_USAGE,
+        ValueError::GlobalNotFound | ValueError::SubscriptNotFound => EXIT_NOT_FOUND,
+        _ => EXIT_ERROR,
+    }
+}
+
+/// Reports a bad argument and returns the exit code for it
+fn usage(message: &str) -> i32 {
+    eprintln!("ydb-ng: {}", message);
+    EXIT_USAGE
+}
+
+/// Parses the REFERENCE argument into a key for `database`
+fn reference_key(matches: &ArgMatches, database: &Database) -> Result<Vec<u8>, ValueError> {
+    database.parse_key(matches.value_of("REFERENCE").unwrap())
+}
+
+/// Prints a node's value, in ZWRITE format unless --raw is given
+fn get(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let key = reference_key(matches, database)?;
+    match database.get(&key)? {
+        Some(value) if matches.is_present("raw") => {
+            println!("{}", String::from_utf8_lossy(&value));
+            Ok(EXIT_OK)
+        },
+        Some(value) => {
+            println!("{}={}", format_key(&key), format_value(&value));
+            Ok(EXIT_OK)
+        },
+        None => {
+            eprintln!("ydb-ng: {} is undefined", format_key(&key));
+            Ok(EXIT_NOT_FOUND)
+        },
+    }
+}
+
+fn set(matches: &ArgMatches, database: &mut Database) -> Result<i32, ValueError> {
+    let key = reference_key(matches, database)?;
+    let value = matches.value_of("VALUE").unwrap();
+    database.set(&key, value.as_bytes())?;
+    Ok(EXIT_OK)
+}
+
+fn kill(matches: &ArgMatches, database: &mut Database) -> Result<i32, ValueError> {
+    let key = reference_key(matches, database)?;
+    let removed = if matches.is_present("node-only") {
+        database.zkill(&key)?
     } else {
-        //let combined_search = combined_search.into_boxed_slice();
-        // Find the block containing this subscript
-        let block = match database.find_value_block(&combined_search).unwrap() {
-            BlkNum::Block(x) => x,
-            _ => 0,
-        };
-        let b = database.get_block(block)?;
-        let b = get_block(&b, block, BlkType::DataBlock).unwrap();
-        // Search that block
-        let value = database.find_value(&combined_search, &b).unwrap();
-        // Print the value
-        println!("Value: {:#?}", String::from_utf8_lossy(&value));
+        database.kill(&key)?
+    };
+    println!("Killed {} nodes", removed);
+    Ok(if removed > 0 { EXIT_OK } else { EXIT_NOT_FOUND })
+}
+
+/// Prints the next (or previous) sibling of a reference, like $ORDER
+fn order(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let key = reference_key(matches, database)?;
+    match database.order(&key, matches.is_present("reverse"))? {
+        Some(next) => {
+            println!("{}", format_key(&next));
+            Ok(EXIT_OK)
+        },
+        None => Ok(EXIT_NOT_FOUND),
+    }
+}
+
+/// Prints `raw` as rows of 16 hex bytes, each labelled with its offset in the block
+fn print_hex<W: Write>(out: &mut W, raw: &[u8], offset: usize) -> io::Result<()> {
+    for (i, row) in raw.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        let text: String = row.iter()
+            .map(|b| if *b >= 0x20 && *b < 0x7F { *b as char } else { '.' })
+            .collect();
+        writeln!(out, "  {:8X} : | {:<47} | {:<16} |", offset + i * 16, hex.join(" "), text)?;
     }
     Ok(())
 }
 
-fn do_integ(database: &Mutex<Database>,
-            next: &IntegBlock,
-            to_visit: &Mutex<FnvHashSet<usize>>) -> Result<Vec<IntegBlock>, std::io::Error> {
-    let blk_num = match next.blk_num {
-        BlkNum::Block(x) => x,
-        _ => panic!("Expected a known block; didn't get it"),
-    };
-    to_visit.lock().remove(&blk_num);
-    //println!("Integ on block {}", blk_num);
-    let blk = database.lock().get_block(blk_num)?;
-    let blk = get_block(&blk, blk_num, next.typ.clone()).unwrap();
-    let next_blocks = blk.integ(&next.start).unwrap();
-    Ok(next_blocks)
-}
-
-fn add_block_to_pool(database: &Arc<Mutex<Database>>,
-                     to_visit: &Arc<Mutex<FnvHashSet<usize>>>,
-                     pool: &Arc<Mutex<ThreadPool>>,
-                     blk: IntegBlock) {
-    let next_blocks = do_integ(&database, &blk, &to_visit);
-    let next_blocks = next_blocks.unwrap();
-    let mut blocks_to_queue = Vec::with_capacity(next_blocks.len());
-    {
-        let mut b_to_visit = to_visit.lock();
-        for blk in next_blocks {
-            let blk_num = match blk.blk_num {
-                BlkNum::Block(x) => x,
-                _ => panic!("Scanning unknown block!"),
-            };
-            {
-                if b_to_visit.contains(&blk_num) {
-                    b_to_visit.remove(&blk_num);
-                    blocks_to_queue.push(blk);
-                    //println!("Adding block to queue: {:?}", blk);
-                }
+/// Prints a block's header and every record in it, like DSE DUMP -BLOCK
+fn dump_block(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let blk_num = match matches.value_of("BLOCK").unwrap().parse::<usize>() {
+        Ok(x) => x,
+        Err(_) => return Ok(usage("BLOCK must be a block number")),
+    };
+    print_block(&mut io::stdout().lock(), database, blk_num, matches.is_present("hex"))
+}
+
+/// Prints every documented field of the file header, like DSE DUMP -FILEHEADER
+fn dump_header(database: &Database) -> Result<i32, ValueError> {
+    for (name, value) in database.fhead.fields() {
+        println!("{:<32}{}", name, value);
+    }
+    Ok(EXIT_OK)
+}
+
+/// Writes a block's header and every record in it to `out`, like DSE DUMP -BLOCK
+fn print_block<W: Write>(out: &mut W, database: &Database, blk_num: usize, hex: bool)
+        -> Result<i32, ValueError> {
+    if blk_num >= database.total_blocks() {
+        eprintln!("ydb-ng: block {} is past the end of the database", blk_num);
+        return Ok(EXIT_NOT_FOUND);
+    }
+    let raw = database.get_block(blk_num)?;
+    let blk = match get_block(&raw, blk_num, BlkType::Unknown) {
+        Ok(x) => x,
+        Err(e) => {
+            writeln!(out, "Block {} has an unreadable header: {:?}", blk_num, e)?;
+            if hex {
+                print_hex(out, &raw, 0)?;
             }
+            return Ok(EXIT_ERROR);
         }
+    };
+    let header = blk.header();
+    writeln!(out, "Block {}   Size {:X}   Level {}   TN {:X}   Version {}\n",
+                  blk_num, header.bsiz, header.levl, header.tn, header.bver)?;
+    if hex {
+        print_hex(out, &raw[..std::mem::size_of_val(header)], 0)?;
     }
-    {
-        let l_pool = pool.lock();
-        for blk in blocks_to_queue {
-            let database = database.clone();
-            let to_visit = to_visit.clone();
-            let n_pool = pool.clone();
-            l_pool.execute(move || {
-                add_block_to_pool(&database, &to_visit, &n_pool, blk);
-            });
+    if header.levl == bitmap::LOCAL_BITMAP_LEVEL {
+        // Local bitmaps have no records, so the hex dump is all there is to show
+        if hex {
+            print_hex(out, blk.data(), std::mem::size_of_val(header))?;
         }
+        return Ok(EXIT_OK);
     }
-}
-
-fn main() -> std::io::Result<()> {
-    let matches = App::new("ydb-ng")
-        .version("0.1")
-        .author("Charles Hathaway <chathaway@logrit.com>")
-        .about("Reads YottaDB databases and allows clustered operation")
-        .arg(Arg::with_name("INPUT")
-             .help("The file to read from")
-             .required(true)
-             .index(1))
-        .arg(Arg::with_name("global")
-             .help("Global to search the database for")
-             .short("g")
-             .long("global")
-             .takes_value(true))
-        .arg(Arg::with_name("subscripts")
-             .help("Subscript of the key we are searching for")
-             .short("s")
-             .long("subscripts")
-             .takes_value(true))
-        .arg(Arg::with_name("value")
-             .help("If present, indicates that the indicated key should be set to value")
-             .short("v")
-             .long("value")
-             .takes_value(true))
-        .arg(Arg::with_name("integ")
-             .help("Runs an integrity check on all blocks")
-             .short("i")
-             .long("integ"))                   
-        .arg(Arg::with_name("integ-threads")
-             .help("Number of concurrent threads to use for integrity check")
-             .short("t")
-             .long("integ-threads")
-             .takes_value(true))
-        .get_matches();
-    // Load the database
-    let database = Arc::new(Mutex::new(Database::open(matches.value_of("INPUT").unwrap())?));
-    if matches.is_present("integ") {
-        let pool = Arc::new(Mutex::new(ThreadPool::new(4)));
-        let to_visit = Arc::new(Mutex::new(FnvHashSet::default()));
-        // Scan through the local maps; when we get an empty one, there are no more. Verify that
-        // they are marked correctly in the master bitmap
-        for i in 0.. {
-            let blk = database.lock().get_block(i * 512);
-            // We attempted to read past the end of the database, meaning no more local
-            // bitmaps
-            if blk.is_err() {
+    let mut key = Vec::new();
+    for (i, record) in RecordCursor::new(&blk).enumerate() {
+        let record = match record {
+            Ok(x) => x,
+            Err(e) => {
+                writeln!(out, "Rec:{}  unreadable: {:?}", i + 1, e)?;
                 break;
             }
-            let blk = blk.unwrap();
-            let blk = &blk[mem::size_of::<blk_hdr>()..];
-            let mut byte_num = 0;
-            let mut block_num = 0;
-            for byte in blk {
-                let byte = byte.clone();
-                // 2 bits for each block, so we have 4 blocks per byte to check
-                for bittle in 0..4 {
-                    // The 0th block is the local bitmap; skip it
-                    if byte & (0b11 << (2*bittle)) == 0b00 && block_num != 0 {
-                        let b = i * 512 + (4 * byte_num + (bittle as usize));
-                        //println!("Adding block {} to be scanned", b);
-                        to_visit.lock().insert(b);
-                    }
-                    block_num += 1;
-                    if block_num == 512 {
-                        break;
-                    }
-                }
-                byte_num += 1;
-                if block_num == 512 {
-                    break;
-                }
+        };
+        let rec_hdr = record.header();
+        let is_star = header.levl > 0 && rec_hdr.rsiz == 8;
+        let key_text = if is_star {
+            String::from("*")
+        } else {
+            match RecordCursor::expand_key(&record, &mut key) {
+                Ok(_) => format_key(&key),
+                Err(e) => format!("unreadable: {:?}", e),
             }
+        };
+        writeln!(out, "Rec:{}  Blk {}  Off {:X}  Size {:X}  Cmpc {}  Key {}",
+                  i + 1, blk_num, record.offset(), rec_hdr.rsiz, rec_hdr.cmpc, key_text)?;
+        if header.levl > 0 {
+            match record.ptr() {
+                Ok(BlkNum::Block(x)) => writeln!(out, "      Ptr {} ({:X})", x, x)?,
+                x => writeln!(out, "      Ptr unreadable: {:?}", x)?,
+            }
+        } else {
+            writeln!(out, "      Value {}", format_value(record.data()))?;
+        }
+        if hex {
+            let start = record.offset() - std::mem::size_of_val(header);
+            print_hex(out, &blk.data()[start..start + rec_hdr.rsiz as usize], record.offset())?;
+        }
+    }
+    Ok(EXIT_OK)
+}
+
+/// Prints the bitmap status, level and owner of a range of blocks
+fn block_map(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let start = matches.value_of("START").map(|s| s.parse::<usize>()).unwrap_or(Ok(0));
+    let end = matches.value_of("END").map(|s| s.parse::<usize>())
+        .unwrap_or_else(|| Ok(database.total_blocks()));
+    let (start, end) = match (start, end) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => return Ok(usage("START and END must be block numbers")),
+    };
+    for entry in database.block_map(start..end)? {
+        let status = match entry.status {
+            LocalBitmapStatus::Busy => "busy",
+            LocalBitmapStatus::NeverUsed => "free (never used)",
+            LocalBitmapStatus::Free => "free (reused)",
+            LocalBitmapStatus::Invalid => "invalid",
+        };
+        let levl = entry.levl.map(|l| l.to_string()).unwrap_or_default();
+        let owner = match entry.owner {
+            Some(BlockOwner::LocalBitmap) => String::from("local bitmap"),
+            Some(BlockOwner::DirectoryTree) => String::from("directory tree"),
+            Some(BlockOwner::Global(name)) => format!("^{}", String::from_utf8_lossy(&name)),
+            None => String::new(),
+        };
+        println!("Block {:<10} {:<18} {:>5}  {}", entry.blk_num, status, levl, owner);
+    }
+    Ok(EXIT_OK)
+}
+
+/// Prints each block visited looking up a global reference, like DSE FIND -KEY
+fn find_path(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let key = reference_key(matches, database)?;
+    print_path(database, &key)
+}
+
+/// Prints each block visited looking up `key`, with the record chosen in each
+fn print_path(database: &Database, key: &[u8]) -> Result<i32, ValueError> {
+    let path = database.find_path(key)?;
+    for step in path.iter() {
+        let tree = if step.typ == BlkType::DirectoryTree { "directory" } else { "global" };
+        let record = match step.record {
+            Some((i, offset)) => format!("Rec:{}  Off {:X}", i, offset),
+            None => String::from("no matching record"),
+        };
+        let key = match (&step.record, &step.key) {
+            (_, Some(key)) => format!("  Key {}", format_key(key)),
+            (Some(_), None) => String::from("  Key *"),
+            (None, None) => String::new(),
+        };
+        let ptr = step.ptr.map(|p| format!("  -> {}", p)).unwrap_or_default();
+        println!("Block {:<8} Level {}  {:<9}  {}{}{}", step.blk_num, step.levl, tree, record,
+                 key, ptr);
+    }
+    if path.last().map(|step| step.record.is_none()).unwrap_or(false) {
+        println!("{} not found", format_key(key));
+        return Ok(EXIT_NOT_FOUND);
+    }
+    Ok(EXIT_OK)
+}
+
+/// The extract format named on the command line
+fn extract_format(name: &str) -> Option<ExtractFormat> {
+    match name {
+        "zwr" => Some(ExtractFormat::Zwr),
+        "go" => Some(ExtractFormat::Go),
+        "bin" => Some(ExtractFormat::Binary),
+        _ => None,
+    }
+}
+
+fn extract(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let select: Option<Vec<Vec<u8>>> = matches.value_of("select").map(|s| {
+        s.split(',').map(|g| Vec::from(g.trim().trim_start_matches('^').as_bytes())).collect()
+    });
+    let format = extract_format(matches.value_of("format").unwrap()).unwrap();
+    let (progress, progress_thread) = match matches.is_present("progress") {
+        true => {
+            let (tx, t) = print_progress();
+            (Some(tx), Some(t))
+        },
+        false => (None, None),
+    };
+    let cancel = CancelToken::new();
+    let stats = match matches.value_of("OUTPUT") {
+        Some(path) if path != "-" => {
+            let mut out = BufWriter::new(File::create(path)?);
+            let stats = database.extract_with_progress(&mut out, format, select.as_deref(),
+                                                       progress, &cancel);
+            out.flush()?;
+            stats
+        },
+        _ => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            let stats = database.extract_with_progress(&mut out, format, select.as_deref(),
+                                                       progress, &cancel);
+            out.flush()?;
+            stats
+        },
+    };
+    // The sender has been dropped by now, which lets the progress thread finish its line
+    if let Some(t) = progress_thread {
+        t.join().unwrap();
+    }
+    let stats = stats?;
+    // The summary goes to stderr so it doesn't end up in an extract written to stdout
+    for global in stats.iter() {
+        eprintln!("^{}: {} records, max key length {}, max value length {}",
+                  String::from_utf8_lossy(&global.name), global.records, global.max_key_len,
+                  global.max_value_len);
+    }
+    let records: usize = stats.iter().map(|g| g.records).sum();
+    eprintln!("Extracted {} records from {} globals", records, stats.len());
+    if select.is_some() && stats.is_empty() {
+        return Ok(EXIT_NOT_FOUND);
+    }
+    Ok(EXIT_OK)
+}
+
+fn export_json(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let key = reference_key(matches, database)?;
+    let format = if matches.is_present("lines") { JsonFormat::Lines } else { JsonFormat::Nested };
+    let count = match matches.value_of("OUTPUT") {
+        Some(path) if path != "-" => {
+            let mut out = BufWriter::new(File::create(path)?);
+            let count = database.export_json(&mut out, &key, format)?;
+            out.flush()?;
+            count
+        },
+        _ => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            let count = database.export_json(&mut out, &key, format)?;
+            out.flush()?;
+            count
+        },
+    };
+    Ok(if count == 0 { EXIT_NOT_FOUND } else { EXIT_OK })
+}
+
+fn load(matches: &ArgMatches, database: &mut Database) -> Result<i32, ValueError> {
+    let mut options = LoadOptions {
+        format: matches.value_of("format").and_then(extract_format),
+        bulk: matches.is_present("bulk"),
+        ..LoadOptions::default()
+    };
+    if let Some(begin) = matches.value_of("begin") {
+        match begin.parse() {
+            Ok(x) => options.begin = x,
+            Err(_) => return Ok(usage("--begin must be a record number")),
+        }
+    }
+    if let Some(end) = matches.value_of("end") {
+        match end.parse() {
+            Ok(x) => options.end = Some(x),
+            Err(_) => return Ok(usage("--end must be a record number")),
+        }
+    }
+    if let Some(fill_factor) = matches.value_of("fill-factor") {
+        match fill_factor.parse() {
+            Ok(x) => options.fill_factor = x,
+            Err(_) => return Ok(usage("--fill-factor must be a percentage")),
         }
-        // Add the first block
-        {
-            let blk = IntegBlock {
-                blk_num: BlkNum::Block(1),
-                typ: BlkType::DirectoryTree,
-                start: vec![],
-                end: vec![],
-            };
-            add_block_to_pool(&database, &to_visit, &pool, blk);
-        }
-
-        loop {
-            {
-                let p = pool.lock();
-                if p.active_count() == 0  && p.queued_count() == 0 {
-                    break;
+    }
+    let report = match matches.value_of("INPUT").unwrap() {
+        "-" => {
+            let stdin = io::stdin();
+            let input = stdin.lock();
+            database.load(input, &options)?
+        },
+        path => database.load(BufReader::new(File::open(path)?), &options)?,
+    };
+    for rejection in report.rejected.iter() {
+        eprintln!("Record {} rejected: {:?}", rejection.record, rejection.error);
+    }
+    println!("Loaded {} records, rejected {}, read {}", report.loaded, report.rejected.len(),
+             report.read);
+    Ok(if report.rejected.is_empty() { EXIT_OK } else { EXIT_ERROR })
+}
+
+/// Starts a thread printing progress to stderr until the returned sender is dropped
+fn print_progress() -> (Sender<Progress>, thread::JoinHandle<()>) {
+    let (tx, rx) = channel::<Progress>();
+    let t = thread::spawn(move || {
+        for p in rx {
+            eprint!("\r{}/{} blocks", p.blocks_processed, p.total_blocks);
+        }
+        eprintln!();
+    });
+    (tx, t)
+}
+
+/// Runs an integrity check, and with --fix repairs what it safely can
+fn integ(matches: &ArgMatches, database: &mut Database, input: &str) -> Result<i32, ValueError> {
+    let mut options = IntegOptions::default();
+    if let Some(threads) = matches.value_of("threads") {
+        match threads.parse() {
+            Ok(x) => options.threads = x,
+            Err(_) => return Ok(usage("--threads must be a number")),
+        }
+    }
+    if let Some(tn) = matches.value_of("modified-after") {
+        match tn.parse() {
+            Ok(x) => options.modified_after = Some(x),
+            Err(_) => return Ok(usage("--modified-after must be a transaction number")),
+        }
+    }
+    if let Some(globals) = matches.value_of("globals") {
+        options.scope = IntegScope::Globals(globals.split(",")
+            .map(|g| Vec::from(g.trim_start_matches('^'))).collect());
+    }
+    if let Some(blocks) = matches.value_of("blocks") {
+        let range: Vec<usize> = blocks.split(":").filter_map(|b| b.parse().ok()).collect();
+        if range.len() != 2 {
+            return Ok(usage("--blocks must be START:END"));
+        }
+        options.scope = IntegScope::Blocks(range[0]..range[1]);
+    }
+    options.fast = matches.is_present("fast");
+    let mut progress_thread = None;
+    if matches.is_present("progress") {
+        let (tx, t) = print_progress();
+        options.progress = Some(tx);
+        progress_thread = Some(t);
+    }
+    let report = database.integ(&options)?;
+    // Dropping the sender lets the progress thread finish its line
+    drop(options);
+    if let Some(t) = progress_thread {
+        t.join().unwrap();
+    }
+    for finding in report.findings.iter() {
+        println!("Block {}: {:?}", finding.blk_num, finding.error);
+    }
+    for (blk_num, tn) in report.modified.iter() {
+        println!("Block {} modified at tn {}", blk_num, tn);
+    }
+    for blk_num in report.needs_upgrade.iter() {
+        println!("Block {}: still in the V4 format and needs upgrading", blk_num);
+    }
+    println!("Checked {} blocks, found {} problems", report.blocks_checked,
+             report.findings.len());
+    if !matches.is_present("fix") {
+        return Ok(if report.findings.is_empty() { EXIT_OK } else { EXIT_INTEG });
+    }
+    let log = matches.value_of("fix-log").map(String::from)
+        .unwrap_or_else(|| format!("{}.fixlog", input));
+    let changes = match database.repair(&report, &log) {
+        Err(ValueError::RepairNotSafe) => {
+            println!("Not fixing; only bitmap problems found by a full check can be fixed");
+            return Ok(EXIT_INTEG);
+        },
+        x => x?,
+    };
+    for change in changes.iter() {
+        println!("{}", change.description);
+    }
+    println!("Made {} changes, saved to {}", changes.len(), log);
+    Ok(EXIT_OK)
+}
+
+fn create(matches: &ArgMatches, input: &str) -> Result<i32, ValueError> {
+    let mut params = CreateParams::default();
+    {
+        let mut settings: [(&str, &mut usize); 6] = [
+            ("block-size", &mut params.blk_size),
+            ("allocation", &mut params.allocation),
+            ("extension-count", &mut params.extension_count),
+            ("key-size", &mut params.max_key_size),
+            ("record-size", &mut params.max_rec_size),
+            ("reserved-bytes", &mut params.reserved_bytes),
+        ];
+        for (name, setting) in settings.iter_mut() {
+            if let Some(value) = matches.value_of(*name) {
+                match value.parse() {
+                    Ok(x) => **setting = x,
+                    Err(_) => return Ok(usage(&format!("--{} must be a number", name))),
                 }
             }
-            sleep(Duration::from_millis(100));
         }
+    }
+    params.std_null_coll = !matches.is_present("gtm-null-collation");
+    let database = match Database::create(input, &params) {
+        Err(ValueError::InvalidParameter(name)) => {
+            return Ok(usage(&format!("the {} is out of range", name)));
+        },
+        x => x?,
+    };
+    println!("Created {} with {} blocks of {} bytes", input, database.total_blocks(),
+             params.blk_size);
+    Ok(EXIT_OK)
+}
 
-        let t = to_visit.lock();
-        for blk in t.iter() {
-            println!("Block {} incorrectly marked busy", blk);
+fn extend(matches: &ArgMatches, database: &mut Database) -> Result<i32, ValueError> {
+    let blocks = match matches.value_of("BLOCKS") {
+        Some(blocks) => match blocks.parse() {
+            Ok(x) => x,
+            Err(_) => return Ok(usage("BLOCKS must be a number")),
+        },
+        None => database.fhead.extension_size as usize,
+    };
+    let before = database.total_blocks();
+    database.extend(blocks)?;
+    println!("Extended from {} to {} blocks", before, database.total_blocks());
+    Ok(EXIT_OK)
+}
+
+fn reorg(matches: &ArgMatches, database: &mut Database) -> Result<i32, ValueError> {
+    let select: Option<Vec<Vec<u8>>> = matches.value_of("select").map(|s| {
+        s.split(',').map(|g| Vec::from(g.trim().trim_start_matches('^').as_bytes())).collect()
+    });
+    if let Some(fill_factor) = matches.value_of("fill-factor") {
+        match fill_factor.parse() {
+            Ok(x) => database.set_fill_factor(x),
+            Err(_) => return Ok(usage("--fill-factor must be a percentage")),
         }
+    }
+    let stats = database.reorg(select.as_deref())?;
+    for global in stats.iter() {
+        println!("^{}: {} blocks, now {}; {} levels removed", String::from_utf8_lossy(&global.name),
+                 global.blocks_before, global.blocks_after, global.levels_removed);
+    }
+    let freed: usize = stats.iter().map(|g| g.blocks_freed()).sum();
+    println!("Freed {} blocks in {} globals", freed, stats.len());
+    Ok(EXIT_OK)
+}
+
+fn stats(matches: &ArgMatches, database: &Database) -> Result<i32, ValueError> {
+    let select: Option<Vec<Vec<u8>>> = matches.value_of("select").map(|s| {
+        s.split(',').map(|g| Vec::from(g.trim().trim_start_matches('^').as_bytes())).collect()
+    });
+    let stats = database.all_global_stats(select.as_deref())?;
+    for global in stats.iter() {
+        println!("^{}  Root {}  Blocks {}  Records {}", String::from_utf8_lossy(&global.name),
+                 global.root, global.blocks(), global.records());
+        for (levl, level) in global.levels.iter().enumerate().rev() {
+            println!("  Level {:<3} {:>10} blocks {:>12} records {:>14} bytes", levl, level.blocks,
+                     level.records, level.used);
+        }
+        println!("  Fill {:.1}% average, {:.1}% min, {:.1}% max", global.avg_fill(),
+                 global.min_fill, global.max_fill);
+        println!("  Keys {} bytes, values {} bytes, {:.1}% of key bytes compressed\n",
+                 global.key_bytes, global.value_bytes, global.avg_compression());
+    }
+    if select.is_some() && stats.is_empty() {
+        return Ok(EXIT_NOT_FOUND);
+    }
+    Ok(EXIT_OK)
+}
+
+fn journal(input: &str) -> Result<i32, ValueError> {
+    let reader = open_journal(input)?;
+    println!("Journal version {}\n", reader.header.version);
+    let mut count = 0;
+    for record in reader {
+        let record = record?;
+        count += 1;
+        let prefix = record.prefix();
+        print!("TN {:<10} Time {:<10} ", prefix.tn, prefix.time);
+        match record {
+            JnlRecord::Pblk { blk_num, contents, .. } => {
+                println!("PBLK   Blk {} ({} bytes)", blk_num, contents.len())
+            },
+            JnlRecord::Aimg { blk_num, contents, .. } => {
+                println!("AIMG   Blk {} ({} bytes)", blk_num, contents.len())
+            },
+            JnlRecord::Set { update, value, .. } => {
+                println!("SET    {}={}", format_key(&update.key), format_value(&value))
+            },
+            JnlRecord::Kill { update, .. } => println!("KILL   {}", format_key(&update.key)),
+            JnlRecord::Zkill { update, .. } => println!("ZKILL  {}", format_key(&update.key)),
+            JnlRecord::Tcom { token_seq, num_participants, .. } => {
+                println!("TCOM   Token {}  Participants {}", token_seq, num_participants)
+            },
+            JnlRecord::Epoch { jnl_seqno, free_blocks, total_blks, .. } => {
+                println!("EPOCH  Seqno {}  Free {} of {} blocks", jnl_seqno, free_blocks,
+                         total_blks)
+            },
+            JnlRecord::Eof { jnl_seqno, .. } => println!("EOF    Seqno {}", jnl_seqno),
+            JnlRecord::Other { prefix, data } => {
+                println!("Type {:<3} ({} bytes)", prefix.jrec_type, data.len())
+            },
+        }
+    }
+    println!("\nRead {} records", count);
+    Ok(EXIT_OK)
+}
+
+fn truncate(database: &mut Database) -> Result<i32, ValueError> {
+    let before = database.total_blocks();
+    let removed = database.truncate()?;
+    if removed == 0 {
+        println!("Nothing to truncate; the last local bitmap has blocks in use");
     } else {
-        find_value(&matches, &database.lock())?;
+        println!("Truncated from {} to {} blocks", before, database.total_blocks());
+    }
+    Ok(EXIT_OK)
+}
+
+fn undo_fix(matches: &ArgMatches, database: &mut Database) -> Result<i32, ValueError> {
+    let changes = load_repair_log(matches.value_of("LOG").unwrap())?;
+    database.undo_repair(&changes)?;
+    println!("Undid {} changes", changes.len());
+    Ok(EXIT_OK)
+}
+
+/// The database file argument every subcommand starts with
+fn database_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("DATABASE")
+        .help("The database file to use")
+        .required(true)
+        .index(1)
+}
+
+/// A global reference argument, like ^ACCT(1,"name")
+fn reference_arg<'a, 'b>(help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name("REFERENCE")
+        .help(help)
+        .required(true)
+        .index(2)
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("ydb-ng")
+        .version("0.1")
+        .author("Charles Hathaway <chathaway@logrit.com>")
+        .about("Reads YottaDB databases and allows clustered operation")
+        .after_help("Exit codes: 0 success, 1 not found, 2 bad arguments, \
+                     3 integrity problems, 4 database error")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("force")
+             .help("Write to the database even if its header says YottaDB has it open")
+             .long("force")
+             .global(true))
+        .subcommand(SubCommand::with_name("get")
+             .about("Prints the value of a node")
+             .arg(database_arg())
+             .arg(reference_arg("Node to print, like ^ACCT(1,\"name\")"))
+             .arg(Arg::with_name("raw")
+                  .help("Print only the value, without quoting")
+                  .long("raw")))
+        .subcommand(SubCommand::with_name("set")
+             .about("Sets the value of a node, creating the global if needed")
+             .arg(database_arg())
+             .arg(reference_arg("Node to set, like ^ACCT(1,\"name\")"))
+             .arg(Arg::with_name("VALUE")
+                  .help("The value to store")
+                  .required(true)
+                  .index(3)))
+        .subcommand(SubCommand::with_name("kill")
+             .about("Removes a node and everything under it")
+             .arg(database_arg())
+             .arg(reference_arg("Node to remove, like ^ACCT(1)"))
+             .arg(Arg::with_name("node-only")
+                  .help("Only remove the node's own value, like ZKILL")
+                  .long("node-only")))
+        .subcommand(SubCommand::with_name("order")
+             .about("Prints the next sibling of a node, like $ORDER")
+             .arg(database_arg())
+             .arg(reference_arg("Node to start from; an empty last subscript starts at the first"))
+             .arg(Arg::with_name("reverse")
+                  .help("Print the previous sibling instead")
+                  .long("reverse")))
+        .subcommand(SubCommand::with_name("integ")
+             .about("Runs an integrity check on the database")
+             .arg(database_arg())
+             .arg(Arg::with_name("threads")
+                  .help("Number of concurrent threads to use")
+                  .short("t")
+                  .long("threads")
+                  .takes_value(true))
+             .arg(Arg::with_name("modified-after")
+                  .help("Report blocks updated after this transaction number")
+                  .long("modified-after")
+                  .takes_value(true))
+             .arg(Arg::with_name("globals")
+                  .help("Comma separated globals to restrict the check to")
+                  .long("globals")
+                  .takes_value(true)
+                  .conflicts_with("blocks"))
+             .arg(Arg::with_name("blocks")
+                  .help("Block range START:END to restrict the check to; END is excluded")
+                  .long("blocks")
+                  .takes_value(true))
+             .arg(Arg::with_name("fast")
+                  .help("Only check the index structure, skipping data blocks")
+                  .long("fast"))
+             .arg(Arg::with_name("fix")
+                  .help("Fix bitmap errors and the free block count after checking")
+                  .long("fix"))
+             .arg(Arg::with_name("fix-log")
+                  .help("Where to save the changes made by --fix; defaults to DATABASE.fixlog")
+                  .long("fix-log")
+                  .takes_value(true))
+             .arg(Arg::with_name("progress")
+                  .help("Print progress to stderr")
+                  .long("progress")))
+        .subcommand(SubCommand::with_name("create")
+             .about("Creates a new, empty database file, like MUPIP CREATE")
+             .arg(database_arg())
+             .arg(Arg::with_name("block-size")
+                  .help("Block size in bytes, a multiple of 512; defaults to 4096")
+                  .long("block-size")
+                  .takes_value(true))
+             .arg(Arg::with_name("allocation")
+                  .help("Number of blocks to create, not counting bitmaps; defaults to 5000")
+                  .long("allocation")
+                  .takes_value(true))
+             .arg(Arg::with_name("extension-count")
+                  .help("Blocks to add when the file is full; defaults to 10000")
+                  .long("extension-count")
+                  .takes_value(true))
+             .arg(Arg::with_name("key-size")
+                  .help("Longest key in bytes; defaults to 64")
+                  .long("key-size")
+                  .takes_value(true))
+             .arg(Arg::with_name("record-size")
+                  .help("Longest value in bytes; defaults to 256")
+                  .long("record-size")
+                  .takes_value(true))
+             .arg(Arg::with_name("reserved-bytes")
+                  .help("Bytes to leave unused in each block; defaults to 0")
+                  .long("reserved-bytes")
+                  .takes_value(true))
+             .arg(Arg::with_name("gtm-null-collation")
+                  .help("Sort empty string subscripts between numbers and strings, instead of \
+                         first")
+                  .long("gtm-null-collation")))
+        .subcommand(SubCommand::with_name("extend")
+             .about("Adds blocks to the end of the database file, like MUPIP EXTEND")
+             .arg(database_arg())
+             .arg(Arg::with_name("BLOCKS")
+                  .help("Number of blocks to add; defaults to the extension count")
+                  .index(2)))
+        .subcommand(SubCommand::with_name("reorg")
+             .about("Merges sparsely filled blocks and frees the rest, like MUPIP REORG")
+             .arg(database_arg())
+             .arg(Arg::with_name("select")
+                  .help("Comma separated globals to reorganise; * matches any characters")
+                  .long("select")
+                  .takes_value(true))
+             .arg(Arg::with_name("fill-factor")
+                  .help("Percentage of each block to fill; at least 30")
+                  .long("fill-factor")
+                  .takes_value(true)))
+        .subcommand(SubCommand::with_name("stats")
+             .about("Shows the blocks, records and fill of each level of each global")
+             .arg(database_arg())
+             .arg(Arg::with_name("select")
+                  .help("Comma separated globals to show; * matches any characters")
+                  .long("select")
+                  .takes_value(true)))
+        .subcommand(SubCommand::with_name("journal")
+             .about("Lists the records of a journal file, like MUPIP JOURNAL -EXTRACT")
+             .arg(Arg::with_name("JOURNAL")
+                  .help("The journal (.mjl) file to read")
+                  .required(true)
+                  .index(1)))
+        .subcommand(SubCommand::with_name("truncate")
+             .about("Removes unused local bitmap regions from the end of the file, like \
+                     MUPIP REORG -TRUNCATE")
+             .arg(database_arg()))
+        .subcommand(SubCommand::with_name("undo-fix")
+             .about("Undoes the changes saved by a previous integ --fix")
+             .arg(database_arg())
+             .arg(Arg::with_name("LOG")
+                  .help("The log saved by integ --fix")
+                  .required(true)
+                  .index(2)))
+        .subcommand(SubCommand::with_name("dump-block")
+             .about("Prints a block's header and records, like DSE DUMP -BLOCK")
+             .arg(database_arg())
+             .arg(Arg::with_name("BLOCK")
+                  .help("The block number to dump")
+                  .required(true)
+                  .index(2))
+             .arg(Arg::with_name("hex")
+                  .help("Also print the raw bytes of the header and each record")
+                  .long("hex")))
+        .subcommand(SubCommand::with_name("dump-header")
+             .about("Prints the database file header, like DSE DUMP -FILEHEADER")
+             .arg(database_arg()))
+        .subcommand(SubCommand::with_name("map")
+             .about("Shows the bitmap status, level and owning global of a range of blocks")
+             .arg(database_arg())
+             .arg(Arg::with_name("START")
+                  .help("First block to show; defaults to 0")
+                  .index(2))
+             .arg(Arg::with_name("END")
+                  .help("Block to stop before; defaults to the end of the database")
+                  .index(3)))
+        .subcommand(SubCommand::with_name("find-path")
+             .about("Shows every block visited looking up a key, like DSE FIND -KEY")
+             .arg(database_arg())
+             .arg(reference_arg("Global reference to look up, like ^ACCT(1,\"name\")")))
+        .subcommand(SubCommand::with_name("extract")
+             .about("Writes globals to an extract file, like MUPIP EXTRACT")
+             .arg(database_arg())
+             .arg(Arg::with_name("OUTPUT")
+                  .help("File to write; defaults to stdout")
+                  .index(2))
+             .arg(Arg::with_name("select")
+                  .help("Comma separated globals to extract; * matches any characters")
+                  .long("select")
+                  .takes_value(true))
+             .arg(Arg::with_name("format")
+                  .help("Extract format")
+                  .long("format")
+                  .possible_values(&["zwr", "go", "bin"])
+                  .default_value("zwr"))
+             .arg(Arg::with_name("progress")
+                  .help("Print progress to stderr")
+                  .long("progress")))
+        .subcommand(SubCommand::with_name("export-json")
+             .about("Writes a node and everything under it as JSON")
+             .arg(database_arg())
+             .arg(reference_arg("Node to export, like ^ACCT or ^ACCT(1)"))
+             .arg(Arg::with_name("OUTPUT")
+                  .help("File to write; defaults to stdout")
+                  .index(3))
+             .arg(Arg::with_name("lines")
+                  .help("Write one {\"key\": [...], \"value\": ...} object per line instead \
+                         of a nested document")
+                  .long("lines")))
+        .subcommand(SubCommand::with_name("load")
+             .about("Sets the nodes in a ZWR, GO or binary extract, like MUPIP LOAD")
+             .arg(database_arg())
+             .arg(Arg::with_name("INPUT")
+                  .help("Extract file to read, or - for stdin")
+                  .required(true)
+                  .index(2))
+             .arg(Arg::with_name("format")
+                  .help("Require this format; by default it is taken from the header")
+                  .long("format")
+                  .possible_values(&["zwr", "go", "bin"])
+                  .takes_value(true))
+             .arg(Arg::with_name("begin")
+                  .help("First record to load, counting nodes from 1 after the header")
+                  .long("begin")
+                  .takes_value(true))
+             .arg(Arg::with_name("end")
+                  .help("Last record to load")
+                  .long("end")
+                  .takes_value(true))
+             .arg(Arg::with_name("fill-factor")
+                  .help("Percentage of each block to fill; at least 30")
+                  .long("fill-factor")
+                  .takes_value(true))
+             .arg(Arg::with_name("bulk")
+                  .help("Build each global's blocks directly; the input must be in key order \
+                         and its globals must not exist yet")
+                  .long("bulk")))
+        .subcommand(SubCommand::with_name("shell")
+             .about("Browses the database interactively; type help at the prompt for commands")
+             .arg(database_arg()))
+}
+
+fn run(matches: &ArgMatches) -> Result<i32, ValueError> {
+    let (name, matches) = match matches.subcommand() {
+        (name, Some(matches)) => (name, matches),
+        _ => return Ok(EXIT_USAGE),
+    };
+    if name == "journal" {
+        return journal(matches.value_of("JOURNAL").unwrap());
+    }
+    let input = matches.value_of("DATABASE").unwrap();
+    if name == "create" {
+        return create(matches, input);
+    }
+    let mut database = Database::open(input)?;
+    let writes = match name {
+        "set" | "kill" | "undo-fix" | "extend" | "reorg" | "truncate" | "load" => true,
+        "integ" => matches.is_present("fix"),
+        _ => false,
+    };
+    if writes && database.in_use() && !matches.is_present("force") {
+        eprintln!("ydb-ng: {} is open in YottaDB; run MUPIP RUNDOWN once nothing is using it, \
+                   or pass --force", input);
+        return Err(ValueError::DatabaseInUse);
+    }
+    match name {
+        "get" => get(matches, &database),
+        "set" => set(matches, &mut database),
+        "kill" => kill(matches, &mut database),
+        "order" => order(matches, &database),
+        "integ" => integ(matches, &mut database, input),
+        "undo-fix" => undo_fix(matches, &mut database),
+        "extend" => extend(matches, &mut database),
+        "reorg" => reorg(matches, &mut database),
+        "stats" => stats(matches, &database),
+        "truncate" => truncate(&mut database),
+        "dump-block" => dump_block(matches, &database),
+        "dump-header" => dump_header(&database),
+        "map" => block_map(matches, &database),
+        "find-path" => find_path(matches, &database),
+        "extract" => extract(matches, &database),
+        "load" => load(matches, &mut database),
+        "export-json" => export_json(matches, &database),
+        "shell" => cli::shell::run(&database),
+        _ => Ok(EXIT_USAGE),
+    }
+}
+
+fn main() {
+    let matches = match app().get_matches_safe() {
+        Ok(x) => x,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            process::exit(EXIT_USAGE);
+        },
+        // Help and version output
+        Err(e) => e.exit(),
+    };
+    let code = match run(&matches) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("ydb-ng: {:?}", e);
+            exit_code(&e)
+        },
+    };
+    process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ydb_ng::key::{encode_key, parse_reference};
+
+    /// A database in the temporary directory, removed when dropped
+    struct Scratch(String, Database);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let path = std::env::temp_dir()
+                .join(format!("ydb-ng-main-{}-{}.dat", process::id(), name));
+            let path = path.to_string_lossy().into_owned();
+            let _ = std::fs::remove_file(&path);
+            let params = CreateParams { blk_size: 1024, allocation: 2000, ..Default::default() };
+            let database = Database::create(&path, &params).unwrap();
+            Scratch(path, database)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn key(reference: &str) -> Vec<u8> {
+        let (name, subs) = parse_reference(reference).unwrap();
+        encode_key(&name, &subs, true)
+    }
+
+    fn dump(database: &Database, blk_num: usize, hex: bool) -> (i32, String) {
+        let mut out = Vec::new();
+        let code = print_block(&mut out, database, blk_num, hex).unwrap();
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn dumps_blocks() {
+        let mut scratch = Scratch::new("dump");
+        let database = &mut scratch.1;
+        database.set(&key("^ACCT(1)"), b"a").unwrap();
+        database.set(&key("^ACCT(1,2)"), b"b").unwrap();
+        let data = database.find_path(&key("^ACCT(1)")).unwrap().last().unwrap().blk_num;
+        assert_eq!(dump(database, data, false), (EXIT_OK, format!("\
+Block {0}   Size 27   Level 0   TN 2   Version 1
+
+Rec:1  Blk {0}  Off 10  Size E  Cmpc 0  Key ^ACCT(1)
+      Value \"a\"
+Rec:2  Blk {0}  Off 1E  Size 9  Cmpc 8  Key ^ACCT(1,2)
+      Value \"b\"
+", data)));
+        // Index records show their pointers, and the * record has no key
+        assert_eq!(dump(database, 1, false), (EXIT_OK, String::from("\
+Block 1   Size 18   Level 1   TN 1   Version 1
+
+Rec:1  Blk 1  Off 10  Size 8  Cmpc 0  Key *
+      Ptr 2 (2)
+")));
+        let (code, text) = dump(database, 1, true);
+        assert_eq!(code, EXIT_OK);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[2], "         0 : | 01 00 00 01 18 00 00 00 01 00 00 00 00 00 00 00 \
+                              | ................ |");
+        assert_eq!(lines[5], "        10 : | 08 00 00 00 02 00 00 00                         \
+                              | ........         |");
+    }
+
+    #[test]
+    fn dumps_bitmaps_and_bad_blocks() {
+        let scratch = Scratch::new("bitmap");
+        let database = &scratch.1;
+        // A local bitmap has no records, so only the hex dump shows its contents
+        let (code, text) = dump(database, 0, false);
+        assert_eq!(code, EXIT_OK);
+        assert_eq!(text.lines().count(), 2);
+        let (_, text) = dump(database, 0, true);
+        let lines: Vec<&str> = text.lines().collect();
+        // The 16 byte header and 128 bytes of bitmap, with blocks 0, 1 and 2 busy
+        assert_eq!(lines.len(), 2 + 1 + 128 / 16);
+        assert_eq!(lines[3], "        10 : | 40 55 55 55 55 55 55 55 55 55 55 55 55 55 55 55 \
+                              | @UUUUUUUUUUUUUUU |");
+        assert_eq!(dump(database, database.total_blocks(), false), (EXIT_NOT_FOUND, String::new()));
+        // A never used block is all zeros, which isn't a valid header
+        let (code, text) = dump(database, 3, true);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(text.starts_with("Block 3 has an unreadable header"));
+        assert_eq!(text.lines().count(), 1 + 1024 / 16);
     }
-    Ok(())
 }
@@ -0,0 +1,133 @@
+use super::*;
+
+use std::ops::Range;
+use fnv::FnvHashMap;
+
+/// What a block is used for, found by walking the directory tree and every global's tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockOwner {
+    LocalBitmap,
+    DirectoryTree,
+    Global(Vec<u8>),
+}
+
+/// A block's bitmap status, and where it sits in the database if it is in use
+#[derive(Debug, Clone)]
+pub struct BlockMapEntry {
+    pub blk_num: usize,
+    pub status: LocalBitmapStatus,
+    /// Level of the block, if it was reached from the directory tree
+    pub levl: Option<u8>,
+    /// Owner of the block, if it is a bitmap or was reached from the directory tree
+    pub owner: Option<BlockOwner>,
+}
+
+impl Database {
+    /// Reports the bitmap status of every block in `range`, and the level and owner of those
+    /// reachable from the directory tree. Every index block is read to find owners, but data
+    /// blocks are not.
+    pub fn block_map(&self, range: Range<usize>) -> Result<Vec<BlockMapEntry>, ValueError> {
+        let range = range.start..std::cmp::min(range.end, self.total_blocks());
+        let mut owners: FnvHashMap<usize, (u8, BlockOwner)> = FnvHashMap::default();
+        {
+            let mut note = |owner: &BlockOwner, blk_num: usize, levl: u8| {
+                if range.contains(&blk_num) {
+                    owners.insert(blk_num, (levl, owner.clone()));
+                }
+            };
+            let owner = BlockOwner::DirectoryTree;
+            self.walk_index(1, BlkType::DirectoryTree, &mut |b, l| note(&owner, b, l))?;
+            for (name, root) in self.globals()? {
+                let owner = BlockOwner::Global(name);
+                self.walk_index(root, BlkType::IndexBlock, &mut |b, l| note(&owner, b, l))?;
+            }
+        }
+
+        let bplmap = self.blocks_per_map();
+        let mut ret = Vec::with_capacity(range.len());
+        let mut map_num = None;
+        let mut statuses = Vec::new();
+        for blk_num in range {
+            if map_num != Some(blk_num / bplmap) {
+                map_num = Some(blk_num / bplmap);
+                statuses = self.local_bitmap(blk_num / bplmap)?;
+            }
+            let (levl, owner) = match owners.remove(&blk_num) {
+                Some((levl, owner)) => (Some(levl), Some(owner)),
+                None if blk_num % bplmap == 0 => (None, Some(BlockOwner::LocalBitmap)),
+                None => (None, None),
+            };
+            ret.push(BlockMapEntry {
+                blk_num,
+                status: statuses[blk_num % bplmap].clone(),
+                levl,
+                owner,
+            });
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key, sample_nodes};
+
+    fn owned_by<'a>(map: &'a [BlockMapEntry], owner: &BlockOwner) -> Vec<&'a BlockMapEntry> {
+        map.iter().filter(|e| e.owner.as_ref() == Some(owner)).collect()
+    }
+
+    #[test]
+    fn maps_owners_levels_and_status() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(1500) {
+            db.set(&key, &value).unwrap();
+        }
+        db.set(&key("^y(1)"), b"1").unwrap();
+        let map = db.block_map(0..db.total_blocks()).unwrap();
+        assert_eq!(map.len(), db.total_blocks());
+        assert!(map.iter().enumerate().all(|(i, e)| e.blk_num == i));
+
+        let bitmaps: Vec<usize> = owned_by(&map, &BlockOwner::LocalBitmap).iter()
+            .map(|e| e.blk_num).collect();
+        assert_eq!(bitmaps, vec![0, 512, 1024, 1536]);
+        let directory: Vec<_> = owned_by(&map, &BlockOwner::DirectoryTree).iter()
+            .map(|e| (e.blk_num, e.levl)).collect();
+        assert_eq!(directory, vec![(1, Some(1)), (2, Some(0))]);
+        let x = owned_by(&map, &BlockOwner::Global(b"x".to_vec()));
+        let stats = db.global_stats(b"x").unwrap();
+        assert_eq!(x.len(), stats.blocks());
+        let root = x.iter().find(|e| e.blk_num == stats.root).unwrap();
+        assert_eq!(root.levl, Some(stats.levels.len() as u8 - 1));
+        for levl in 0..stats.levels.len() {
+            let count = x.iter().filter(|e| e.levl == Some(levl as u8)).count();
+            assert_eq!(count, stats.levels[levl].blocks);
+        }
+        assert_eq!(owned_by(&map, &BlockOwner::Global(b"y".to_vec())).len(), 2);
+        // Owned blocks are busy, and nothing else has been used yet
+        for entry in map.iter() {
+            match entry.owner {
+                Some(_) => assert_eq!(entry.status, LocalBitmapStatus::Busy, "{:?}", entry),
+                None => assert_eq!(entry.status, LocalBitmapStatus::NeverUsed, "{:?}", entry),
+            }
+        }
+
+        // Killing the global frees all but its root and one data block
+        db.kill(&key("^x")).unwrap();
+        let after = db.block_map(0..db.total_blocks()).unwrap();
+        let freed: Vec<_> = x.iter().map(|e| &after[e.blk_num])
+            .filter(|e| e.owner.is_none()).collect();
+        assert_eq!(freed.len(), x.len() - 2);
+        assert!(freed.iter().all(|e| e.status == LocalBitmapStatus::Free && e.levl.is_none()));
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_database() {
+        let db = TempDb::small();
+        let map = db.block_map(1530..5000).unwrap();
+        assert_eq!(map.len(), db.total_blocks() - 1530);
+        assert_eq!(map[6].blk_num, 1536);
+        assert_eq!(map[6].owner, Some(BlockOwner::LocalBitmap));
+        assert!(db.block_map(5000..6000).unwrap().is_empty());
+    }
+}
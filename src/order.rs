@@ -0,0 +1,401 @@
+use super::*;
+
+use std::sync::mpsc::Sender;
+
+use self::key::{encode_subscript, KeyError};
+use self::progress::{Progress, CancelToken};
+use self::tree::MAX_BT_DEPTH;
+use self::update::{Entry, read_entries};
+
+/// Iterates over the nodes of one global as (key, value) pairs, in collation order or in
+/// reverse. Each block is read once, as the iteration reaches it
+pub struct Nodes<'a> {
+    database: &'a Database,
+    reverse: bool,
+    /// Pointers in each index block from the root down, in the direction of travel, and the one
+    /// currently being followed
+    levels: Vec<(Vec<usize>, usize)>,
+    /// What is left of the current level 0 block, in the direction of travel
+    records: std::vec::IntoIter<Entry>,
+    /// Blocks read so far, and where to report them
+    blocks_read: usize,
+    progress: Option<Sender<Progress>>,
+    cancel: CancelToken,
+}
+
+impl<'a> Nodes<'a> {
+    fn new(database: &'a Database, reverse: bool) -> Nodes<'a> {
+        Nodes {
+            database,
+            reverse,
+            levels: Vec::new(),
+            records: Vec::new().into_iter(),
+            blocks_read: 0,
+            progress: None,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// Sends progress to `progress` after each block is read, counting on from `blocks_read`
+    /// so several iterations can report as one scan, and stops with Cancelled once `cancel` is
+    /// set
+    pub fn watch(mut self, progress: Option<Sender<Progress>>, cancel: CancelToken,
+                 blocks_read: usize) -> Nodes<'a> {
+        self.blocks_read += blocks_read;
+        self.progress = progress;
+        self.cancel = cancel;
+        self
+    }
+
+    /// Number of blocks read so far, including any counted before `watch`
+    pub fn blocks_read(&self) -> usize {
+        self.blocks_read
+    }
+
+    /// Reads from `blk_num` down to a level 0 block. With a `start` key, follows the records
+    /// leading to it and skips what comes before it; otherwise takes the first child of each
+    /// block in the direction of travel
+    fn descend(&mut self, mut blk_num: usize, start: Option<&[u8]>) -> Result<(), ValueError> {
+        while self.levels.len() <= MAX_BT_DEPTH {
+            let raw = self.database.get_block(blk_num)?;
+            self.blocks_read += 1;
+            if let Some(progress) = &self.progress {
+                // Nobody listening is not a reason to stop reading
+                let _ = progress.send(Progress {
+                    blocks_processed: self.blocks_read,
+                    total_blocks: self.database.total_blocks(),
+                });
+            }
+            let blk = get_block(&raw, blk_num, BlkType::IndexBlock)?;
+            let mut entries = read_entries(&blk)?;
+            if blk.header().levl == 0 {
+                if let Some(start) = start {
+                    let reverse = self.reverse;
+                    entries.retain(|e| match &e.key {
+                        Some(k) if reverse => k.as_slice() <= start,
+                        Some(k) => k.as_slice() >= start,
+                        None => false,
+                    });
+                }
+                if self.reverse {
+                    entries.reverse();
+                }
+                self.records = entries.into_iter();
+                return Ok(());
+            }
+            let mut index = match start {
+                Some(start) => entries.iter()
+                    .position(|e| e.key.as_ref().map(|k| k.as_slice() >= start).unwrap_or(true))
+                    .ok_or(ValueError::MalformedRecord)?,
+                None if self.reverse => entries.len() - 1,
+                None => 0,
+            };
+            let mut ptrs = entries.iter().map(|e| e.ptr()).collect::<Result<Vec<_>, _>>()?;
+            if self.reverse {
+                ptrs.reverse();
+                index = ptrs.len() - 1 - index;
+            }
+            blk_num = ptrs[index];
+            self.levels.push((ptrs, index));
+        }
+        Err(ValueError::MalformedRecord)
+    }
+
+    /// Moves on to the next level 0 block in the direction of travel. Returns false once the
+    /// whole tree has been seen
+    fn next_block(&mut self) -> Result<bool, ValueError> {
+        while let Some((ptrs, index)) = self.levels.last_mut() {
+            if *index + 1 < ptrs.len() {
+                // Checked only when there is more to read, so the error is returned just once
+                if self.cancel.is_cancelled() {
+                    return Err(ValueError::Cancelled);
+                }
+                *index += 1;
+                let blk_num = ptrs[*index];
+                self.descend(blk_num, None)?;
+                return Ok(true);
+            }
+            self.levels.pop();
+        }
+        Ok(false)
+    }
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>), ValueError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.records.next() {
+                return Some(Ok((entry.key.unwrap_or_default(), entry.value)));
+            }
+            match self.next_block() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(e) => {
+                    self.levels.clear();
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+/// The key of the child of `parent` (a key prefix ending in a subscript separator) that `key`
+/// is or is under, as a full key
+fn child_key(parent: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    if !key.starts_with(parent) || key.get(parent.len()).map(|c| *c == 0).unwrap_or(true) {
+        return None;
+    }
+    let end = parent.len() + key[parent.len()..].iter().position(|c| *c == 0)?;
+    let mut ret = key[..=end].to_vec();
+    ret.push(0);
+    Some(ret)
+}
+
+/// The global name at the start of a key or partial key
+fn global_name(key: &[u8]) -> &[u8] {
+    let end = key.iter().position(|c| !(c.is_ascii_alphanumeric() || *c == b'%'));
+    &key[..end.unwrap_or(key.len())]
+}
+
+impl Database {
+    fn nodes_from(&self, name: &[u8], start: &[u8], reverse: bool)
+            -> Result<Nodes<'_>, ValueError> {
+        let mut nodes = Nodes::new(self, reverse);
+        match self.find_global_root(name) {
+            Ok(root) => nodes.descend(root, Some(start))?,
+            Err(ValueError::GlobalNotFound) => {},
+            Err(e) => return Err(e),
+        }
+        Ok(nodes)
+    }
+
+    /// Iterates over the nodes of the global `start` belongs to, from the first key at or after
+    /// `start`. `start` may be a partial key, like a global name followed by a 0 byte
+    pub fn nodes(&self, start: &[u8]) -> Result<Nodes<'_>, ValueError> {
+        self.nodes_from(global_name(start), start, false)
+    }
+
+    /// Iterates backwards over the nodes of the global `start` belongs to, from the last key at
+    /// or before `start`. The global name followed by a 1 byte starts from its last node
+    pub fn nodes_rev(&self, start: &[u8]) -> Result<Nodes<'_>, ValueError> {
+        self.nodes_from(global_name(start), start, true)
+    }
+
+    /// Like $ORDER: finds the sibling after `key` (or before it, if `reverse` is set) that has
+    /// a value or children, and returns its key. An empty last subscript starts from the first
+    /// (or last) sibling. For an unsubscripted global, steps through the global names
+    pub fn order(&self, key: &[u8], reverse: bool) -> Result<Option<Vec<u8>>, ValueError> {
+        self.sibling(key, reverse, true)
+    }
+
+    /// Like `order`, but an empty last subscript is taken as the sibling it is, not as the
+    /// start of the list, so every child can be visited in turn whichever way "" collates
+    pub fn next_sibling(&self, key: &[u8], reverse: bool)
+            -> Result<Option<Vec<u8>>, ValueError> {
+        self.sibling(key, reverse, false)
+    }
+
+    fn sibling(&self, key: &[u8], reverse: bool, empty_starts: bool)
+            -> Result<Option<Vec<u8>>, ValueError> {
+        if !key.ends_with(&[0, 0]) {
+            return Err(ValueError::from(KeyError::Unterminated));
+        }
+        let name_end = key.iter().position(|c| *c == 0).unwrap_or(key.len());
+        if key.len() <= name_end + 2 {
+            let globals = self.globals()?;
+            let name = &key[..name_end];
+            let found = if reverse {
+                globals.into_iter().rev().find(|(g, _)| g.as_slice() < name)
+            } else {
+                globals.into_iter().find(|(g, _)| g.as_slice() > name)
+            };
+            return Ok(found.map(|(mut g, _)| {
+                g.extend(&[0, 0]);
+                g
+            }));
+        }
+        // Split off the last subscript; the parent prefix keeps its trailing separator
+        let sub_end = key.len() - 2;
+        let sub_start = key[..sub_end].iter().rposition(|c| *c == 0)
+            .ok_or(ValueError::from(KeyError::Unterminated))? + 1;
+        let parent = &key[..sub_start];
+        let last = &key[sub_start..sub_end];
+        // Only this database's encoding of "" is empty; with GT.M collation the other one is a
+        // real subscript
+        let empty = empty_starts
+            && (last.is_empty() || last == encode_subscript(b"", self.std_null_coll()).as_slice());
+        let mut start = Vec::from(parent);
+        if empty && reverse {
+            // Past every child of the parent
+            start.pop();
+            start.push(1);
+        } else if !empty && reverse {
+            start.extend(last);
+            start.push(0);
+        } else if !empty {
+            // Past this sibling and everything under it
+            start.extend(last);
+            start.push(1);
+        }
+        let nodes = self.nodes_from(&key[..name_end], &start, reverse)?;
+        for node in nodes {
+            let (k, _) = node?;
+            // The parent's own value sorts before its children
+            if k.len() == parent.len() + 1 && k.starts_with(parent) {
+                continue;
+            }
+            let child = child_key(parent, &k);
+            // An empty starting subscript is itself a sibling, but must not be returned
+            if empty && child.as_ref().map(|c| c.as_slice() == key).unwrap_or(false) {
+                continue;
+            }
+            return Ok(child);
+        }
+        Ok(None)
+    }
+
+    /// Finds the first child of the node `key`, whatever its subscript, and returns its key.
+    /// Unlike `order`, this includes a child with an empty subscript
+    pub fn first_child(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ValueError> {
+        let parent = &key[..key.len().saturating_sub(1)];
+        for node in self.nodes(parent)? {
+            let (k, _) = node?;
+            if k.as_slice() != key {
+                return Ok(child_key(parent, &k));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use self::create::CreateParams;
+    use self::key::format_key;
+    use test_util::{TempDb, key, sample_nodes, all_nodes};
+
+    fn set(db: &mut Database, reference: &str, value: &str) {
+        let key = db.parse_key(reference).unwrap();
+        db.set(&key, value.as_bytes()).unwrap();
+    }
+
+    /// Every sibling $ORDER visits starting from `reference`, formatted as references
+    fn walk(db: &Database, reference: &str, reverse: bool, order: bool) -> Vec<String> {
+        let mut ret = Vec::new();
+        let mut key = db.parse_key(reference).unwrap();
+        loop {
+            let next = match order {
+                true => db.order(&key, reverse),
+                false => db.next_sibling(&key, reverse),
+            };
+            match next.unwrap() {
+                Some(next) => key = next,
+                None => return ret,
+            }
+            ret.push(format_key(&key));
+        }
+    }
+
+    #[test]
+    fn nodes_run_both_ways() {
+        let mut db = TempDb::small();
+        let nodes = sample_nodes(2000);
+        for (key, value) in nodes.iter() {
+            db.set(key, value).unwrap();
+        }
+        assert_eq!(all_nodes(&db, b"x"), nodes);
+        let reversed: Vec<_> = db.nodes_rev(b"x\x01").unwrap().map(|n| n.unwrap()).collect();
+        assert_eq!(reversed, nodes.iter().rev().cloned().collect::<Vec<_>>());
+        // A partial key starts at the first node at or after it, or at or before it in reverse
+        let (first, _) = db.nodes(&key("^x(1000)")).unwrap().next().unwrap().unwrap();
+        assert_eq!(first, key("^x(1000,\"name 6\")"));
+        let (last, _) = db.nodes_rev(&key("^x(1000)")).unwrap().next().unwrap().unwrap();
+        assert_eq!(last, key("^x(999,\"name 5\")"));
+        assert!(db.nodes(b"nosuch\0").unwrap().next().is_none());
+    }
+
+    #[test]
+    fn order_steps_through_siblings() {
+        let mut db = TempDb::small();
+        set(&mut db, "^x(1)", "a");
+        set(&mut db, "^x(2,\"a\")", "b");
+        set(&mut db, "^x(10)", "c");
+        set(&mut db, "^x(\"s\")", "d");
+        set(&mut db, "^y", "e");
+        set(&mut db, "^a(1)", "f");
+        assert_eq!(walk(&db, "^x(\"\")", false, true),
+                   ["^x(1)", "^x(2)", "^x(10)", "^x(\"s\")"]);
+        assert_eq!(walk(&db, "^x(\"\")", true, true),
+                   ["^x(\"s\")", "^x(10)", "^x(2)", "^x(1)"]);
+        // Starting from a sibling that doesn't exist
+        assert_eq!(walk(&db, "^x(5)", false, true), ["^x(10)", "^x(\"s\")"]);
+        assert_eq!(walk(&db, "^x(2,\"\")", false, true), ["^x(2,\"a\")"]);
+        // Unsubscripted, the global names
+        assert_eq!(walk(&db, "^a", false, true), ["^x", "^y"]);
+        assert_eq!(walk(&db, "^y", true, true), ["^x", "^a"]);
+        assert_eq!(format_key(&db.first_child(&key("^x")).unwrap().unwrap()), "^x(1)");
+        assert!(db.order(b"x\0", false).is_err());
+    }
+
+    #[test]
+    fn empty_subscripts_collate_by_database() {
+        let mut db = TempDb::small();
+        for reference in ["^x(\"\")", "^x(1)", "^x(\"a\")"].iter() {
+            set(&mut db, reference, "v");
+        }
+        // Standard null collation puts "" first, where $ORDER starts anyway
+        assert_eq!(walk(&db, "^x(\"\")", false, true), ["^x(1)", "^x(\"a\")"]);
+        assert_eq!(walk(&db, "^x(\"\")", false, false), ["^x(1)", "^x(\"a\")"]);
+        assert_eq!(format_key(&db.first_child(&key("^x")).unwrap().unwrap()), "^x(\"\")");
+
+        let mut db = TempDb::new(&CreateParams {
+            blk_size: 1024,
+            allocation: 100,
+            std_null_coll: false,
+            ..CreateParams::default()
+        });
+        for reference in ["^x(\"\")", "^x(1)", "^x(\"a\")"].iter() {
+            set(&mut db, reference, "v");
+        }
+        // GT.M null collation puts "" between numbers and strings; $ORDER still starts from it,
+        // but stepping sibling by sibling visits it
+        let first = db.order(&db.parse_key("^x(\"\")").unwrap(), false).unwrap().unwrap();
+        assert_eq!(format_key(&first), "^x(1)");
+        assert_eq!(walk(&db, "^x(1)", false, false), ["^x(\"\")", "^x(\"a\")"]);
+        assert_eq!(walk(&db, "^x(\"a\")", true, false), ["^x(\"\")", "^x(1)"]);
+        assert_eq!(format_key(&db.first_child(&db.parse_key("^x").unwrap()).unwrap().unwrap()),
+                   "^x(1)");
+    }
+
+    #[test]
+    fn reports_progress_and_stops_when_cancelled() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(2000) {
+            db.set(&key, &value).unwrap();
+        }
+        let (tx, rx) = channel();
+        let mut nodes = db.nodes(b"x\0").unwrap().watch(Some(tx), CancelToken::new(), 5);
+        assert_eq!(nodes.by_ref().count(), 2000);
+        let sent: Vec<Progress> = rx.try_iter().collect();
+        // The first path down was read before progress was being watched
+        assert!(sent.len() > 10);
+        assert_eq!(sent.last().unwrap().blocks_processed, nodes.blocks_read());
+        assert!(sent.windows(2).all(|p| p[1].blocks_processed == p[0].blocks_processed + 1));
+        assert!(sent.iter().all(|p| p.total_blocks == db.total_blocks()));
+
+        let cancel = CancelToken::new();
+        let mut nodes = db.nodes(b"x\0").unwrap().watch(None, cancel.clone(), 0);
+        assert!(nodes.next().unwrap().is_ok());
+        cancel.cancel();
+        // The block already read is finished, then the iteration stops
+        let rest: Vec<_> = nodes.by_ref().collect();
+        assert!(rest.len() < 2000);
+        assert!(matches!(rest.last(), Some(Err(ValueError::Cancelled))));
+        assert!(rest[..rest.len() - 1].iter().all(|n| n.is_ok()));
+        assert!(nodes.next().is_none());
+    }
+}
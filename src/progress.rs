@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How far a long running scan has gotten, sent after each block is processed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub blocks_processed: usize,
+    /// Total blocks in the database according to the file header. Scans restricted to part of
+    /// the database will finish before reaching this
+    pub total_blocks: usize,
+}
+
+/// Shared flag used to stop a long running scan early. Clones share the same flag, so one can
+/// be handed to the scan and another kept by whoever wants to stop it
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Asks every scan holding this token to stop as soon as possible
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -8,14 +8,7 @@ pub struct RawRec<'a> {
     pub(crate) offset: usize,
 }
 
-/// Represents an exapnded record with a key and a "data" section
-#[derive(Debug, Clone)]
-pub struct Rec<'a> {
-    pub(crate) key: &'a [u8],
-    pub(crate) data: &'a [u8],
-}
-
-named_args!(pub(crate) record_header<'a>(offset: usize)<RawRec>,
+named_args!(pub(crate) record_header(offset: usize)<RawRec<'_>>,
        do_parse!(
            rsiz: le_u16 >>
            cmpc: le_u8  >>
@@ -26,34 +19,28 @@ named_args!(pub(crate) record_header<'a>(offset: usize)<RawRec>,
 );
 
 impl<'a> RawRec<'a> {
+    pub fn header(&self) -> &rec_hdr {
+        &self.header
+    }
+
+    /// Offset of this record from the start of its block, header included
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Everything after the record header: the compressed key, then the value or pointer
+    pub fn raw(&self) -> &[u8] {
+        self.data
+    }
+
     pub fn ptr(&self) -> Result<BlkNum, ValueError> {
-        let ret;
-        let data = self.data;
-        if self.header.rsiz == 8 {
-            // This is a * record; transmute the data to be a little endian int
-            let data = &self.data[self.data.len() - 4..self.data.len()];
-            let block = unsafe {
-                mem::transmute::<[u8; 4], u32>([data[0],
-                                                       data[1],
-                                                       data[2],
-                                                       data[3],
-            ])};
-            ret = BlkNum::Block(block as usize);
-        } else {
-            // Fetch the tail end of the pointer, and transmute it
-            let data = &self.data();
-            if data.len() < 4 {
-                return Err(ValueError::MalformedRecord);
-            }
-            let block = unsafe {
-                mem::transmute::<[u8; 4], u32>([data[0],
-                                                       data[1],
-                                                       data[2],
-                                                       data[3],
-            ])};
-            ret = BlkNum::Block(block as usize);
+        // A * record holds only the pointer; otherwise it follows the key
+        let data = if self.header.rsiz == 8 { self.data } else { self.data() };
+        if data.len() < 4 {
+            return Err(ValueError::MalformedRecord);
         }
-        Ok(ret)
+        let block = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        Ok(BlkNum::Block(block as usize))
     }
 
     pub fn data(&self) -> &[u8] {
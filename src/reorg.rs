@@ -0,0 +1,242 @@
+//! Compaction of sparsely filled global trees, like MUPIP REORG.
+//!
+//! Each index block is visited after its children. Runs of adjacent children whose records fit
+//! together within the fill factor are merged into the first block of the run, and the others
+//! are freed. Merging only happens between children of the same parent, but as index blocks are
+//! merged their children become neighbours, so passes repeat until one frees nothing. Finally,
+//! a root left with a single child takes over that child's records, removing a level.
+
+use super::*;
+
+use self::extract::matches_pattern;
+use self::update::{Entry, block_size, build_block, read_entries};
+
+/// What `Database::reorg` did to one global
+#[derive(Debug, Clone, Default)]
+pub struct ReorgStats {
+    pub name: Vec<u8>,
+    /// Blocks in the tree before and after, data and index
+    pub blocks_before: usize,
+    pub blocks_after: usize,
+    pub levels_removed: usize,
+}
+
+impl ReorgStats {
+    /// Blocks returned to the bitmaps
+    pub fn blocks_freed(&self) -> usize {
+        self.blocks_before - self.blocks_after
+    }
+}
+
+/// Adjacent children of an index block being merged into the first of them
+struct Run {
+    blk_num: usize,
+    records: Vec<Entry>,
+    /// The parent's key for the last child in the run
+    key: Option<Vec<u8>>,
+    /// Whether anything has been merged into the first block
+    merged: bool,
+}
+
+impl Database {
+    /// Reads a block of a global tree and expands its records
+    fn read_tree_block(&self, blk_num: usize) -> Result<(u8, Vec<Entry>), ValueError> {
+        let raw = self.get_block(blk_num)?;
+        let blk = get_block(&raw, blk_num, BlkType::IndexBlock)?;
+        Ok((blk.header().levl, read_entries(&blk)?))
+    }
+
+    /// Number of blocks in the tree rooted at `root`
+    fn tree_blocks(&self, root: usize) -> Result<usize, ValueError> {
+        let mut count = 0;
+        self.walk_index(root, BlkType::IndexBlock, &mut |_, _| count += 1)?;
+        Ok(count)
+    }
+
+    /// Merges what it can below index block `blk_num`, children first. Returns the number of
+    /// blocks freed. The merged blocks and the parent are written before anything is freed, so
+    /// a failure part way never leaves the tree pointing at a free block
+    fn reorg_block(&mut self, blk_num: usize, tn: u64) -> Result<usize, ValueError> {
+        let (levl, entries) = self.read_tree_block(blk_num)?;
+        if levl == 0 {
+            return Ok(0);
+        }
+        let mut freed = 0;
+        let mut emptied = Vec::new();
+        if levl > 1 {
+            for entry in entries.iter() {
+                freed += self.reorg_block(entry.ptr()?, tn)?;
+            }
+        }
+        let fill = self.fill_capacity();
+        let blk_size = self.fhead.blk_size as usize;
+        let child_levl = levl - 1;
+        let mut run: Option<Run> = None;
+        let mut parent = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let child = entry.ptr()?;
+            let (_, mut records) = self.read_tree_block(child)?;
+            // The * record of an index block covers keys up to the parent's key for the block;
+            // once it is followed by other records it needs that key
+            if child_levl > 0 {
+                if let Some(last) = records.last_mut() {
+                    last.key = entry.key.clone();
+                }
+            }
+            if let Some(run) = run.as_mut() {
+                let mut combined = run.records.clone();
+                combined.extend(records.iter().cloned());
+                if block_size(&combined) <= fill {
+                    run.records = combined;
+                    run.key = entry.key.clone();
+                    run.merged = true;
+                    emptied.push(child);
+                    continue;
+                }
+            }
+            if let Some(run) = run.take() {
+                parent.push(self.close_run(run, child_levl, tn)?);
+            }
+            run = Some(Run { blk_num: child, records, key: entry.key.clone(), merged: false });
+        }
+        if let Some(run) = run.take() {
+            parent.push(self.close_run(run, child_levl, tn)?);
+        }
+        if !emptied.is_empty() {
+            if let Some(last) = parent.last_mut() {
+                last.key = None;
+            }
+            self.write_block(blk_num, &build_block(levl, tn, &parent, blk_size))?;
+        }
+        for child in emptied {
+            self.free_block(child)?;
+            freed += 1;
+        }
+        Ok(freed)
+    }
+
+    /// Writes out a run of merged blocks, if anything was merged into it, and returns the
+    /// parent's record for it
+    fn close_run(&mut self, mut run: Run, levl: u8, tn: u64) -> Result<Entry, ValueError> {
+        if run.merged {
+            if levl > 0 {
+                if let Some(last) = run.records.last_mut() {
+                    last.key = None;
+                }
+            }
+            let raw = build_block(levl, tn, &run.records, self.fhead.blk_size as usize);
+            self.write_block(run.blk_num, &raw)?;
+        }
+        Ok(Entry::pointer(run.key, run.blk_num))
+    }
+
+    /// While the root has a single child and is above level 1, moves the child's records into
+    /// the root and frees the child. Returns the number of levels removed
+    fn collapse_root(&mut self, root: usize, tn: u64) -> Result<usize, ValueError> {
+        let mut removed = 0;
+        loop {
+            let (levl, entries) = self.read_tree_block(root)?;
+            if levl < 2 || entries.len() != 1 {
+                return Ok(removed);
+            }
+            let child = entries[0].ptr()?;
+            let (child_levl, records) = self.read_tree_block(child)?;
+            let raw = build_block(child_levl, tn, &records, self.fhead.blk_size as usize);
+            self.write_block(root, &raw)?;
+            self.free_block(child)?;
+            removed += 1;
+        }
+    }
+
+    /// Merges adjacent blocks of the globals matching `select` (or of every global, if it is
+    /// None) wherever their records fit together within the fill factor set with
+    /// `set_fill_factor`, and frees the blocks emptied. Blocks already fuller than that are
+    /// left alone; nothing is split. Returns what was done to each global
+    pub fn reorg(&mut self, select: Option<&[Vec<u8>]>) -> Result<Vec<ReorgStats>, ValueError> {
+        let globals: Vec<(Vec<u8>, usize)> = self.globals()?.into_iter()
+            .filter(|(name, _)| select.map(|s| s.iter().any(|p| matches_pattern(p, name)))
+                    .unwrap_or(true))
+            .collect();
+        let mut ret = Vec::with_capacity(globals.len());
+        for (name, root) in globals {
+            let tn = self.current_tn();
+            let mut stats = ReorgStats { name, ..ReorgStats::default() };
+            stats.blocks_before = self.tree_blocks(root)?;
+            while self.reorg_block(root, tn)? > 0 {}
+            stats.levels_removed = self.collapse_root(root, tn)?;
+            stats.blocks_after = self.tree_blocks(root)?;
+            if stats.blocks_freed() > 0 {
+                self.commit()?;
+            }
+            ret.push(stats);
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::update::MIN_FILL_FACTOR;
+    use test_util::{TempDb, key, sample_nodes, all_nodes, assert_integ_clean};
+
+    /// A global set at the lowest fill factor, then with nine nodes in ten killed
+    fn sparse() -> TempDb {
+        let mut db = TempDb::small();
+        db.set_fill_factor(MIN_FILL_FACTOR);
+        for (key, value) in sample_nodes(4000) {
+            db.set(&key, &value).unwrap();
+        }
+        db.set_fill_factor(100);
+        for i in (0..4000).filter(|i| i % 10 != 0) {
+            db.kill(&key(&format!("^x({})", i))).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn keeps_every_node() {
+        let mut db = sparse();
+        let before = all_nodes(&db, b"x");
+        assert_eq!(before.len(), 400);
+        let free = db.fhead.trans_hist.free_blocks as usize;
+        let stats = db.reorg(None).unwrap();
+        assert_eq!(stats.len(), 1);
+        let stats = &stats[0];
+        assert!(stats.blocks_freed() > stats.blocks_before / 2, "{:?}", stats);
+        assert_eq!(db.fhead.trans_hist.free_blocks as usize, free + stats.blocks_freed());
+        assert_eq!(db.global_stats(b"x").unwrap().blocks(), stats.blocks_after);
+        assert_eq!(all_nodes(&db, b"x"), before);
+        assert_integ_clean(&db);
+        // A second pass finds nothing left to merge
+        assert_eq!(db.reorg(None).unwrap()[0].blocks_freed(), 0);
+    }
+
+    #[test]
+    fn removes_levels_left_with_one_child() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(3000) {
+            db.set(&key, &value).unwrap();
+        }
+        let levels = db.global_stats(b"x").unwrap().levels.len();
+        assert!(levels > 2);
+        db.kill(&key("^x")).unwrap();
+        db.set(&key("^x(1)"), b"one").unwrap();
+        let stats = db.reorg(None).unwrap().remove(0);
+        assert_eq!(stats.levels_removed, levels - 2);
+        assert_eq!(stats.blocks_after, 2);
+        assert_eq!(all_nodes(&db, b"x"), vec![(key("^x(1)"), b"one".to_vec())]);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn only_touches_the_globals_selected() {
+        let mut db = sparse();
+        db.set(&key("^y(1)"), b"one").unwrap();
+        let stats = db.reorg(Some(&[b"y".to_vec()])).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].name, b"y");
+        assert_eq!(stats[0].blocks_freed(), 0);
+        assert_integ_clean(&db);
+    }
+}
@@ -0,0 +1,269 @@
+use super::*;
+
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use serde::{Serialize, Deserialize};
+
+/// A single change made to the database file by `Database::repair`. Writing `before` back at
+/// `offset` undoes it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepairChange {
+    pub description: String,
+    pub offset: u64,
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+}
+
+/// Appends `change` to a repair log and makes sure it is on disk
+fn append_repair_log(log: &mut File, change: &RepairChange) -> std::io::Result<()> {
+    let raw = bincode::serialize(change).map_err(std::io::Error::other)?;
+    log.write_all(&raw)?;
+    log.sync_data()
+}
+
+/// Writes `changes` to a new repair log at `path`, in the format `repair` writes
+pub fn save_repair_log(changes: &[RepairChange], path: &str) -> std::io::Result<()> {
+    let mut log = File::create(path)?;
+    for change in changes {
+        append_repair_log(&mut log, change)?;
+    }
+    Ok(())
+}
+
+/// Reads the changes in a repair log, in the order they were made
+pub fn load_repair_log(path: &str) -> std::io::Result<Vec<RepairChange>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut ret = Vec::new();
+    while !input.fill_buf()?.is_empty() {
+        ret.push(bincode::deserialize_from(&mut input)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?);
+    }
+    Ok(ret)
+}
+
+impl Database {
+    /// Logs a change, then makes it, so a repair that fails part way can still be undone
+    fn apply_change(&mut self, log: &mut File, changes: &mut Vec<RepairChange>,
+                    description: String, offset: u64, before: Vec<u8>, after: Vec<u8>)
+            -> std::io::Result<()> {
+        let change = RepairChange { description, offset, before, after };
+        append_repair_log(log, &change)?;
+        self.write_at(offset, &change.after)?;
+        changes.push(change);
+        Ok(())
+    }
+
+    /// Fixes the bitmap problems found by a full integ: busy blocks nothing points to are marked
+    /// free, free blocks the tree points to are marked busy, then the master bitmap is rebuilt
+    /// from the local maps and the file header's free block count is recomputed. Nothing is
+    /// changed if the report has any other kind of problem, since the tree walk can't then be
+    /// trusted to have found every block in use. Each change is appended to a new log at
+    /// `log_path` before it is made, for `undo_repair`
+    pub fn repair(&mut self, report: &IntegReport, log_path: &str)
+            -> Result<Vec<RepairChange>, ValueError> {
+        if report.scope != IntegScope::All || report.cancelled {
+            return Err(ValueError::RepairNotSafe);
+        }
+        // Sort the fixes by local map, so each map is only written once
+        let bplmap = self.blocks_per_map();
+        let mut fixes: BTreeMap<usize, Vec<(usize, LocalBitmapStatus)>> = BTreeMap::new();
+        for finding in report.findings.iter() {
+            let status = match finding.error {
+                ValueError::BlockIncorrectlyMarkedBusy => LocalBitmapStatus::Free,
+                ValueError::BlockIncorrectlyMarkedFree => LocalBitmapStatus::Busy,
+                // Fixed by rebuilding the master bitmap below
+                ValueError::BitmapError(BitmapError::MasterMapIncorrectlyMarkedFull) => continue,
+                ValueError::BitmapError(BitmapError::MasterMapIncorrectlyMarkedFree) => continue,
+                // Doesn't affect which blocks are in use
+                ValueError::BlockError(BlockError::TransactionNumberTooLarge(_)) => continue,
+                _ => return Err(ValueError::RepairNotSafe),
+            };
+            // A pointer past the end of the database is not a bitmap problem
+            if finding.blk_num >= self.total_blocks() || finding.blk_num % bplmap == 0 {
+                return Err(ValueError::RepairNotSafe);
+            }
+            fixes.entry(finding.blk_num / bplmap).or_default()
+                .push((finding.blk_num % bplmap, status));
+        }
+
+        let mut log = File::create(log_path)?;
+        let mut changes = Vec::new();
+        for (map_num, map_fixes) in fixes {
+            let blk_num = map_num * bplmap;
+            let before = self.get_block(blk_num)?;
+            let mut after = before.clone();
+            let mut description = format!("Local bitmap {}:", blk_num);
+            for (index, status) in map_fixes {
+                set_local_status(&mut after, index, &status);
+                description.push_str(&format!(" block {} marked {:?};", blk_num + index, status));
+            }
+            let offset = self.block_offset(blk_num);
+            self.apply_change(&mut log, &mut changes, description, offset, before, after)?;
+        }
+
+        // Rebuild the master bitmap and count the free blocks from the (now fixed) local maps
+        let before = self.master_bitmap.to_vec();
+        let mut free_blocks = 0;
+        for map_num in 0..self.local_bitmap_count() {
+            let map = self.local_bitmap(map_num)?;
+            let free = map.iter().skip(1).filter(|s| s.is_free()).count();
+            free_blocks += free;
+            self.set_master_map_free(map_num, free > 0);
+        }
+        let first = before.iter().zip(self.master_bitmap.iter()).position(|(a, b)| a != b);
+        let last = before.iter().zip(self.master_bitmap.iter()).rposition(|(a, b)| a != b);
+        if let (Some(first), Some(last)) = (first, last) {
+            let after = self.master_bitmap[first..last + 1].to_vec();
+            let offset = (mem::size_of::<sgmnt_data_struct>() + first) as u64;
+            self.apply_change(&mut log, &mut changes, String::from("Master bitmap rebuilt"),
+                              offset, before[first..last + 1].to_vec(), after)?;
+        }
+        // Written as a logged change above, so there is nothing left for `write_header`
+        self.master_map_dirty = None;
+
+        if self.fhead.trans_hist.free_blocks as usize != free_blocks {
+            let description = format!("File header free blocks changed from {} to {}",
+                                      self.fhead.trans_hist.free_blocks, free_blocks);
+            let before = self.header_bytes();
+            self.fhead.trans_hist.free_blocks = free_blocks as _;
+            let after = self.header_bytes();
+            self.apply_change(&mut log, &mut changes, description, 0, before, after)?;
+        }
+        Ok(changes)
+    }
+
+    /// Undoes changes made by `repair`, most recent first
+    pub fn undo_repair(&mut self, changes: &[RepairChange]) -> std::io::Result<()> {
+        for change in changes.iter().rev() {
+            self.write_at(change.offset, &change.before)?;
+        }
+        self.reload_header()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, TempPath, sample_nodes, assert_integ_clean};
+
+    fn file_bytes(db: &TempDb) -> Vec<u8> {
+        std::fs::read(&db.path.0).unwrap()
+    }
+
+    /// Marks `blk_num` in its local bitmap without touching the master bitmap or free count
+    fn mark(db: &mut Database, blk_num: usize, status: LocalBitmapStatus) {
+        let map = blk_num - blk_num % db.blocks_per_map();
+        let mut raw = db.get_block(map).unwrap();
+        set_local_status(&mut raw, blk_num % db.blocks_per_map(), &status);
+        db.write_block(map, &raw).unwrap();
+    }
+
+    fn finding(blk_num: usize, error: ValueError) -> IntegFinding {
+        IntegFinding { blk_num, error }
+    }
+
+    fn errors(report: &IntegReport) -> Vec<(usize, String)> {
+        report.findings.iter().map(|f| (f.blk_num, format!("{:?}", f.error))).collect()
+    }
+
+    #[test]
+    fn fixes_bitmaps_and_undoes() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(100) {
+            db.set(&key, &value).unwrap();
+        }
+        let free = db.fhead.trans_hist.free_blocks;
+        let root = db.find_global_root(b"x").unwrap();
+        mark(&mut db, 1500, LocalBitmapStatus::Busy);
+        mark(&mut db, root, LocalBitmapStatus::Free);
+        db.set_master_map_free(3, false);
+        db.fhead.trans_hist.free_blocks += 7;
+        db.write_header().unwrap();
+
+        let report = db.integ(&IntegOptions::default()).unwrap();
+        let mut found = errors(&report);
+        found.sort();
+        let mut expected = vec![
+            (root, format!("{:?}", ValueError::BlockIncorrectlyMarkedFree)),
+            (1500, format!("{:?}", ValueError::BlockIncorrectlyMarkedBusy)),
+            (1536, format!("{:?}",
+                           ValueError::from(BitmapError::MasterMapIncorrectlyMarkedFull))),
+        ];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let damaged = file_bytes(&db);
+        let log = TempPath::new("log");
+        let changes = db.repair(&report, &log.0).unwrap();
+        // Two local maps, the master bitmap and the header
+        assert_eq!(changes.len(), 4);
+        assert_eq!(db.fhead.trans_hist.free_blocks, free);
+        assert_integ_clean(&db);
+        let reopened = Database::open(&db.path.0).unwrap();
+        assert_eq!(reopened.fhead.trans_hist.free_blocks, free);
+        assert!(reopened.master_map_free(3));
+
+        let logged = load_repair_log(&log.0).unwrap();
+        assert_eq!(logged.len(), changes.len());
+        db.undo_repair(&logged).unwrap();
+        assert_eq!(file_bytes(&db), damaged);
+        assert_eq!(db.fhead.trans_hist.free_blocks, free + 7);
+    }
+
+    #[test]
+    fn refuses_unsafe_reports() {
+        let mut db = TempDb::small();
+        let log = TempPath::new("log");
+        let unsafe_reports = vec![
+            IntegReport {
+                findings: vec![finding(3, ValueError::from(BlockError::SizeTooSmall(4)))],
+                ..IntegReport::default()
+            },
+            IntegReport {
+                findings: vec![finding(3, ValueError::BlockIncorrectlyMarkedBusy)],
+                scope: IntegScope::Blocks(0..10),
+                ..IntegReport::default()
+            },
+            IntegReport {
+                scope: IntegScope::Globals(vec![b"x".to_vec()]),
+                ..IntegReport::default()
+            },
+            IntegReport { cancelled: true, ..IntegReport::default() },
+            // Past the end of the database
+            IntegReport {
+                findings: vec![finding(5000, ValueError::BlockIncorrectlyMarkedFree)],
+                ..IntegReport::default()
+            },
+        ];
+        let before = file_bytes(&db);
+        for report in unsafe_reports {
+            let result = db.repair(&report, &log.0);
+            assert!(matches!(result, Err(ValueError::RepairNotSafe)), "{:?}", report);
+        }
+        assert_eq!(file_bytes(&db), before);
+        assert!(std::fs::metadata(&log.0).is_err());
+    }
+
+    #[test]
+    fn log_survives_a_failed_repair() {
+        let mut db = TempDb::small();
+        mark(&mut db, 100, LocalBitmapStatus::Busy);
+        // Cut the file off before the last local map, so repair fails after fixing the first
+        let end = db.block_offset(1536);
+        db.handle.set_len(end).unwrap();
+        let report = IntegReport {
+            findings: vec![finding(100, ValueError::BlockIncorrectlyMarkedBusy),
+                           finding(1600, ValueError::BlockIncorrectlyMarkedBusy)],
+            ..IntegReport::default()
+        };
+        let damaged = file_bytes(&db);
+        let log = TempPath::new("log");
+        assert!(db.repair(&report, &log.0).is_err());
+        assert_ne!(file_bytes(&db), damaged);
+        let logged = load_repair_log(&log.0).unwrap();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].offset, db.block_offset(0));
+        db.undo_repair(&logged).unwrap();
+        assert_eq!(file_bytes(&db), damaged);
+    }
+}
@@ -0,0 +1,170 @@
+use super::*;
+
+use self::extract::matches_pattern;
+
+/// Space used by one level of a global's tree
+#[derive(Debug, Clone, Default)]
+pub struct LevelStats {
+    pub blocks: usize,
+    pub records: usize,
+    /// Bytes in use, block headers included
+    pub used: usize,
+}
+
+/// How a global's tree uses its blocks, found by `Database::global_stats`
+#[derive(Debug, Clone, Default)]
+pub struct GlobalStats {
+    pub name: Vec<u8>,
+    pub root: usize,
+    /// Indexed by level, so data blocks come first
+    pub levels: Vec<LevelStats>,
+    /// Fill of the emptiest and fullest blocks, as percentages of the space usable in a block
+    pub min_fill: f64,
+    pub max_fill: f64,
+    /// Key bytes of data records left out by compression against the previous key
+    pub compressed_bytes: usize,
+    /// Key bytes of data records as stored: what follows the record header once the compressed
+    /// prefix is left out, terminators included
+    pub key_bytes: usize,
+    /// Value bytes of data records
+    pub value_bytes: usize,
+    /// Space usable in each block
+    capacity: usize,
+}
+
+impl GlobalStats {
+    pub fn blocks(&self) -> usize {
+        self.levels.iter().map(|l| l.blocks).sum()
+    }
+
+    /// Number of nodes, which is the number of data records
+    pub fn records(&self) -> usize {
+        self.levels.first().map(|l| l.records).unwrap_or(0)
+    }
+
+    /// Average fill of every block in the tree, as a percentage of the space usable in a block
+    pub fn avg_fill(&self) -> f64 {
+        let used: usize = self.levels.iter().map(|l| l.used).sum();
+        match self.blocks() {
+            0 => 0.0,
+            blocks => used as f64 * 100.0 / (blocks * self.capacity) as f64,
+        }
+    }
+
+    /// Percentage of the full keys of data records saved by compression
+    pub fn avg_compression(&self) -> f64 {
+        match self.compressed_bytes + self.key_bytes {
+            0 => 0.0,
+            total => self.compressed_bytes as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+impl Database {
+    /// Walks the tree of `name` and reports the blocks and records at each level, how full the
+    /// blocks are, and how much of the data is keys and how much values
+    pub fn global_stats(&self, name: &[u8]) -> Result<GlobalStats, ValueError> {
+        let root = self.find_global_root(name)?;
+        let mut blocks = Vec::new();
+        self.walk_index(root, BlkType::IndexBlock, &mut |blk_num, levl| {
+            blocks.push((blk_num, levl))
+        })?;
+        let capacity = self.block_capacity();
+        let mut ret = GlobalStats {
+            name: name.to_vec(),
+            root,
+            min_fill: if blocks.is_empty() { 0.0 } else { 100.0 },
+            capacity,
+            ..GlobalStats::default()
+        };
+        for (blk_num, levl) in blocks {
+            let raw = self.get_block(blk_num)?;
+            let blk = get_block(&raw, blk_num, BlkType::IndexBlock)?;
+            let used = blk.header().bsiz as usize;
+            let fill = used as f64 * 100.0 / capacity as f64;
+            ret.min_fill = ret.min_fill.min(fill);
+            ret.max_fill = ret.max_fill.max(fill);
+            let levl = levl as usize;
+            if ret.levels.len() <= levl {
+                ret.levels.resize(levl + 1, LevelStats::default());
+            }
+            let level = &mut ret.levels[levl];
+            level.blocks += 1;
+            level.used += used;
+            for record in RecordCursor::new(&blk) {
+                let record = record?;
+                level.records += 1;
+                if levl == 0 {
+                    let value = record.data().len();
+                    ret.compressed_bytes += record.header().cmpc as usize;
+                    ret.key_bytes += record.raw().len() - value;
+                    ret.value_bytes += value;
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Statistics for each global matching `select`, or for every global if it is None
+    pub fn all_global_stats(&self, select: Option<&[Vec<u8>]>)
+            -> Result<Vec<GlobalStats>, ValueError> {
+        self.globals()?.into_iter()
+            .filter(|(name, _)| select.map(|s| s.iter().any(|p| matches_pattern(p, name)))
+                    .unwrap_or(true))
+            .map(|(name, _)| self.global_stats(&name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key};
+
+    #[test]
+    fn counts_a_small_tree() {
+        let mut db = TempDb::small();
+        let nodes = [("^x(1)", "a"), ("^x(2)", "bb"), ("^x(\"name\")", "ccc")];
+        for (reference, value) in nodes.iter() {
+            db.set(&key(reference), value.as_bytes()).unwrap();
+        }
+        let stats = db.global_stats(b"x").unwrap();
+        // A root at level 1 holding a * record, over one data block
+        assert_eq!(stats.levels.len(), 2);
+        assert_eq!(stats.blocks(), 2);
+        assert_eq!(stats.records(), 3);
+        assert_eq!(stats.levels[1].records, 1);
+        let root_size = mem::size_of::<blk_hdr>() + 8;
+        assert_eq!(stats.levels[1].used, root_size);
+        // Each key after the first leaves out what it shares with the one before
+        let mut prev: Vec<u8> = Vec::new();
+        let (mut compressed, mut stored) = (0, 0);
+        for (reference, _) in nodes.iter() {
+            let full = key(reference);
+            let cmpc = full.iter().zip(prev.iter()).take_while(|(a, b)| a == b).count();
+            compressed += cmpc;
+            stored += full.len() - cmpc;
+            prev = full;
+        }
+        assert_eq!(stats.compressed_bytes, compressed);
+        assert_eq!(stats.key_bytes, stored);
+        assert_eq!(stats.value_bytes, 6);
+        let data_size = mem::size_of::<blk_hdr>() + 3 * mem::size_of::<rec_hdr>() + stored + 6;
+        assert_eq!(stats.levels[0].used, data_size);
+        let total = (compressed + stored) as f64;
+        assert_eq!(stats.avg_compression(), compressed as f64 * 100.0 / total);
+        assert_eq!(stats.min_fill, root_size as f64 * 100.0 / 1024.0);
+        assert_eq!(stats.max_fill, data_size as f64 * 100.0 / 1024.0);
+        assert_eq!(stats.avg_fill(), (root_size + data_size) as f64 * 100.0 / 2048.0);
+    }
+
+    #[test]
+    fn empty_stats_are_zero() {
+        let stats = GlobalStats::default();
+        assert_eq!((stats.blocks(), stats.records()), (0, 0));
+        assert_eq!((stats.min_fill, stats.max_fill), (0.0, 0.0));
+        assert_eq!((stats.avg_fill(), stats.avg_compression()), (0.0, 0.0));
+        let db = TempDb::small();
+        assert!(db.global_stats(b"x").is_err());
+    }
+}
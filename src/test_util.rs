@@ -0,0 +1,100 @@
+//! Helpers shared by the tests: scratch databases in the temporary directory, and checks that
+//! a database is consistent and holds the nodes expected
+
+use super::*;
+
+use std::ops::{Deref, DerefMut};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use self::create::CreateParams;
+use self::key::{encode_key, parse_reference};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A path in the temporary directory no other test is using, removed when dropped
+pub(crate) struct TempPath(pub String);
+
+impl TempPath {
+    pub fn new(suffix: &str) -> TempPath {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir()
+            .join(format!("ydb-ng-test-{}-{}.{}", process::id(), id, suffix));
+        let path = path.to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&path);
+        TempPath(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A database created for a test, removed when dropped
+pub(crate) struct TempDb {
+    database: Database,
+    pub path: TempPath,
+}
+
+impl TempDb {
+    pub fn new(params: &CreateParams) -> TempDb {
+        let path = TempPath::new("dat");
+        let database = Database::create(&path.0, params).unwrap();
+        TempDb { database, path }
+    }
+
+    /// A database with small blocks, so a few hundred nodes are enough to split them
+    pub fn small() -> TempDb {
+        TempDb::new(&CreateParams {
+            blk_size: 1024,
+            allocation: 2000,
+            ..CreateParams::default()
+        })
+    }
+}
+
+impl Deref for TempDb {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.database
+    }
+}
+
+impl DerefMut for TempDb {
+    fn deref_mut(&mut self) -> &mut Database {
+        &mut self.database
+    }
+}
+
+/// The internal key for a reference like ^x(1,"a"), with standard null collation
+pub(crate) fn key(reference: &str) -> Vec<u8> {
+    let (name, subs) = parse_reference(reference).unwrap();
+    encode_key(&name, &subs, true)
+}
+
+/// Nodes with keys and values varied enough to exercise compression and splits
+pub(crate) fn sample_nodes(count: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut nodes: Vec<_> = (0..count)
+        .map(|i| (key(&format!("^x({},\"name {}\")", i, i % 7)), format!("value {}", i * 31)))
+        .map(|(key, value)| (key, value.into_bytes()))
+        .collect();
+    nodes.sort();
+    nodes
+}
+
+/// Every node of the global `name`, in order
+pub(crate) fn all_nodes(database: &Database, name: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut start = name.to_vec();
+    start.push(0);
+    database.nodes(&start).unwrap().map(|node| node.unwrap()).collect()
+}
+
+/// Panics with the findings if an integrity check finds any problems
+pub(crate) fn assert_integ_clean(database: &Database) {
+    let report = database.integ(&IntegOptions::default()).unwrap();
+    assert!(report.findings.is_empty(), "{:?}", report.findings);
+    assert!(!report.cancelled);
+}
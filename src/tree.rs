@@ -0,0 +1,312 @@
+use super::*;
+
+use fnv::FnvHashSet;
+
+/// Deepest a directory or global tree can be, used to stop walking a corrupt tree that loops
+pub(crate) const MAX_BT_DEPTH: usize = 11;
+
+/// One block visited while searching for a key, and the record chosen in it
+#[derive(Debug, Clone)]
+pub struct PathStep {
+    pub blk_num: usize,
+    pub levl: u8,
+    /// `DirectoryTree` for steps through the directory tree, otherwise the global's tree
+    pub typ: BlkType,
+    /// Number of the chosen record, counting from 1 as DSE does, and its offset in the block.
+    /// None in a level 0 block that doesn't hold the key
+    pub record: Option<(usize, usize)>,
+    /// Expanded key of the chosen record; None for a * record
+    pub key: Option<Vec<u8>>,
+    /// The block the chosen record points to, for index records and directory tree leaves
+    pub ptr: Option<usize>,
+}
+
+impl Database {
+    /// Returns the value of the node `key` (in the internal format, terminators included), or
+    /// None if the node has no value
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ValueError> {
+        let name = &key[..key.iter().position(|c| *c == 0).unwrap_or(key.len())];
+        let root = match self.find_global_root(name) {
+            Ok(x) => x,
+            Err(ValueError::GlobalNotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let leaf = self.descend(root, BlkType::IndexBlock, key)?.pop();
+        Ok(leaf.and_then(|leaf| {
+            leaf.entries.into_iter().find(|e| e.key.as_deref() == Some(key)).map(|e| e.value)
+        }))
+    }
+
+    /// Lists every block visited looking up `key` (in the internal format, terminators
+    /// included), from the directory tree root down to the data block, like DSE FIND -KEY.
+    /// If the global or key doesn't exist, the last step is the level 0 block it would be in,
+    /// with no record chosen
+    pub fn find_path(&self, key: &[u8]) -> Result<Vec<PathStep>, ValueError> {
+        let name_end = key.iter().position(|c| *c == 0).unwrap_or(key.len());
+        let mut goal = Vec::from(&key[..name_end]);
+        goal.extend(&[0, 0]);
+        let mut typ = BlkType::DirectoryTree;
+        let mut blk_num = 1;
+        let mut expected = None;
+        let mut path = Vec::new();
+        while path.len() <= 2 * MAX_BT_DEPTH {
+            let raw = self.get_block(blk_num)?;
+            let blk = get_valid_block(&raw, blk_num, typ.clone(), expected,
+                                      self.fully_upgraded())?;
+            let levl = blk.header().levl;
+            let mut step = PathStep {
+                blk_num,
+                levl,
+                typ: blk.typ().clone(),
+                record: None,
+                key: None,
+                ptr: None,
+            };
+            let mut expanded = Vec::new();
+            for (i, record) in RecordCursor::new(&blk).enumerate() {
+                let record = record?;
+                // The * record has no key, and covers everything after the previous record
+                let is_star = levl > 0 && record.header().rsiz == 8;
+                if !is_star {
+                    RecordCursor::expand_key(&record, &mut expanded)?;
+                    match RecordCursor::compare_strings(&expanded, &goal) {
+                        SortOrder::SortsBefore => continue,
+                        SortOrder::SortsAfter if levl == 0 => break,
+                        _ => step.key = Some(expanded.clone()),
+                    }
+                }
+                step.record = Some((i + 1, record.offset()));
+                if levl > 0 || typ == BlkType::DirectoryTree {
+                    step.ptr = match record.ptr()? {
+                        BlkNum::Block(x) => Some(x),
+                        _ => return Err(ValueError::MalformedRecord),
+                    };
+                }
+                break;
+            }
+            let next = step.ptr;
+            let found = step.record.is_some();
+            path.push(step);
+            if levl > 0 {
+                blk_num = next.ok_or(ValueError::MalformedRecord)?;
+                expected = Some(levl - 1);
+            } else if typ == BlkType::DirectoryTree && found {
+                // Found the global; continue from the root of its tree
+                blk_num = next.ok_or(ValueError::MalformedRecord)?;
+                expected = None;
+                typ = BlkType::IndexBlock;
+                goal = key.to_vec();
+            } else {
+                return Ok(path);
+            }
+        }
+        Err(ValueError::MalformedRecord)
+    }
+
+    /// Lists every global in the directory tree with the block number of its root, in collation
+    /// order
+    pub fn globals(&self) -> Result<Vec<(Vec<u8>, usize)>, ValueError> {
+        let mut ret = Vec::new();
+        // Depth first, keeping the children in order so the globals come out sorted
+        let mut stack = vec![(1, None)];
+        let mut visited = FnvHashSet::default();
+        while let Some((blk_num, expected)) = stack.pop() {
+            if !visited.insert(blk_num) {
+                return Err(ValueError::TreeLoop(blk_num));
+            }
+            let raw = self.get_block(blk_num)?;
+            let blk = get_valid_block(&raw, blk_num, BlkType::DirectoryTree, expected,
+                                      self.fully_upgraded())?;
+            let levl = blk.header().levl;
+            let mut children = Vec::new();
+            let mut key = Vec::new();
+            for record in RecordCursor::new(&blk) {
+                let record = record?;
+                if levl > 0 {
+                    if let BlkNum::Block(x) = record.ptr()? {
+                        children.push((x, Some(levl - 1)));
+                    }
+                    continue;
+                }
+                RecordCursor::expand_key(&record, &mut key)?;
+                let name_end = key.iter().position(|c| *c == 0).unwrap_or(key.len());
+                if let BlkNum::Block(x) = record.ptr()? {
+                    ret.push((key[..name_end].to_vec(), x));
+                }
+            }
+            stack.extend(children.into_iter().rev());
+        }
+        Ok(ret)
+    }
+
+    /// Calls `visit(blk_num, levl)` for every block in the tree rooted at `root`, parents before
+    /// children and children in key order. Only index blocks are read; the data blocks they
+    /// point to are visited without being read. Each block must be at the level its parent
+    /// expects and be reached only once, so a corrupt tree is an error rather than a hang
+    pub fn walk_index<F>(&self, root: usize, typ: BlkType, visit: &mut F) -> Result<(), ValueError>
+            where F: FnMut(usize, u8) {
+        let mut stack = vec![(root, None)];
+        let mut visited = FnvHashSet::default();
+        while let Some((blk_num, expected)) = stack.pop() {
+            if !visited.insert(blk_num) {
+                return Err(ValueError::TreeLoop(blk_num));
+            }
+            let raw = self.get_block(blk_num)?;
+            let blk = get_valid_block(&raw, blk_num, typ.clone(), expected,
+                                      self.fully_upgraded())?;
+            let levl = blk.header().levl;
+            visit(blk_num, levl);
+            if levl == 0 {
+                continue;
+            }
+            let mut children = Vec::new();
+            for record in RecordCursor::new(&blk) {
+                if let BlkNum::Block(x) = record?.ptr()? {
+                    children.push(x);
+                }
+            }
+            if levl == 1 {
+                for child in children {
+                    if !visited.insert(child) {
+                        return Err(ValueError::TreeLoop(child));
+                    }
+                    visit(child, 0);
+                }
+            } else {
+                stack.extend(children.into_iter().rev().map(|child| (child, Some(levl - 1))));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::update::{Entry, build_block, read_entries};
+    use test_util::{TempDb, key, sample_nodes};
+
+    /// Rewrites index block `blk_num` with the pointer of record `index` changed to `ptr`
+    fn repoint(db: &mut Database, blk_num: usize, index: usize, ptr: usize) {
+        let raw = db.get_block(blk_num).unwrap();
+        let blk = get_block(&raw, blk_num, BlkType::IndexBlock).unwrap();
+        let levl = blk.header().levl;
+        let mut entries = read_entries(&blk).unwrap();
+        entries[index] = Entry::pointer(entries[index].key.clone(), ptr);
+        let tn = db.current_tn();
+        let raw = build_block(levl, tn, &entries, db.fhead.blk_size as usize);
+        db.write_block(blk_num, &raw).unwrap();
+    }
+
+    fn walk(db: &Database, root: usize) -> Result<Vec<(usize, u8)>, ValueError> {
+        let mut blocks = Vec::new();
+        db.walk_index(root, BlkType::IndexBlock, &mut |blk_num, levl| blocks.push((blk_num, levl)))
+            .map(|_| blocks)
+    }
+
+    #[test]
+    fn walks_stop_on_corrupt_trees() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(3000) {
+            db.set(&key, &value).unwrap();
+        }
+        let root = db.find_global_root(b"x").unwrap();
+        let blocks = walk(&db, root).unwrap();
+        assert_eq!(blocks[0], (root, blocks[0].1));
+        assert!(blocks[0].1 >= 2);
+        let index = blocks.iter().find(|(_, levl)| *levl == 1).unwrap().0;
+        let data: Vec<usize> = read_entries(&get_block(&db.get_block(index).unwrap(), index,
+                                                       BlkType::IndexBlock).unwrap())
+            .unwrap().iter().map(|e| e.ptr().unwrap()).collect();
+
+        // Two records pointing at the same data block
+        repoint(&mut db, index, 1, data[0]);
+        assert!(matches!(walk(&db, root), Err(ValueError::TreeLoop(x)) if x == data[0]));
+        repoint(&mut db, index, 1, data[1]);
+        assert_eq!(walk(&db, root).unwrap(), blocks);
+
+        // A record pointing straight at a data block, which is at the wrong level to be its child
+        repoint(&mut db, root, 0, data[0]);
+        assert!(matches!(walk(&db, root),
+                         Err(ValueError::BlockError(BlockError::IncorrectLevel { found: 0, .. }))));
+        // A record pointing back up at the root
+        repoint(&mut db, root, 0, root);
+        assert!(matches!(walk(&db, root), Err(ValueError::TreeLoop(x)) if x == root));
+    }
+
+    #[test]
+    fn globals_stop_on_a_directory_loop() {
+        let mut db = TempDb::small();
+        // Enough globals to give the directory tree an index level
+        for i in 0..300 {
+            db.set(&test_util::key(&format!("^g{}(1)", i)), b"1").unwrap();
+        }
+        let globals = db.globals().unwrap();
+        assert_eq!(globals.len(), 300);
+        let raw = db.get_block(1).unwrap();
+        let blk = get_block(&raw, 1, BlkType::DirectoryTree).unwrap();
+        assert!(blk.header().levl > 0);
+        let first = read_entries(&blk).unwrap()[0].ptr().unwrap();
+        repoint(&mut db, 1, 1, first);
+        assert!(matches!(db.globals(), Err(ValueError::TreeLoop(x)) if x == first));
+    }
+
+    #[test]
+    fn find_path_descends_to_the_data_block() {
+        let mut db = TempDb::small();
+        let nodes = sample_nodes(3000);
+        for (key, value) in nodes.iter() {
+            db.set(key, value).unwrap();
+        }
+        let (target, value) = &nodes[1234];
+        let path = db.find_path(target).unwrap();
+        let root = db.find_global_root(b"x").unwrap();
+        let levels = db.global_stats(b"x").unwrap().levels.len();
+        // Both directory tree blocks, then one block per level of the global's tree
+        assert_eq!(path.len(), 2 + levels);
+        assert_eq!((path[0].blk_num, path[0].levl, path[0].ptr), (1, 1, Some(2)));
+        assert_eq!(path[1].key.as_deref(), Some(&b"x\0\0"[..]));
+        assert_eq!(path[1].ptr, Some(root));
+        assert!(path[..2].iter().all(|step| step.typ == BlkType::DirectoryTree));
+        assert_eq!(path[2].blk_num, root);
+        for (i, step) in path.iter().enumerate().skip(2) {
+            assert_eq!(step.levl as usize, levels + 1 - i);
+            let typ = if step.levl > 0 { BlkType::IndexBlock } else { BlkType::DataBlock };
+            assert_eq!(step.typ, typ);
+            assert!(step.record.is_some());
+        }
+        // Each step points at the next
+        for pair in path.windows(2) {
+            assert_eq!(pair[0].ptr, Some(pair[1].blk_num));
+        }
+        let leaf = path.last().unwrap();
+        assert_eq!(leaf.ptr, None);
+        assert_eq!(leaf.key.as_ref(), Some(target));
+        // The chosen record is the node itself
+        let raw = db.get_block(leaf.blk_num).unwrap();
+        let blk = get_block(&raw, leaf.blk_num, BlkType::IndexBlock).unwrap();
+        let (number, offset) = leaf.record.unwrap();
+        let record = RecordCursor::new(&blk).nth(number - 1).unwrap().unwrap();
+        assert_eq!(record.offset(), offset);
+        assert_eq!(record.data(), &value[..]);
+    }
+
+    #[test]
+    fn find_path_ends_where_a_missing_key_would_be() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(3000) {
+            db.set(&key, &value).unwrap();
+        }
+        let found = db.find_path(&key("^x(1500,\"name 2\")")).unwrap();
+        let missing = db.find_path(&key("^x(1500,\"name 3\")")).unwrap();
+        assert_eq!(missing.len(), found.len());
+        assert_eq!(missing.last().unwrap().blk_num, found.last().unwrap().blk_num);
+        assert_eq!(missing.last().unwrap().record, None);
+        assert_eq!(missing.last().unwrap().key, None);
+        // A missing global stops in the directory tree
+        let path = db.find_path(&key("^nosuch(1)")).unwrap();
+        assert_eq!(path.len(), 2);
+        assert!(path.iter().all(|step| step.typ == BlkType::DirectoryTree));
+        assert_eq!(path[1].record, None);
+    }
+}
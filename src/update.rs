@@ -0,0 +1,543 @@
+use super::*;
+
+use self::tree::MAX_BT_DEPTH;
+
+/// The lowest fill factor accepted, as in YottaDB
+pub const MIN_FILL_FACTOR: usize = 30;
+
+/// A record as it is held while a block is rebuilt: the full key, or None for a * record, and
+/// what follows the key, which is either the value or a 4 byte block pointer
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub key: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+}
+
+impl Entry {
+    /// An index record pointing at `blk_num`
+    pub fn pointer(key: Option<Vec<u8>>, blk_num: usize) -> Entry {
+        Entry { key, value: (blk_num as u32).to_le_bytes().to_vec() }
+    }
+
+    /// The block an index record points at
+    pub fn ptr(&self) -> Result<usize, ValueError> {
+        if self.value.len() < 4 {
+            return Err(ValueError::MalformedRecord);
+        }
+        let data = &self.value[self.value.len() - 4..];
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize)
+    }
+}
+
+/// One block on the way down to a level 0 block, with its records and the one that was followed
+pub(crate) struct Level {
+    pub blk_num: usize,
+    pub levl: u8,
+    pub entries: Vec<Entry>,
+    pub index: usize,
+}
+
+/// Number of leading bytes `key` can share with the previous key; cmpc is a single byte
+fn compression(prev: &[u8], key: &[u8]) -> usize {
+    let shared = prev.iter().zip(key).take_while(|(a, b)| a == b).count();
+    std::cmp::min(shared, u8::MAX as usize)
+}
+
+/// Size of the record `entry` becomes when it follows a record with key `prev`
+pub(crate) fn record_size(prev: &[u8], entry: &Entry) -> usize {
+    let hdr_size = mem::size_of::<rec_hdr>();
+    match &entry.key {
+        Some(key) => hdr_size + key.len() - compression(prev, key) + entry.value.len(),
+        None => hdr_size + entry.value.len(),
+    }
+}
+
+/// Size of a block holding `entries`, header included
+pub(crate) fn block_size(entries: &[Entry]) -> usize {
+    let mut size = mem::size_of::<blk_hdr>();
+    let mut prev: &[u8] = &[];
+    for entry in entries {
+        size += record_size(prev, entry);
+        if let Some(key) = &entry.key {
+            prev = key;
+        }
+    }
+    size
+}
+
+/// Builds a raw block of `blk_size` bytes holding `entries`, compressing each key against the
+/// one before it
+pub(crate) fn build_block(levl: u8, tn: u64, entries: &[Entry], blk_size: usize) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(blk_size);
+    raw.extend(&block::GDSV6.to_le_bytes());
+    raw.push(0);
+    raw.push(levl);
+    // bsiz is filled in once the records are in place
+    raw.extend(&[0; 4]);
+    raw.extend(&tn.to_le_bytes());
+    let mut prev: &[u8] = &[];
+    for entry in entries {
+        let rsiz = record_size(prev, entry) as u16;
+        raw.extend(&rsiz.to_le_bytes());
+        match &entry.key {
+            Some(key) => {
+                let cmpc = compression(prev, key);
+                raw.push(cmpc as u8);
+                raw.push(0);
+                raw.extend(&key[cmpc..]);
+                prev = key;
+            },
+            None => raw.extend(&[0, 0]),
+        }
+        raw.extend(&entry.value);
+    }
+    let bsiz = raw.len() as u32;
+    raw[4..8].copy_from_slice(&bsiz.to_le_bytes());
+    raw.resize(blk_size, 0);
+    raw
+}
+
+/// Expands every record in a block
+pub(crate) fn read_entries(blk: &Blk) -> Result<Vec<Entry>, ValueError> {
+    let levl = blk.header().levl;
+    let mut key = Vec::new();
+    let mut ret = Vec::new();
+    for record in RecordCursor::new(blk) {
+        let record = record?;
+        if levl > 0 && record.header().rsiz == 8 {
+            ret.push(Entry { key: None, value: record.raw().to_vec() });
+            continue;
+        }
+        RecordCursor::expand_key(&record, &mut key)?;
+        ret.push(Entry { key: Some(key.clone()), value: record.data().to_vec() });
+    }
+    Ok(ret)
+}
+
+/// Splits `entries` into runs which each fit in `capacity` bytes. Two way splits are balanced,
+/// unless `append` is set, in which case the last entry is moved out on its own so blocks
+/// filled in key order stay full
+pub(crate) fn split_entries(entries: Vec<Entry>, capacity: usize, append: bool)
+        -> Result<Vec<Vec<Entry>>, ValueError> {
+    let hdr_size = mem::size_of::<blk_hdr>();
+    if block_size(&entries) <= capacity {
+        return Ok(vec![entries]);
+    }
+    // Sizes of each record compressed against the one before it, and on its own
+    let mut compressed = Vec::with_capacity(entries.len());
+    let mut alone = Vec::with_capacity(entries.len());
+    let mut prev: &[u8] = &[];
+    for entry in entries.iter() {
+        compressed.push(record_size(prev, entry));
+        alone.push(record_size(&[], entry));
+        if let Some(key) = &entry.key {
+            prev = key;
+        }
+    }
+    if alone.iter().any(|size| hdr_size + size > capacity) {
+        return Err(ValueError::RecordTooLarge);
+    }
+    // Block sizes if the entries are split before entry i
+    let mut before = Vec::with_capacity(compressed.len() + 1);
+    before.push(0);
+    for size in compressed.iter() {
+        before.push(before.last().unwrap() + size);
+    }
+    let total = *before.last().unwrap();
+    let sizes = |i: usize| {
+        (hdr_size + before[i], hdr_size + alone[i] + total - before[i + 1])
+    };
+    let fits = |(left, right): (usize, usize)| left <= capacity && right <= capacity;
+    let n = entries.len();
+    let split = if append && n > 1 && fits(sizes(n - 1)) {
+        Some(n - 1)
+    } else {
+        (1..n).filter(|i| fits(sizes(*i)))
+            .min_by_key(|i| {
+                let (left, right) = sizes(*i);
+                left.abs_diff(right)
+            })
+    };
+    if let Some(i) = split {
+        let mut left = entries;
+        let right = left.split_off(i);
+        return Ok(vec![left, right]);
+    }
+    // Too much for two blocks; fill each one in turn
+    let mut ret = Vec::new();
+    let mut current: Vec<Entry> = Vec::new();
+    let mut size = hdr_size;
+    for entry in entries {
+        let prev = current.last().and_then(|e| e.key.as_ref()).map(|k| k.as_slice());
+        let entry_size = record_size(prev.unwrap_or(&[]), &entry);
+        if size + entry_size > capacity {
+            ret.push(mem::take(&mut current));
+            size = hdr_size + record_size(&[], &entry);
+        } else {
+            size += entry_size;
+        }
+        current.push(entry);
+    }
+    ret.push(current);
+    Ok(ret)
+}
+
+impl Database {
+    /// Space in each block records can use
+    pub(crate) fn block_capacity(&self) -> usize {
+        (self.fhead.blk_size - self.fhead.reserved_bytes) as usize
+    }
+
+    /// Sets how full, as a percentage of `block_capacity`, blocks are left when they have to
+    /// be split, like MUPIP LOAD -FILL_FACTOR. Lower values leave room for later updates
+    /// without further splits. Values are clamped to MIN_FILL_FACTOR..=100
+    pub fn set_fill_factor(&mut self, percent: usize) {
+        self.fill_factor = percent.clamp(MIN_FILL_FACTOR, 100);
+    }
+
+    /// Space records can use in blocks produced by a split
+    pub(crate) fn fill_capacity(&self) -> usize {
+        let hdr_size = mem::size_of::<blk_hdr>();
+        hdr_size + (self.block_capacity() - hdr_size) * self.fill_factor / 100
+    }
+
+    /// Splits `entries` if they don't fit in a block, leaving each run within the fill factor
+    /// where the records allow it
+    fn split_filled(&self, entries: Vec<Entry>, append: bool)
+            -> Result<Vec<Vec<Entry>>, ValueError> {
+        let capacity = self.block_capacity();
+        if block_size(&entries) <= capacity {
+            return Ok(vec![entries]);
+        }
+        let fill = self.fill_capacity();
+        if fill < capacity && entries.iter().all(|e| block_size(std::slice::from_ref(e)) <= fill) {
+            return split_entries(entries, fill, append);
+        }
+        split_entries(entries, capacity, append)
+    }
+
+    /// Reads the blocks from `root` down to the level 0 block `goal` belongs in, noting the
+    /// index record followed in each
+    pub(crate) fn descend(&self, root: usize, typ: BlkType, goal: &[u8])
+            -> Result<Vec<Level>, ValueError> {
+        let mut path = Vec::new();
+        let mut blk_num = root;
+        while path.len() <= MAX_BT_DEPTH {
+            let raw = self.get_block(blk_num)?;
+            let blk = get_block(&raw, blk_num, typ.clone())?;
+            let levl = blk.header().levl;
+            let entries = read_entries(&blk)?;
+            if levl == 0 {
+                path.push(Level { blk_num, levl, entries, index: 0 });
+                return Ok(path);
+            }
+            let index = entries.iter()
+                .position(|e| e.key.as_ref().map(|k| k.as_slice() >= goal).unwrap_or(true))
+                .ok_or(ValueError::MalformedRecord)?;
+            let next = entries[index].ptr()?;
+            path.push(Level { blk_num, levl, entries, index });
+            blk_num = next;
+        }
+        Err(ValueError::MalformedRecord)
+    }
+
+    /// Writes back a path from `descend` after its level 0 block has been changed. Blocks that
+    /// no longer fit are split into newly allocated blocks, and a level 0 block left empty is
+    /// freed unless it is the only child of its parent. The root keeps its block number, so
+    /// if it splits the tree grows a level
+    pub(crate) fn write_path(&mut self, mut path: Vec<Level>, tn: u64, append: bool)
+            -> Result<(), ValueError> {
+        let blk_size = self.fhead.blk_size as usize;
+        // Records to put in place of the parent's record for the block just written
+        let mut replacement: Option<Vec<Entry>> = None;
+        while let Some(mut level) = path.pop() {
+            if let Some(entries) = replacement.take() {
+                level.entries.splice(level.index..=level.index, entries);
+                // Whatever is last in an index block covers everything after it
+                if let Some(last) = level.entries.last_mut() {
+                    last.key = None;
+                }
+            }
+            let parent_entries = path.last().map(|p| p.entries.len()).unwrap_or(0);
+            if level.entries.is_empty() && parent_entries > 1 {
+                self.free_block(level.blk_num)?;
+                replacement = Some(Vec::new());
+                continue;
+            }
+            let mut chunks = self.split_filled(level.entries, append)?;
+            if chunks.len() == 1 {
+                let raw = build_block(level.levl, tn, &chunks[0], blk_size);
+                self.write_block(level.blk_num, &raw)?;
+                return Ok(());
+            }
+            let mut levl = level.levl;
+            if path.is_empty() {
+                // Move everything out of the root until what points at it fits
+                loop {
+                    let mut entries = Vec::with_capacity(chunks.len());
+                    let count = chunks.len();
+                    for (i, chunk) in chunks.into_iter().enumerate() {
+                        let blk_num = self.allocate_block()?;
+                        let (key, chunk) = close_chunk(chunk, levl, i + 1 == count);
+                        self.write_block(blk_num, &build_block(levl, tn, &chunk, blk_size))?;
+                        entries.push(Entry::pointer(key, blk_num));
+                    }
+                    levl += 1;
+                    chunks = self.split_filled(entries, false)?;
+                    if chunks.len() == 1 {
+                        let raw = build_block(levl, tn, &chunks[0], blk_size);
+                        self.write_block(level.blk_num, &raw)?;
+                        return Ok(());
+                    }
+                }
+            }
+            // The original block keeps the last run, so the parent's record for it still holds
+            let parent = path.last().unwrap();
+            let mut entries = Vec::with_capacity(chunks.len());
+            let last = chunks.pop().unwrap();
+            for chunk in chunks {
+                let blk_num = self.allocate_block()?;
+                let (key, chunk) = close_chunk(chunk, levl, false);
+                self.write_block(blk_num, &build_block(levl, tn, &chunk, blk_size))?;
+                entries.push(Entry::pointer(key, blk_num));
+            }
+            self.write_block(level.blk_num, &build_block(levl, tn, &last, blk_size))?;
+            entries.push(parent.entries[parent.index].clone());
+            replacement = Some(entries);
+        }
+        Ok(())
+    }
+
+    /// Creates an empty global: a level 1 root with a * record pointing to an empty data block,
+    /// and its record in the directory tree. Returns the root block
+    fn create_global(&mut self, name: &[u8], tn: u64) -> Result<usize, ValueError> {
+        let blk_size = self.fhead.blk_size as usize;
+        let data = self.allocate_block()?;
+        self.write_block(data, &build_block(0, tn, &[], blk_size))?;
+        let root = self.allocate_block()?;
+        let entries = [Entry::pointer(None, data)];
+        self.write_block(root, &build_block(1, tn, &entries, blk_size))?;
+        let mut key = Vec::from(name);
+        key.extend(&[0, 0]);
+        self.insert(1, BlkType::DirectoryTree, &key, &(root as u32).to_le_bytes(), tn)?;
+        Ok(root)
+    }
+
+    /// Adds or replaces the record for `key` in the tree rooted at `root`
+    pub(crate) fn insert(&mut self, root: usize, typ: BlkType, key: &[u8], value: &[u8], tn: u64)
+            -> Result<(), ValueError> {
+        let mut path = self.descend(root, typ, key)?;
+        let leaf = path.last_mut().unwrap();
+        let entry = Entry { key: Some(key.to_vec()), value: value.to_vec() };
+        let pos = leaf.entries.iter()
+            .position(|e| e.key.as_ref().map(|k| k.as_slice() >= key).unwrap_or(true))
+            .unwrap_or(leaf.entries.len());
+        if leaf.entries.get(pos).map(|e| e.key == entry.key).unwrap_or(false) {
+            leaf.entries[pos] = entry;
+        } else {
+            leaf.entries.insert(pos, entry);
+        }
+        let append = pos + 1 == leaf.entries.len();
+        self.write_path(path, tn, append)
+    }
+
+    /// Finishes an update: moves to the next transaction number and saves the file header
+    pub(crate) fn commit(&mut self) -> std::io::Result<()> {
+        self.fhead.trans_hist.curr_tn += 1;
+        self.fhead.trans_hist.early_tn = self.fhead.trans_hist.curr_tn;
+        self.write_header()
+    }
+
+    /// Sets the node `key` (in the internal format, terminators included) to `value`, creating
+    /// the global if needed
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), ValueError> {
+        self.set_uncommitted(key, value)?;
+        self.commit()?;
+        Ok(())
+    }
+
+    /// Sets a node like `set` but leaves the commit to the caller, so a batch of nodes can
+    /// share one transaction
+    pub(crate) fn set_uncommitted(&mut self, key: &[u8], value: &[u8])
+            -> Result<(), ValueError> {
+        if key.len() > self.max_key_size() {
+            return Err(ValueError::from(RecordError::KeyTooLong));
+        }
+        if value.len() > self.fhead.max_rec_size as usize {
+            return Err(ValueError::RecordTooLarge);
+        }
+        let tn = self.current_tn();
+        let name = &key[..key.iter().position(|c| *c == 0).unwrap_or(key.len())];
+        let root = match self.find_global_root(name) {
+            Ok(x) => x,
+            Err(ValueError::GlobalNotFound) => self.create_global(name, tn)?,
+            Err(e) => return Err(e),
+        };
+        self.insert(root, BlkType::IndexBlock, key, value, tn)?;
+        Ok(())
+    }
+
+    /// Removes the node `key` and everything under it, like KILL, returning how many nodes were
+    /// removed. The global's root stays in the directory tree, even if it ends up empty
+    pub fn kill(&mut self, key: &[u8]) -> Result<usize, ValueError> {
+        // Descendants share everything but the final terminator
+        let prefix = &key[..key.len().saturating_sub(1)];
+        self.remove_where(key, prefix, |k| k.starts_with(prefix))
+    }
+
+    /// Removes only the node `key`, leaving anything under it, like ZKILL
+    pub fn zkill(&mut self, key: &[u8]) -> Result<usize, ValueError> {
+        self.remove_where(key, key, |k| k == key)
+    }
+
+    /// Removes every record of `key`'s global from `start` onward for which `matches` is true,
+    /// stopping at the first level 0 block holding a key past everything starting with `start`
+    fn remove_where<F>(&mut self, key: &[u8], start: &[u8], matches: F) -> Result<usize, ValueError>
+            where F: Fn(&[u8]) -> bool {
+        let name = &key[..key.iter().position(|c| *c == 0).unwrap_or(key.len())];
+        let root = match self.find_global_root(name) {
+            Ok(x) => x,
+            Err(ValueError::GlobalNotFound) => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        // Every key past `start` and not starting with it sorts at or after this
+        let mut end = Vec::from(&start[..start.len().saturating_sub(1)]);
+        end.push(start.last().map(|c| c + 1).unwrap_or(1));
+        let tn = self.current_tn();
+        let mut goal = start.to_vec();
+        let mut removed = 0;
+        loop {
+            let mut path = self.descend(root, BlkType::IndexBlock, &goal)?;
+            // The lowest record with a key on the way here bounds the keys in this block; * records
+            // only bound it by whatever bounds their own block
+            let next = path.iter().rev().skip(1)
+                .find_map(|level| level.entries[level.index].key.clone());
+            let leaf = path.last_mut().unwrap();
+            let done = leaf.entries.iter()
+                .any(|e| e.key.as_ref().map(|k| k.as_slice() >= end.as_slice()).unwrap_or(false));
+            let before = leaf.entries.len();
+            leaf.entries.retain(|e| !e.key.as_ref().map(|k| matches(k)).unwrap_or(false));
+            let count = before - leaf.entries.len();
+            if count > 0 {
+                removed += count;
+                self.write_path(path, tn, false)?;
+            }
+            match next {
+                Some(mut k) if !done => {
+                    // Anything after the last key in this block
+                    k.push(0);
+                    goal = k;
+                },
+                _ => break,
+            }
+        }
+        if removed > 0 {
+            self.commit()?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Prepares a run of records split out of a block: returns the key the parent should use for
+/// it, and for index blocks other than the last run, turns its last record into a * record
+pub(crate) fn close_chunk(mut chunk: Vec<Entry>, levl: u8, last: bool)
+        -> (Option<Vec<u8>>, Vec<Entry>) {
+    if last {
+        return (None, chunk);
+    }
+    let key = if levl > 0 {
+        chunk.last_mut().and_then(|e| e.key.take())
+    } else {
+        chunk.last().and_then(|e| e.key.clone())
+    };
+    (key, chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util::{TempDb, key, sample_nodes, all_nodes, assert_integ_clean};
+
+    #[test]
+    fn sets_and_overwrites() {
+        let mut db = TempDb::small();
+        db.set(&key("^x(1)"), b"one").unwrap();
+        db.set(&key("^x(1)"), b"uno").unwrap();
+        db.set(&key("^y"), b"top").unwrap();
+        assert_eq!(db.get(&key("^x(1)")).unwrap(), Some(b"uno".to_vec()));
+        assert_eq!(db.get(&key("^y")).unwrap(), Some(b"top".to_vec()));
+        assert_eq!(db.get(&key("^x(2)")).unwrap(), None);
+        assert_eq!(db.get(&key("^z")).unwrap(), None);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn each_set_is_a_transaction() {
+        let mut db = TempDb::small();
+        let tn = db.current_tn();
+        db.set(&key("^x(1)"), b"one").unwrap();
+        db.set(&key("^x(2)"), b"two").unwrap();
+        assert_eq!(db.current_tn(), tn + 2);
+        // The header on disk agrees
+        let reopened = Database::open(&db.path.0).unwrap();
+        assert_eq!(reopened.current_tn(), tn + 2);
+    }
+
+    #[test]
+    fn splits_keep_every_node() {
+        let mut db = TempDb::small();
+        let nodes = sample_nodes(2000);
+        // Out of order, so splits happen in the middle of blocks as well as at the end
+        for (key, value) in nodes.iter().rev().step_by(2).chain(nodes.iter().step_by(2)) {
+            db.set(key, value).unwrap();
+        }
+        let stats = db.global_stats(b"x").unwrap();
+        assert!(stats.levels.len() > 2, "expected a tree of more than two levels");
+        assert_eq!(all_nodes(&db, b"x"), nodes);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn rejects_oversized_nodes() {
+        let mut db = TempDb::small();
+        let long_key = key(&format!("^x(\"{}\")", "k".repeat(100)));
+        assert!(db.set(&long_key, b"").is_err());
+        assert!(db.set(&key("^x(1)"), &[b'v'; 300]).is_err());
+        assert_eq!(db.get(&key("^x(1)")).unwrap(), None);
+        assert_integ_clean(&db);
+    }
+
+    #[test]
+    fn kill_removes_descendants() {
+        let mut db = TempDb::small();
+        for (key, value) in sample_nodes(500) {
+            db.set(&key, &value).unwrap();
+        }
+        db.set(&key("^x(10)"), b"parent").unwrap();
+        db.set(&key("^x(100)"), b"not a child").unwrap();
+        // ^x(10) and its one child
+        assert_eq!(db.kill(&key("^x(10)")).unwrap(), 2);
+        assert_eq!(db.get(&key("^x(10,\"name 3\")")).unwrap(), None);
+        assert_eq!(db.get(&key("^x(100)")).unwrap(), Some(b"not a child".to_vec()));
+        // Killing most of the tree frees blocks without breaking it
+        let free = db.fhead.trans_hist.free_blocks;
+        for i in 20..480 {
+            db.kill(&key(&format!("^x({})", i))).unwrap();
+        }
+        assert!(db.fhead.trans_hist.free_blocks > free);
+        assert_eq!(all_nodes(&db, b"x").len(), 500 - 1 - 460);
+        assert_integ_clean(&db);
+        assert_eq!(db.kill(&key("^nothing")).unwrap(), 0);
+    }
+
+    #[test]
+    fn zkill_keeps_descendants() {
+        let mut db = TempDb::small();
+        db.set(&key("^x(1)"), b"parent").unwrap();
+        db.set(&key("^x(1,2)"), b"child").unwrap();
+        assert_eq!(db.zkill(&key("^x(1)")).unwrap(), 1);
+        assert_eq!(db.get(&key("^x(1)")).unwrap(), None);
+        assert_eq!(db.get(&key("^x(1,2)")).unwrap(), Some(b"child".to_vec()));
+        assert_integ_clean(&db);
+    }
+}